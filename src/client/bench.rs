@@ -0,0 +1,138 @@
+use rpubsub::{Endpoint, Message};
+use std::env;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Throughput of a running server: each client subscribes to a topic of its own and then
+// alternates PUTs and GETs on it, so the topics stay short and every request changes the state.
+//
+//     bench <SERVER_ENDPOINT> [<CLIENTS> [<REQUESTS>]]
+//
+// Compare runs against servers started with different RPUBSUB_WORKERS.
+
+const DEFAULT_CLIENTS: usize = 16;
+const DEFAULT_REQUESTS: usize = 500;
+const TIMEOUT_MS: i32 = 10000;
+
+fn request(socket: &zmq::Socket, message: &Message) -> Result<Message, String> {
+    rpubsub::send_message_to(socket, message)
+        .and_then(|_| rpubsub::receive_message_from(socket))
+        .map_err(|e| e.to_string())
+}
+
+// Returns how long each request took
+fn run_client(context: &zmq::Context, endpoint: &Endpoint, curve_config: &rpubsub::CurveConfig, id: String, requests: usize) -> Result<Vec<Duration>, String> {
+    let socket = context.socket(zmq::REQ).map_err(|e| e.to_string())?;
+    socket.set_rcvtimeo(TIMEOUT_MS).map_err(|e| e.to_string())?;
+    socket.set_linger(0).map_err(|e| e.to_string())?;
+
+    rpubsub::secure_client_socket(&socket, curve_config).map_err(|e| e.to_string())?;
+    rpubsub::connect_to(&socket, endpoint).map_err(|e| e.to_string())?;
+
+    let topic = id.clone();
+
+    match request(&socket, &Message::SUB { ip: id.clone(), topic: topic.clone(), filter: None })? {
+        Message::REP { result: Ok(_) } => (),
+        reply => return Err(format!("unexpected reply {}", reply.to_string())),
+    }
+
+    let mut sequence_num = 0;
+
+    let mut latencies = Vec::with_capacity(requests);
+
+    for i in 0..requests {
+        let message = if i % 2 == 0 {
+            Message::PUT {
+                ip: id.clone(),
+                topic: topic.clone(),
+                sequence_num: i as u128,
+                payload: format!("update {}", i),
+                options: rpubsub::PutOptions::default(),
+                compression: rpubsub::Compression::NONE,
+            }
+        } else {
+            Message::GET { ip: id.clone(), topic: topic.clone(), sequence_num, accept: rpubsub::Compression::NONE }
+        };
+
+        let start = Instant::now();
+        let reply = request(&socket, &message)?;
+        latencies.push(start.elapsed());
+
+        match reply {
+            Message::REP { result: Ok(rpubsub::ReplyOption::TUP((Some(_), _))) } => sequence_num += 1,
+            Message::REP { result: Ok(_) } => (),
+            reply => return Err(format!("unexpected reply {}", reply.to_string())),
+        }
+    }
+
+    Ok(latencies)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 || args.len() > 4 {
+        println!("Wrong number of arguments");
+        println!("Usage: bench <SERVER_ENDPOINT> [<CLIENTS> [<REQUESTS>]]");
+        return;
+    }
+
+    let parsed = (
+        Endpoint::parse(&args[1]),
+        args.get(2).map_or(Ok(DEFAULT_CLIENTS), |clients| clients.parse::<usize>()),
+        args.get(3).map_or(Ok(DEFAULT_REQUESTS), |requests| requests.parse::<usize>()),
+        rpubsub::load_curve_config(),
+    );
+
+    let (endpoint, clients, requests, curve_config) = match parsed {
+        (Ok(endpoint), Ok(clients), Ok(requests), Ok(curve_config)) => (endpoint, clients, requests, curve_config),
+        (Err(e), _, _, _) | (_, _, _, Err(e)) => {
+            println!("{}", e);
+            return;
+        }
+        _ => {
+            println!("error: the number of clients and requests must be numbers");
+            return;
+        }
+    };
+
+    let context = zmq::Context::new();
+    let run = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let start = Instant::now();
+
+    let results: Vec<Result<Vec<Duration>, String>> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..clients)
+            .map(|client| {
+                let (context, endpoint, curve_config) = (&context, &endpoint, &curve_config);
+                let id = format!("bench-{}-{}", run, client);
+
+                scope.spawn(move || run_client(context, endpoint, curve_config, id, requests))
+            })
+            .collect();
+
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+
+    let elapsed = start.elapsed();
+
+    let mut latencies = Vec::new();
+
+    for result in results {
+        match result {
+            Ok(client_latencies) => latencies.extend(client_latencies),
+            Err(e) => println!("error: a client failed - {}", e),
+        }
+    }
+
+    if latencies.is_empty() {
+        return;
+    }
+
+    latencies.sort();
+
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100].as_secs_f64() * 1000.0;
+
+    println!("clients: {}; requests: {}; elapsed: {:.2}s", clients, latencies.len(), elapsed.as_secs_f64());
+    println!("throughput: {:.0} requests/s", latencies.len() as f64 / elapsed.as_secs_f64());
+    println!("latency: p50 {:.2}ms; p99 {:.2}ms; max {:.2}ms", percentile(50), percentile(99), percentile(100));
+}
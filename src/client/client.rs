@@ -1,316 +1,64 @@
-extern crate serde;
-extern crate serde_json;
-
-use rpubsub::{Message, SocketAddress};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use client::{get_state_file_content, keyring, process_line, send_message_with_retries, Client, TIMEOUT_MS};
+use rpubsub::Endpoint;
 use std::env;
-use std::fs;
 use std::io;
 
+fn main() {
+    println!("{}", std::env::current_dir().unwrap().to_str().unwrap());
+    let args: Vec<String> = env::args().collect();
 
-const MAX_TRIES: u32 = 3;
-const TIMEOUT_MS: i64 = 3000;
-
-//use zmq;
-
-pub struct Client {
-    pub ip: String,
-    pub state: State,
-    pub state_path: String,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct State {
-    pub sequence_numbers: HashMap<String, u128>, //hashmap [topic] = sequence_number
-    pub put_counters: HashMap<String, u128>,     //hashmap [topic] = counter
-}
-
-fn get_state_file_content(client: &mut Client) -> bool {
-    let client_path = format!("./data/clients_data/{}/", client.ip);
-    client.state_path = client_path.clone() + "state.json";
-
-    let res = fs::read_dir(&client_path);
-    const WITH_STATE: bool = true;
-
-    if res.is_err() {
-        println!("info: client state not found. creating one..");
-        fs::create_dir_all(&client_path);
-        let serialized_state = serde_json::to_string(&client.state).unwrap();
-
-        fs::write(&client.state_path, serialized_state);
-        !WITH_STATE
-    } else {
-        println!("info: client state found. backing up..");
-        let state_json = fs::read(&client.state_path);
-        //String::from_utf8(fs::read(client_path + "state.json").unwrap()).unwrap();
-        //
-        client.state = serde_json::from_slice(&state_json.unwrap().as_slice()).unwrap();        ;
-        WITH_STATE
-    }
-}
-
-fn save_state(client: & Client) -> Result<(), io::Error> {
-    let client_path = format!("./data/clients_data/{}/", client.ip);
-    let serialized_state = serde_json::to_string(&client.state).unwrap();
-    
-    match fs::create_dir_all(&client_path){
-        Err(e) => return Err(e),
-        Ok(_) => (),
-    }
-
-    fs::write(client_path.clone() + "state.json", serialized_state)
-}
-
-fn process_operation(client: &mut Client, op: &String) -> Result<Message, String> {
-    let operands: Vec<&str> = op.split(" ").collect();
-
-    println!("{:#?}", operands);
-
-    if operands.len() < 2 {
-        return Err(String::from("error: no operation was inputed"));
-    }
-
-    match operands[0] {
-        "GET" | "SUB" | "UNSUB" => {
-            if operands.len() != 2 {
-                return Err(String::from("error: missing parameters"));
-            }
+    if args.len() == 3 && args[1] == "keygen" {
+        match rpubsub::generate_curve_keys(&args[2]) {
+            Ok(_) => println!("info: CURVE keys written to {} and {}.pub", args[2], args[2]),
+            Err(e) => println!("{}", e),
         }
+        return;
+    }
 
-        "PUT" => {
-            if operands.len() != 3 {
-                return Err(String::from("error: missing parameters"));
-            }
+    // The server is given as an endpoint, or as an IP and port over TCP
+    let server_addr = match args.len() {
+        3 => Endpoint::parse(&args[2]),
+        4 => Endpoint::parse(&format!("tcp://{}:{}", args[2], args[3])),
+        _ => {
+            println!("Wrong number of arguments");
+            println!("Usage: client <IP> <SERVER_IP> <SERVER_PORT>");
+            println!("       client <IP> <SERVER_ENDPOINT>");
+            println!("       client keygen <KEY_FILE>");
+            return;
         }
-
-        _ => (),
     };
 
-    // TODO THIS
-    /*
-                if !client.sequence_numbers.contains_key(&topic) {
-                client.sequence_numbers.insert(topic.clone(), 0);
-                println!("info: no sequence number is associated to topic {}. Creating one", topic);
-            }
-
-    */
-    let topic = String::from(operands[1]);
-    match operands[0] {
-        "SUB" => Ok(Message::SUB {
-            ip: client.ip.clone(),
-            topic: topic,
-        }),
-
-        "UNSUB" => Ok(Message::UNSUB {
-            ip: client.ip.clone(),
-            topic: topic,
-        }),
-
-        "PUT" => {
-            // The client doesn't need to subscribe to put a message on a topic
-            if !client.state.put_counters.contains_key(&topic) {
-                client.state.put_counters.insert(topic.clone(), 0);
-                println!(
-                    "info: no put counter is associated to topic {}. Creating one",
-                    topic
-                );
-            }
-            // TODO
-            let payload = String::from(operands[2]);
-            Ok(Message::PUT {
-                ip: client.ip.clone(),
-                topic: topic.clone(),
-                sequence_num: *client.state.put_counters.get(&topic).unwrap(),
-                payload: payload,
-            })
+    let server_addr = match server_addr {
+        Ok(server_addr) => server_addr,
+        Err(e) => {
+            println!("{}", e);
+            return;
         }
+    };
 
-        "GET" => {
-            if !client.state.sequence_numbers.contains_key(&topic) {
-                return Err::<Message, String>(String::from(format!(
-                    "error: no sequence number associated to topic {}. This might mean that the service didn't get subscription confirmation from the server",
-                    topic
-                )));
-            }
+    let mut client = Client::new(&args[1]);
 
-            Ok(Message::GET {
-                ip: client.ip.clone(),
-                sequence_num: *client.state.sequence_numbers.get(&topic).unwrap(),
-                topic: topic,
-            })
+    client.state_key = match rpubsub::load_state_key() {
+        Ok(state_key) => state_key,
+        Err(e) => {
+            println!("{}", e);
+            return;
         }
-        _ => Err(String::from("error: unknown operation")),
-    }
-}
-
-fn process_reply(client: &mut Client, request: &Message, reply: &Message) {
-    match reply {
-        Message::REP { result } => {
-            match request {
-                Message::SUB { ip: _, topic } => {
-                    if result.is_err() {
-                        println!(
-                            "error: cannot subsribe to topic. topic: {}; reason: {:?}",
-                            topic,
-                            result.as_ref().unwrap_err()
-                        );
-                        return;
-                    }
-
-                    if !client.state.sequence_numbers.contains_key(topic) {
-                        client.state.sequence_numbers.insert(topic.clone(), 0);
-                        println!(
-                            "info: no sequence number is associated to topic {}. Creating one",
-                            topic
-                        );
-                    }
-                }
-
-                Message::UNSUB { ip: _, topic } => {
-                    // This error should never happen
-                    if result.is_err() {
-                        println!(
-                            "error: cannot unsubsribe to topic. topic: {}; reason: {:?}",
-                            topic,
-                            &result.as_ref().unwrap_err()
-                        );
-                        return;
-                    }
-
-                    if client.state.sequence_numbers.contains_key(topic) {
-                        client.state.sequence_numbers.remove(topic);
-                        println!(
-                            "info: removed sequence number associated to topic {}",
-                            topic
-                        );
-                    }
-                }
-
-                Message::PUT {
-                    ip: _,
-                    topic,
-                    sequence_num: _,
-                    payload: _,
-                } => {
-                    if result.is_err() {
-                        println!(
-                            "error: cannot put message on topic. topic: {}; reason: {:?}",
-                            topic,
-                            &result.as_ref().unwrap_err()
-                        );
-                        return;
-                    }
-
-                    if !client.state.put_counters.contains_key(topic) {
-                        client.state.put_counters.insert(topic.clone(), 0);
-                        println!(
-                            "info: no put counter is associated to topic {}. Creating one",
-                            topic
-                        );
-                    }
-
-                    let reply_option = result.as_ref().unwrap();
-
-                    match reply_option {
-                        rpubsub::ReplyOption::NoOk => {
-                            if let Some(counter) = client.state.put_counters.get_mut(topic) {
-                                *counter += 1;
-                            }
-                        }
-                        rpubsub::ReplyOption::TUP(_) => (),
-                    }
-                }
-
-                Message::GET {
-                    ip: _,
-                    topic,
-                    sequence_num,
-                } => {
-                    if result.is_err() {
-                        println!(
-                            "error: cannot get message from topic. topic: {}; reason: {:?}",
-                            topic,
-                            &result.as_ref().unwrap_err()
-                        );
-                        return;
-                    }
-
-                    let reply_option = result.as_ref().unwrap();
-
-                    match reply_option {
-                        rpubsub::ReplyOption::TUP(tup) => {
-                            if *sequence_num == tup.1 {
-                                if tup.0.is_some() {
-                                    if let Some(counter) =
-                                        client.state.sequence_numbers.get_mut(topic)
-                                    {
-                                        *counter += 1;
-                                    }
-                                }
-                            } else {
-                                println!("error: receiving outdated messages. topic: {} seq_nums(client, server): ({}, {})", topic, sequence_num, tup.1);
-                                println!("info: Syncronizing local sequence number with server");
-
-                                if let Some(counter) = client.state.put_counters.get_mut(topic) {
-                                    *counter = tup.1;
-                                }
-                            }
-                        }
-                        rpubsub::ReplyOption::NoOk => (),
-                    }
-                }
+    };
 
-                // Message::UP { ip: _, sequence_nums } => {},
-                _ => (),
-            };
+    let with_state = match get_state_file_content(&mut client) {
+        Ok(with_state) => with_state,
+        Err(e) => {
+            println!("{}", e);
+            return;
         }
-        _ => (),
-    }
-}
-
-pub fn send_message_with_retries(socket: &zmq::Socket, message: &Message) -> Message {
-    match rpubsub::send_message_to(socket, message) {
-        Ok(_) => {
-            println!("Sent message");
-            
-            match rpubsub::receive_message_from(socket) {
-            Ok(reply) => return reply,
-            Err(_) => return rpubsub::Message::NOMSG,
-            };
-        },
-
-        Err(_) => return rpubsub::Message::NOMSG,
     };
 
-}
-
-fn main() {
-    println!("{}", std::env::current_dir().unwrap().to_str().unwrap());
-    let args: Vec<String> = env::args().collect();
-
-    if args.len() != 4 {
-        println!("Wrong number of arguments");
-        println!("Usage: client <IP> <SERVER_IP> <SERVER_PORT>");
+    if let Err(e) = keyring::load_keyring(&mut client) {
+        println!("{}", e);
         return;
     }
 
-    let server_addr = SocketAddress {
-        ip: args[2].clone(),
-        port: args[3].clone().parse::<u16>().unwrap(),
-    };
-
-    let mut client = Client {
-        ip: args[1].clone(),
-        state: State {
-            sequence_numbers: HashMap::new(),
-            put_counters: HashMap::new(),
-        },
-        state_path: String::new()
-    };
-
-    let with_state = get_state_file_content(&mut client);
-
     let context = zmq::Context::new();
 
     let req_socket = match context.socket(zmq::REQ) {
@@ -325,10 +73,28 @@ fn main() {
     req_socket.set_rcvtimeo(TIMEOUT_MS.try_into().unwrap());
     req_socket.set_linger(0);
 
+    // A request whose reply was lost doesn't keep the socket from sending the next one,
+    // and late or duplicated replies are dropped
+    let _ = req_socket.set_req_relaxed(true);
+    let _ = req_socket.set_req_correlate(true);
+
+    let curve_config = match rpubsub::load_curve_config() {
+        Ok(curve_config) => curve_config,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = rpubsub::secure_client_socket(&req_socket, &curve_config) {
+        println!("{}", e.to_string().as_str());
+        return;
+    }
+
     match rpubsub::connect_to(&req_socket, &server_addr) {
         Ok(_) => println!(
-            "Connected to server listening on {}:{}",
-            server_addr.ip, server_addr.port
+            "Connected to server listening on {}",
+            server_addr
         ),
         Err(e) => {
             println!("{}", e.to_string().as_str());
@@ -342,7 +108,7 @@ fn main() {
             sequence_nums: client.state.sequence_numbers.clone(),
         };
         let reply = send_message_with_retries(&req_socket, &message);
-        println!("Received reply: {}", reply.to_string());
+        println!("Received reply: {}", reply.to_log_string());
     }
 
     loop {
@@ -350,20 +116,8 @@ fn main() {
         io::stdin().read_line(&mut line).unwrap();
         line = String::from(line.trim());
 
-        match process_operation(&mut client, &line) {
-            Ok(request) => {
-                let reply = send_message_with_retries(&req_socket, &request);
-
-                println!("Received reply {}", reply.to_string());
-
-                process_reply(&mut client, &request, &reply);
-
-                match save_state(&client){
-                    Err(e) => println!("error: while saving state. e: {}", e),
-                    Ok(_) => (),
-                }
-            }
-            Err(e) => println!("{}", e),
+        if let Err(e) = process_line(&mut client, &req_socket, &line) {
+            println!("{}", e);
         }
     }
 }
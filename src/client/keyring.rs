@@ -0,0 +1,315 @@
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rpubsub::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+use crate::Client;
+
+const NONCE_SIZE: usize = 12;
+// Bytes sealing adds: the nonce and the authentication tag
+pub const SEAL_OVERHEAD: usize = NONCE_SIZE + 16;
+
+// One version of the key of a topic. Rotating the key adds a version, the older ones
+// are kept to read the updates published before.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopicKey {
+    pub version: u32,
+    // base64
+    pub key: String,
+}
+
+// Keys of the encrypted topics, kept apart from the client state in keys.json. Keys are
+// shared out of band: KEY EXPORT writes them to a file for the other clients to KEY IMPORT.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Keyring {
+    pub topics: HashMap<String, Vec<TopicKey>>,
+}
+
+fn keyring_path(ip: &String) -> String {
+    format!("./data/clients_data/{}/keys.json", ip)
+}
+
+// Like the client state, the keyring is encrypted at rest once there is a state key
+pub fn load_keyring(client: &mut Client) -> Result<(), String> {
+    let content = match fs::read(keyring_path(&client.ip)) {
+        Ok(content) => content,
+        Err(_) => return Ok(()),
+    };
+
+    let encrypted = rpubsub::is_encrypted_state(&content);
+    let content = rpubsub::open_state(client.state_key.as_ref(), content)?;
+
+    client.keyring = serde_json::from_slice(&content).map_err(|e| format!("error: invalid keyring. e: {}", e))?;
+
+    if !encrypted && client.state_key.is_some() {
+        println!("info: encrypting plaintext keyring..");
+        save_keyring(client)?;
+    }
+
+    Ok(())
+}
+
+// Keys may be in plaintext, so only the owner may read the files they are written to.
+// They are renamed over the file like the state, so a crash while saving doesn't lose the keys.
+fn write_keys(path: &str, content: &[u8]) -> std::io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+
+    fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp_path)
+        .and_then(|mut file| {
+            // A tmp file left by an earlier attempt keeps its mode otherwise
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+            file.write_all(content)
+        })
+        .and_then(|_| fs::rename(&tmp_path, path))
+}
+
+fn save_keyring(client: &Client) -> Result<(), String> {
+    let serialized_keyring = rpubsub::seal_state(client.state_key.as_ref(), serde_json::to_vec(&client.keyring).unwrap());
+
+    fs::create_dir_all(format!("./data/clients_data/{}/", client.ip))
+        .and_then(|_| write_keys(&keyring_path(&client.ip), &serialized_keyring))
+        .map_err(|e| format!("error: while saving keyring. e: {}", e))
+}
+
+// Handles KEY NEW|EXPORT|IMPORT, which only change the keyring. Returns None for the other operations.
+pub fn process_keys(client: &mut Client, op: &str) -> Option<Result<(), String>> {
+    let operands: Vec<&str> = op.split(" ").collect();
+
+    if operands[0] != "KEY" {
+        return None;
+    }
+
+    let res = match operands[1..] {
+        ["NEW", topic] => new_key(client, topic),
+        ["EXPORT", topic, path] => export_keys(client, topic, path),
+        ["IMPORT", path] => import_keys(client, path),
+        _ => Err(String::from("error: missing parameters")),
+    };
+
+    Some(res)
+}
+
+// Adds a key version, used from then on for the updates put on the topic
+fn new_key(client: &mut Client, topic: &str) -> Result<(), String> {
+    let keys = client.keyring.topics.entry(String::from(topic)).or_default();
+    let version = keys.iter().map(|key| key.version + 1).max().unwrap_or(1);
+
+    keys.push(TopicKey {
+        version,
+        key: rpubsub::encode_chunk(&ChaCha20Poly1305::generate_key(&mut OsRng)),
+    });
+
+    println!("info: topic {} is now encrypted with key version {}", topic, version);
+
+    save_keyring(client)
+}
+
+fn export_keys(client: &Client, topic: &str, path: &str) -> Result<(), String> {
+    let keys = match client.keyring.topics.get(topic) {
+        Some(keys) => keys,
+        None => return Err(format!("error: no key for topic {}", topic)),
+    };
+
+    let exported = Keyring { topics: HashMap::from([(String::from(topic), keys.clone())]) };
+
+    write_keys(path, serde_json::to_string(&exported).unwrap().as_bytes()).map_err(|e| format!("error: cannot write file. e: {}", e))
+}
+
+// Adds the key versions in the file that the keyring doesn't have yet
+fn import_keys(client: &mut Client, path: &str) -> Result<(), String> {
+    let content = fs::read(path).map_err(|e| format!("error: cannot open file. e: {}", e))?;
+    let imported: Keyring = serde_json::from_slice(&content).map_err(|e| format!("error: invalid key file. e: {}", e))?;
+
+    for (topic, imported_keys) in imported.topics {
+        let keys = client.keyring.topics.entry(topic.clone()).or_default();
+
+        for imported_key in imported_keys {
+            if !keys.iter().any(|key| key.version == imported_key.version) {
+                println!("info: imported key version {} of topic {}", imported_key.version, topic);
+                keys.push(imported_key);
+            }
+        }
+    }
+
+    save_keyring(client)
+}
+
+fn cipher(key: &TopicKey) -> Option<ChaCha20Poly1305> {
+    match rpubsub::decode_chunk(&key.key) {
+        Some(key) if key.len() == 32 => Some(ChaCha20Poly1305::new(Key::from_slice(&key))),
+        _ => None,
+    }
+}
+
+// The ciphertext is bound to the topic and key version, so it can't be passed off as
+// an update of another topic. It also says whether the plaintext was compressed.
+fn associated_data(topic: &str, version: u32, compressed: bool) -> Vec<u8> {
+    if compressed {
+        format!("{}:{}:lz4", topic, version).into_bytes()
+    } else {
+        format!("{}:{}", topic, version).into_bytes()
+    }
+}
+
+// File chunks are also bound to their blob and place in it, so they can't be swapped
+fn chunk_associated_data(topic: &str, version: u32, blob_id: &str, index: u64) -> Vec<u8> {
+    format!("{}:{}:{}:{}", topic, version, blob_id, index).into_bytes()
+}
+
+// The latest version of the topic's key, None if the topic isn't encrypted
+pub fn latest_version(keyring: &Keyring, topic: &str) -> Option<u32> {
+    keyring.topics.get(topic).and_then(|keys| keys.iter().map(|key| key.version).max())
+}
+
+fn key_cipher(keyring: &Keyring, topic: &str, version: u32) -> Result<ChaCha20Poly1305, String> {
+    let key = keyring.topics.get(topic).and_then(|keys| keys.iter().find(|key| key.version == version));
+
+    match key {
+        Some(key) => cipher(key).ok_or(format!("error: invalid key version {} of topic {}", version, topic)),
+        None => Err(format!("error: no key version {} of topic {}", version, topic)),
+    }
+}
+
+// Returns the nonce followed by the ciphertext
+fn encrypt(cipher: &ChaCha20Poly1305, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: data, aad })
+        .map_err(|_| String::from("error: cannot encrypt payload"))?;
+
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn decrypt(cipher: &ChaCha20Poly1305, aad: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < SEAL_OVERHEAD {
+        return None;
+    }
+
+    cipher.decrypt(Nonce::from_slice(&data[..NONCE_SIZE]), Payload { msg: &data[NONCE_SIZE..], aad }).ok()
+}
+
+// Encrypts the payload with the latest key of the topic, returning the base64 nonce and
+// ciphertext and the key version. The payload is compressed first when asked, as the
+// ciphertext doesn't compress. Topics without a key are sent in plaintext.
+pub fn seal(keyring: &Keyring, topic: &str, payload: &str, compression: rpubsub::Compression) -> Result<(String, Option<u32>), String> {
+    match latest_version(keyring, topic) {
+        Some(version) => Ok((seal_version(keyring, topic, version, payload, compression)?, Some(version))),
+        None => Ok((String::from(payload), None)),
+    }
+}
+
+pub fn seal_version(keyring: &Keyring, topic: &str, version: u32, payload: &str, compression: rpubsub::Compression) -> Result<String, String> {
+    let cipher = key_cipher(keyring, topic, version)?;
+
+    let compressed = match compression {
+        rpubsub::Compression::LZ4 if payload.len() >= rpubsub::COMPRESSION_MIN_BYTES => Some(rpubsub::compress_bytes(payload.as_bytes())),
+        _ => None,
+    };
+
+    let sealed = match compressed {
+        Some(compressed) if compressed.len() < payload.len() => encrypt(&cipher, &associated_data(topic, version, true), &compressed)?,
+        _ => encrypt(&cipher, &associated_data(topic, version, false), payload.as_bytes())?,
+    };
+
+    Ok(rpubsub::encode_chunk(&sealed))
+}
+
+pub fn open(keyring: &Keyring, topic: &str, version: u32, payload: &str) -> Result<String, String> {
+    let cipher = key_cipher(keyring, topic, version)?;
+
+    let data = match rpubsub::decode_chunk(payload) {
+        Some(data) => data,
+        None => return Err(String::from("error: received a corrupted payload")),
+    };
+
+    let plaintext = match decrypt(&cipher, &associated_data(topic, version, false), &data) {
+        Some(plaintext) => Some(plaintext),
+        None => decrypt(&cipher, &associated_data(topic, version, true), &data).and_then(|compressed| rpubsub::decompress_bytes(&compressed)),
+    };
+
+    match plaintext.map(String::from_utf8) {
+        Some(Ok(payload)) => Ok(payload),
+        Some(Err(_)) => Err(String::from("error: received a corrupted payload")),
+        None => Err(format!("error: cannot decrypt payload with key version {} of topic {}", version, topic)),
+    }
+}
+
+// Bytes of a file read into each chunk, so that sealed chunks are still CHUNK_SIZE long
+pub fn file_chunk_size(version: Option<u32>) -> usize {
+    match version {
+        Some(_) => rpubsub::CHUNK_SIZE - SEAL_OVERHEAD,
+        None => rpubsub::CHUNK_SIZE,
+    }
+}
+
+pub fn seal_chunk(keyring: &Keyring, topic: &str, version: u32, blob_id: &str, index: u64, data: &[u8]) -> Result<Vec<u8>, String> {
+    encrypt(&key_cipher(keyring, topic, version)?, &chunk_associated_data(topic, version, blob_id, index), data)
+}
+
+pub fn open_chunk(keyring: &Keyring, topic: &str, version: u32, blob_id: &str, index: u64, data: &[u8]) -> Result<Vec<u8>, String> {
+    let cipher = key_cipher(keyring, topic, version)?;
+
+    decrypt(&cipher, &chunk_associated_data(topic, version, blob_id, index), data)
+        .ok_or(format!("error: cannot decrypt chunk {} with key version {} of topic {}", index, version, topic))
+}
+
+// Decrypts the update in the reply to a GET or GGET. Updates that can't be decrypted
+// are left as they came.
+pub fn open_reply(keyring: &Keyring, request: &Message, reply: Message) -> Message {
+    let topic = match request {
+        Message::GET { topic, .. } | Message::GGET { topic, .. } => topic,
+        _ => return reply,
+    };
+
+    match reply {
+        Message::REP { result: Ok(rpubsub::ReplyOption::TUP((Some(mut delivery), seq))) } => {
+            if let Some(version) = delivery.key_version {
+                match open(keyring, topic, version, &delivery.payload) {
+                    Ok(payload) => {
+                        delivery.payload = payload;
+                        delivery.key_version = None;
+                    }
+                    Err(e) => println!("{}", e),
+                }
+            }
+
+            Message::REP { result: Ok(rpubsub::ReplyOption::TUP((Some(delivery), seq))) }
+        }
+        _ => reply,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topic_key(version: u32) -> TopicKey {
+        TopicKey { version, key: rpubsub::encode_chunk(&ChaCha20Poly1305::generate_key(&mut OsRng)) }
+    }
+
+    #[test]
+    fn exported_keys_are_only_readable_by_their_owner() {
+        let path = std::env::temp_dir().join(format!("rpubsub-keyring-export-{}.json", std::process::id())).to_string_lossy().into_owned();
+
+        // Left world-readable by someone else, it is still restricted once the keys are in it
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let mut client = Client::new("c0");
+        client.keyring.topics.insert(String::from("orders"), vec![topic_key(1), topic_key(2)]);
+
+        export_keys(&client, "orders", &path).unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let exported: Keyring = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(exported.topics.get("orders").unwrap().len(), 2);
+
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,750 @@
+extern crate serde;
+extern crate serde_json;
+
+use rpubsub::transport::Transport;
+use rpubsub::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+pub mod keyring;
+pub mod transfer;
+
+const MAX_TRIES: u32 = 3;
+pub const TIMEOUT_MS: i64 = 3000;
+
+//use zmq;
+
+pub struct Client {
+    pub ip: String,
+    pub state: State,
+    pub state_path: String,
+    pub keyring: keyring::Keyring,
+    // state.json and keys.json are encrypted with it when there is one
+    pub state_key: Option<rpubsub::StateKey>,
+}
+
+impl Client {
+    pub fn new(ip: &str) -> Self {
+        Client {
+            ip: String::from(ip),
+            state: State {
+                sequence_numbers: HashMap::new(),
+                put_counters: HashMap::new(),
+                queue_counters: HashMap::new(),
+                group_deliveries: HashMap::new(),
+                tx_counter: rpubsub::first_sequence_num(),
+                uploads: Vec::new(),
+                compression: rpubsub::Compression::NONE,
+            },
+            state_path: String::new(),
+            keyring: keyring::Keyring::default(),
+            state_key: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct State {
+    pub sequence_numbers: HashMap<String, u128>, //hashmap [topic] = sequence_number
+    pub put_counters: HashMap<String, u128>,     //hashmap [topic] = counter
+    #[serde(default)]
+    pub queue_counters: HashMap<String, u128>,   //hashmap [queue] = counter
+    #[serde(default)]
+    pub group_deliveries: HashMap<String, HashMap<String, u128>>, //hashmap [topic][group] = last received delivery id
+    #[serde(default)]
+    pub tx_counter: u128,
+    #[serde(default)]
+    pub uploads: Vec<transfer::PendingUpload>,
+    // Compression used for payloads sent and asked for on payloads received, see COMPRESS
+    #[serde(default)]
+    pub compression: rpubsub::Compression,
+}
+
+pub fn get_state_file_content(client: &mut Client) -> Result<bool, String> {
+    let client_path = format!("./data/clients_data/{}/", client.ip);
+    client.state_path = client_path.clone() + "state.json";
+
+    let res = fs::read_dir(&client_path);
+    const WITH_STATE: bool = true;
+
+    if res.is_err() {
+        println!("info: client state not found. creating one..");
+        fs::create_dir_all(&client_path);
+        let serialized_state = serde_json::to_vec(&client.state).unwrap();
+
+        fs::write(&client.state_path, rpubsub::seal_state(client.state_key.as_ref(), serialized_state));
+        Ok(!WITH_STATE)
+    } else {
+        println!("info: client state found. backing up..");
+        let state_json = fs::read(&client.state_path).map_err(|e| format!("error: couldn't read the client state: {}", e))?;
+        let encrypted = rpubsub::is_encrypted_state(&state_json);
+        //String::from_utf8(fs::read(client_path + "state.json").unwrap()).unwrap();
+        //
+        let state_json = rpubsub::open_state(client.state_key.as_ref(), state_json)?;
+        client.state = serde_json::from_slice(&state_json.as_slice()).unwrap();        ;
+
+        // Existing plaintext state is encrypted as soon as there is a key
+        if !encrypted && client.state_key.is_some() {
+            println!("info: encrypting plaintext client state..");
+            save_state(client).map_err(|e| format!("error: couldn't encrypt the client state: {}", e))?;
+        }
+
+        Ok(WITH_STATE)
+    }
+}
+
+pub fn save_state(client: & Client) -> Result<(), io::Error> {
+    let client_path = format!("./data/clients_data/{}/", client.ip);
+    let serialized_state = rpubsub::seal_state(client.state_key.as_ref(), serde_json::to_vec(&client.state).unwrap());
+    
+    match fs::create_dir_all(&client_path){
+        Err(e) => return Err(e),
+        Ok(_) => (),
+    }
+
+    // Written next to the state and renamed over it, so a crash leaves the previous state
+    fs::write(client_path.clone() + "state.json.tmp", serialized_state)
+        .and_then(|_| fs::rename(client_path.clone() + "state.json.tmp", client_path.clone() + "state.json"))
+}
+
+// Parses the OPTION=VALUE operands that may follow the payload of a PUT
+fn parse_put_options(operands: &[&str]) -> Result<rpubsub::PutOptions, String> {
+    let mut options = rpubsub::PutOptions::default();
+
+    for operand in operands {
+        let (name, value) = match operand.split_once('=') {
+            Some(pair) => pair,
+            None => return Err(format!("error: invalid put option {}", operand)),
+        };
+
+        if let Some(header) = name.strip_prefix("h:") {
+            options.headers.insert(String::from(header), String::from(value));
+            continue;
+        }
+
+        match name {
+            "at" => match value.parse::<u128>() {
+                Ok(deliver_at) => options.deliver_at = Some(deliver_at),
+                Err(_) => return Err(format!("error: invalid delivery time {}", value)),
+            },
+
+            "delay" => match value.parse::<u64>() {
+                Ok(delay_ms) => options.delay_ms = Some(delay_ms),
+                Err(_) => return Err(format!("error: invalid delay {}", value)),
+            },
+
+            "ttl" => match value.parse::<u64>() {
+                Ok(ttl_ms) => options.ttl_ms = Some(ttl_ms),
+                Err(_) => return Err(format!("error: invalid time-to-live {}", value)),
+            },
+
+            "key" => options.key = Some(String::from(value)),
+
+            "prio" => match value.parse::<u8>() {
+                Ok(priority) => options.priority = priority,
+                Err(_) => return Err(format!("error: invalid priority {}", value)),
+            },
+
+            _ => return Err(format!("error: unknown put option {}", name)),
+        }
+    }
+
+    Ok(options)
+}
+
+// COMPRESS on|off only changes the client settings. Returns None for the other operations.
+pub fn process_setting(client: &mut Client, op: &str) -> Option<Result<(), String>> {
+    let operands: Vec<&str> = op.split(" ").collect();
+
+    if operands[0] != "COMPRESS" {
+        return None;
+    }
+
+    client.state.compression = match operands.get(1) {
+        Some(&"on") => rpubsub::Compression::LZ4,
+        Some(&"off") => rpubsub::Compression::NONE,
+        _ => return Some(Err(String::from("error: compression must be on or off"))),
+    };
+
+    Some(save_state(client).map_err(|e| format!("error: while saving state. e: {}", e)))
+}
+
+// Deliveries are printed and handled as the publisher wrote them
+pub fn decompress_reply(reply: Message) -> Message {
+    match reply {
+        Message::REP { result: Ok(rpubsub::ReplyOption::TUP((Some(delivery), seq))) } => {
+            let delivery = match delivery.clone().decompressed() {
+                Some(delivery) => delivery,
+                None => {
+                    println!("error: received a corrupted payload");
+                    delivery
+                }
+            };
+
+            Message::REP { result: Ok(rpubsub::ReplyOption::TUP((Some(delivery), seq))) }
+        }
+        _ => reply,
+    }
+}
+
+// Seals the payload on encrypted topics, compressed beforehand, and otherwise compresses
+// it for the server to decompress
+fn encode_payload(client: &Client, topic: &str, payload: &str) -> Result<(String, Option<u32>, rpubsub::Compression), String> {
+    match keyring::seal(&client.keyring, topic, payload, client.state.compression)? {
+        (sealed, Some(version)) => Ok((sealed, Some(version), rpubsub::Compression::NONE)),
+        (payload, None) => {
+            let (payload, compression) = rpubsub::compress_payload(&payload, client.state.compression);
+            Ok((payload, None, compression))
+        }
+    }
+}
+
+pub fn process_operation(client: &mut Client, op: &str) -> Result<Message, String> {
+    let operands: Vec<&str> = op.split(" ").collect();
+
+    println!("{:#?}", operands);
+
+    if operands.len() < 2 {
+        return Err(String::from("error: no operation was inputed"));
+    }
+
+    match operands[0] {
+        "GET" | "UNSUB" | "QGET" => {
+            if operands.len() != 2 {
+                return Err(String::from("error: missing parameters"));
+            }
+        }
+
+        "PUT" | "TXPUT" => {
+            if operands.len() < 3 {
+                return Err(String::from("error: missing parameters"));
+            }
+        }
+
+        "JOIN" | "LEAVE" | "GGET" | "QPUT" | "QACK" | "QNACK" => {
+            if operands.len() != 3 {
+                return Err(String::from("error: missing parameters"));
+            }
+        }
+
+        "CONF" => {
+            if operands.len() < 3 || operands.len() > 6 {
+                return Err(String::from("error: missing parameters"));
+            }
+        }
+
+        _ => (),
+    };
+
+    // TODO THIS
+    /*
+                if !client.sequence_numbers.contains_key(&topic) {
+                client.sequence_numbers.insert(topic.clone(), 0);
+                println!("info: no sequence number is associated to topic {}. Creating one", topic);
+            }
+
+    */
+    let topic = String::from(operands[1]);
+    match operands[0] {
+        // Everything after the topic is the filter
+        "SUB" => Ok(Message::SUB {
+            ip: client.ip.clone(),
+            topic,
+            filter: if operands.len() > 2 { Some(operands[2..].join(" ")) } else { None },
+        }),
+
+        "UNSUB" => Ok(Message::UNSUB {
+            ip: client.ip.clone(),
+            topic,
+        }),
+
+        "PUT" => {
+            // The client doesn't need to subscribe to put a message on a topic
+            if !client.state.put_counters.contains_key(&topic) {
+                client.state.put_counters.insert(topic.clone(), rpubsub::first_sequence_num());
+                println!(
+                    "info: no put counter is associated to topic {}. Creating one",
+                    topic
+                );
+            }
+            // TODO
+            let mut options = parse_put_options(&operands[3..])?;
+            let (payload, key_version, compression) = encode_payload(client, &topic, operands[2])?;
+            options.key_version = key_version;
+            Ok(Message::PUT {
+                ip: client.ip.clone(),
+                topic: topic.clone(),
+                sequence_num: *client.state.put_counters.get(&topic).unwrap(),
+                payload,
+                options,
+                compression,
+            })
+        }
+
+        // The updates are separated by a lone |, e.g. TXPUT t1 p1 | t2 p2 ttl=1000
+        "TXPUT" => {
+            let mut updates = Vec::new();
+
+            for update in operands[1..].split(|operand| *operand == "|") {
+                if update.len() < 2 {
+                    return Err(String::from("error: missing parameters"));
+                }
+
+                let mut options = parse_put_options(&update[2..])?;
+                let (payload, key_version, compression) = encode_payload(client, update[0], update[1])?;
+                options.key_version = key_version;
+
+                updates.push(rpubsub::TxUpdate {
+                    topic: String::from(update[0]),
+                    payload,
+                    options,
+                    compression,
+                });
+            }
+
+            Ok(Message::TXPUT {
+                ip: client.ip.clone(),
+                sequence_num: client.state.tx_counter,
+                updates,
+            })
+        }
+
+        "JOIN" => Ok(Message::JOIN {
+            ip: client.ip.clone(),
+            topic,
+            group: String::from(operands[2]),
+        }),
+
+        "LEAVE" => Ok(Message::LEAVE {
+            ip: client.ip.clone(),
+            topic,
+            group: String::from(operands[2]),
+        }),
+
+        "GGET" => {
+            let group = String::from(operands[2]);
+            let ack = client
+                .state
+                .group_deliveries
+                .get(&topic)
+                .and_then(|groups| groups.get(&group))
+                .copied();
+
+            Ok(Message::GGET {
+                ip: client.ip.clone(),
+                topic,
+                group,
+                ack,
+                accept: client.state.compression,
+            })
+        }
+
+        // For queue operations the second operand is the queue name
+        "QPUT" => {
+            let (payload, compression) = rpubsub::compress_payload(operands[2], client.state.compression);
+            let sequence_num = *client.state.queue_counters.entry(topic.clone()).or_insert_with(rpubsub::first_sequence_num);
+
+            Ok(Message::QPUT {
+                ip: client.ip.clone(),
+                queue: topic,
+                sequence_num,
+                payload,
+                compression,
+            })
+        }
+
+        "QGET" => Ok(Message::QGET {
+            ip: client.ip.clone(),
+            queue: topic,
+            accept: client.state.compression,
+        }),
+
+        "QACK" | "QNACK" => {
+            let message_id = match operands[2].parse::<u128>() {
+                Ok(message_id) => message_id,
+                Err(_) => return Err(String::from("error: invalid message id")),
+            };
+
+            if operands[0] == "QACK" {
+                Ok(Message::QACK {
+                    ip: client.ip.clone(),
+                    queue: topic,
+                    message_id,
+                })
+            } else {
+                Ok(Message::QNACK {
+                    ip: client.ip.clone(),
+                    queue: topic,
+                    message_id,
+                })
+            }
+        }
+
+        "CONF" => {
+            let option = match operands[2] {
+                "deadletter" => rpubsub::TopicOption::DeadLetter(operands.get(3).map(|dead_letter_topic| String::from(*dead_letter_topic))),
+                "compaction" => match operands.get(3) {
+                    Some(&"on") => rpubsub::TopicOption::Compaction(true),
+                    Some(&"off") => rpubsub::TopicOption::Compaction(false),
+                    _ => return Err(String::from("error: compaction must be on or off")),
+                },
+                "ratelimit" => match (operands.get(3), operands.get(4)) {
+                    (None, _) => rpubsub::TopicOption::RateLimit(None),
+                    (Some(rate), burst) => match (rate.parse::<f64>(), burst.unwrap_or(rate).parse::<f64>()) {
+                        (Ok(rate), Ok(burst)) => rpubsub::TopicOption::RateLimit(Some(rpubsub::RateLimit { rate, burst })),
+                        _ => return Err(String::from("error: invalid rate limit")),
+                    },
+                },
+                "quota" => match operands.get(3).map(|quota_bytes| quota_bytes.parse::<usize>()) {
+                    None => rpubsub::TopicOption::Quota(None),
+                    Some(Ok(quota_bytes)) => rpubsub::TopicOption::Quota(Some(quota_bytes)),
+                    Some(Err(_)) => return Err(String::from("error: invalid quota")),
+                },
+                "watermarks" => {
+                    let marks: Result<Vec<usize>, _> = operands[3..].iter().map(|mark| mark.parse::<usize>()).collect();
+
+                    match marks.as_deref() {
+                        Ok([]) => rpubsub::TopicOption::Watermarks(None),
+                        Ok([high, low]) => rpubsub::TopicOption::Watermarks(Some(rpubsub::Watermarks { high: *high, low: *low, hard: None })),
+                        Ok([high, low, hard]) => rpubsub::TopicOption::Watermarks(Some(rpubsub::Watermarks { high: *high, low: *low, hard: Some(*hard) })),
+                        _ => return Err(String::from("error: invalid watermarks")),
+                    }
+                },
+                _ => return Err(String::from("error: unknown topic option")),
+            };
+
+            Ok(Message::CONF {
+                ip: client.ip.clone(),
+                topic,
+                option,
+            })
+        }
+
+        "GET" => {
+            if !client.state.sequence_numbers.contains_key(&topic) {
+                return Err::<Message, String>(String::from(format!(
+                    "error: no sequence number associated to topic {}. This might mean that the service didn't get subscription confirmation from the server",
+                    topic
+                )));
+            }
+
+            Ok(Message::GET {
+                ip: client.ip.clone(),
+                sequence_num: *client.state.sequence_numbers.get(&topic).unwrap(),
+                topic,
+                accept: client.state.compression,
+            })
+        }
+        _ => Err(String::from("error: unknown operation")),
+    }
+}
+
+pub fn process_reply(client: &mut Client, request: &Message, reply: &Message) {
+    match reply {
+        Message::REP { result } => {
+            match request {
+                Message::SUB { ip: _, topic, filter: _ } => {
+                    // ALREASUB answers a SUB sent again after its reply was lost
+                    if result.is_err() && !matches!(result, Err(rpubsub::ServiceError::ALREASUB)) {
+                        println!(
+                            "error: cannot subsribe to topic. topic: {}; reason: {:?}",
+                            topic,
+                            result.as_ref().unwrap_err()
+                        );
+                        return;
+                    }
+
+                    if !client.state.sequence_numbers.contains_key(topic) {
+                        client.state.sequence_numbers.insert(topic.clone(), 0);
+                        println!(
+                            "info: no sequence number is associated to topic {}. Creating one",
+                            topic
+                        );
+                    }
+                }
+
+                Message::UNSUB { ip: _, topic } => {
+                    // NOSUB answers an UNSUB sent again after its reply was lost
+                    if result.is_err() && !matches!(result, Err(rpubsub::ServiceError::NOSUB)) {
+                        println!(
+                            "error: cannot unsubsribe to topic. topic: {}; reason: {:?}",
+                            topic,
+                            &result.as_ref().unwrap_err()
+                        );
+                        return;
+                    }
+
+                    if client.state.sequence_numbers.contains_key(topic) {
+                        client.state.sequence_numbers.remove(topic);
+                        println!(
+                            "info: removed sequence number associated to topic {}",
+                            topic
+                        );
+                    }
+                }
+
+                Message::PUT {
+                    ip: _,
+                    topic,
+                    sequence_num,
+                    payload: _,
+                    options: _,
+                    compression: _,
+                } => {
+                    match result {
+                        // An earlier attempt of this PUT was published, so it is done
+                        Err(rpubsub::ServiceError::ALREAPUT { last }) if last == sequence_num => {
+                            client.state.put_counters.insert(topic.clone(), last + 1);
+                            return;
+                        }
+                        // The counter is behind the server's, as when the state was lost: the
+                        // PUT wasn't published and goes on from the server's count
+                        Err(rpubsub::ServiceError::ALREAPUT { last }) => {
+                            client.state.put_counters.insert(topic.clone(), last + 1);
+                            println!("error: put counter of topic {} was behind the server, PUT again. last: {}", topic, last);
+                            return;
+                        }
+                        _ => (),
+                    }
+
+                    if result.is_err() {
+                        println!(
+                            "error: cannot put message on topic. topic: {}; reason: {:?}",
+                            topic,
+                            &result.as_ref().unwrap_err()
+                        );
+                        return;
+                    }
+
+                    if !client.state.put_counters.contains_key(topic) {
+                        client.state.put_counters.insert(topic.clone(), 0);
+                        println!(
+                            "info: no put counter is associated to topic {}. Creating one",
+                            topic
+                        );
+                    }
+
+                    let reply_option = result.as_ref().unwrap();
+
+                    match reply_option {
+                        rpubsub::ReplyOption::NoOk | rpubsub::ReplyOption::PUTOK(_) => {
+                            if let Some(counter) = client.state.put_counters.get_mut(topic) {
+                                *counter += 1;
+                            }
+
+                            if let rpubsub::ReplyOption::PUTOK(receipt) = reply_option {
+                                if receipt.backpressure {
+                                    println!("warning: subscribers of topic {} are lagging behind, slow down", topic);
+                                }
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+
+                Message::GET {
+                    ip: _,
+                    topic,
+                    sequence_num,
+                    accept: _,
+                } => {
+                    if result.is_err() {
+                        println!(
+                            "error: cannot get message from topic. topic: {}; reason: {:?}",
+                            topic,
+                            &result.as_ref().unwrap_err()
+                        );
+                        return;
+                    }
+
+                    let reply_option = result.as_ref().unwrap();
+
+                    match reply_option {
+                        rpubsub::ReplyOption::TUP(tup) => {
+                            if *sequence_num == tup.1 {
+                                if tup.0.is_some() {
+                                    if let Some(counter) =
+                                        client.state.sequence_numbers.get_mut(topic)
+                                    {
+                                        *counter += 1;
+                                    }
+                                }
+                            } else {
+                                println!("error: receiving outdated messages. topic: {} seq_nums(client, server): ({}, {})", topic, sequence_num, tup.1);
+                                println!("info: Syncronizing local sequence number with server");
+
+                                if let Some(counter) = client.state.put_counters.get_mut(topic) {
+                                    *counter = tup.1;
+                                }
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+
+                Message::LEAVE { ip: _, topic, group } => {
+                    if result.is_err() {
+                        println!(
+                            "error: cannot leave group. topic: {}; group: {}; reason: {:?}",
+                            topic,
+                            group,
+                            &result.as_ref().unwrap_err()
+                        );
+                        return;
+                    }
+
+                    if let Some(groups) = client.state.group_deliveries.get_mut(topic) {
+                        groups.remove(group);
+                    }
+                }
+
+                Message::GGET {
+                    ip: _,
+                    topic,
+                    group,
+                    ack: _,
+                    accept: _,
+                } => {
+                    if result.is_err() {
+                        println!(
+                            "error: cannot get message from group. topic: {}; group: {}; reason: {:?}",
+                            topic,
+                            group,
+                            &result.as_ref().unwrap_err()
+                        );
+                        return;
+                    }
+
+                    // The delivery id is sent back on the next GGET to acknowledge the update
+                    if let rpubsub::ReplyOption::TUP((Some(_), delivery_id)) = result.as_ref().unwrap() {
+                        client
+                            .state
+                            .group_deliveries
+                            .entry(topic.clone())
+                            .or_default()
+                            .insert(group.clone(), *delivery_id);
+                    }
+                }
+
+                Message::QPUT { queue, sequence_num, .. } => {
+                    match result {
+                        Ok(_) => {
+                            client.state.queue_counters.insert(queue.clone(), sequence_num + 1);
+                        }
+                        // An earlier attempt of this QPUT was enqueued, so it is done
+                        Err(rpubsub::ServiceError::ALREAPUT { last }) if last == sequence_num => {
+                            client.state.queue_counters.insert(queue.clone(), last + 1);
+                        }
+                        Err(rpubsub::ServiceError::ALREAPUT { last }) => {
+                            client.state.queue_counters.insert(queue.clone(), last + 1);
+                            println!("error: put counter of queue {} was behind the server, QPUT again. last: {}", queue, last);
+                        }
+                        Err(e) => println!("error: queue operation failed. queue: {}; reason: {:?}", queue, e),
+                    }
+                }
+
+                Message::QGET { queue, .. }
+                | Message::QACK { queue, .. }
+                | Message::QNACK { queue, .. } => {
+                    if let Err(e) = result {
+                        println!(
+                            "error: queue operation failed. queue: {}; reason: {:?}",
+                            queue, e
+                        );
+                    }
+                }
+
+                Message::CONF { ip: _, topic, option } => {
+                    if result.is_err() {
+                        println!(
+                            "error: cannot configure topic. topic: {}; option: {:?}; reason: {:?}",
+                            topic,
+                            option,
+                            &result.as_ref().unwrap_err()
+                        );
+                    }
+                }
+
+                Message::TXPUT { ip: _, sequence_num, updates } => {
+                    let topics: Vec<&String> = updates.iter().map(|update| &update.topic).collect();
+
+                    match result {
+                        Ok(rpubsub::ReplyOption::TXOK(receipts)) => {
+                            client.state.tx_counter += 1;
+
+                            for (update, receipt) in updates.iter().zip(receipts) {
+                                if receipt.backpressure {
+                                    println!("warning: subscribers of topic {} are lagging behind, slow down", update.topic);
+                                }
+                            }
+                        },
+                        Ok(_) => client.state.tx_counter += 1,
+                        // An earlier attempt of this transaction was committed, so it is done
+                        Err(rpubsub::ServiceError::ALREAPUT { last }) if last == sequence_num => client.state.tx_counter = last + 1,
+                        // The counter is behind the server's: the transaction wasn't committed
+                        Err(rpubsub::ServiceError::ALREAPUT { last }) => {
+                            client.state.tx_counter = last + 1;
+                            println!("error: transaction counter was behind the server, TXPUT again. last: {}", last);
+                        },
+                        Err(err) => println!("error: cannot put transaction. topics: {:?}; reason: {:?}", topics, err),
+                    }
+                }
+
+                // Message::UP { ip: _, sequence_nums } => {},
+                _ => (),
+            };
+        }
+        _ => (),
+    }
+}
+
+pub fn send_message_with_retries(transport: &impl Transport, message: &Message) -> Message {
+    match transport.send(message) {
+        Ok(_) => {
+            println!("Sent message");
+            
+            match transport.receive() {
+            Ok(reply) => return reply,
+            Err(_) => return rpubsub::Message::NOMSG,
+            };
+        },
+
+        Err(_) => return rpubsub::Message::NOMSG,
+    };
+
+}
+
+// Runs one line of input: a setting, a key operation, a file transfer or a request
+pub fn process_line(client: &mut Client, transport: &impl Transport, line: &str) -> Result<(), String> {
+    if let Some(res) = process_setting(client, line) {
+        return res;
+    }
+
+    if let Some(res) = keyring::process_keys(client, line) {
+        return res;
+    }
+
+    if let Some(res) = transfer::process_transfer(client, transport, line) {
+        return res;
+    }
+
+    let request = process_operation(client, line)?;
+
+    send_request(client, transport, &request).map(|_| ())
+}
+
+// Sends the request and handles its reply, which is returned once the state is saved
+pub fn send_request(client: &mut Client, transport: &impl Transport, request: &Message) -> Result<Message, String> {
+    let reply = decompress_reply(send_message_with_retries(transport, request));
+    let reply = keyring::open_reply(&client.keyring, request, reply);
+
+    println!("Received reply {}", reply.to_log_string());
+
+    process_reply(client, request, &reply);
+
+    save_state(client).map_err(|e| format!("error: while saving state. e: {}", e))?;
+
+    Ok(reply)
+}
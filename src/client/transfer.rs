@@ -0,0 +1,219 @@
+use rpubsub::transport::Transport;
+use rpubsub::Message;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{keyring, parse_put_options, save_state, send_message_with_retries, Client};
+
+// Upload that didn't finish, picked up again by the next PUTFILE of the same file on the same topic
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PendingUpload {
+    pub topic: String,
+    pub path: String,
+    pub upload_id: String,
+    pub next_chunk: u64,
+    // Version of the topic key the chunks are sealed with, the latest when the upload started
+    #[serde(default)]
+    pub key_version: Option<u32>,
+}
+
+// Handles the operations that take one request per chunk. Returns None for the others.
+pub fn process_transfer(client: &mut Client, transport: &impl Transport, op: &str) -> Option<Result<(), String>> {
+    let operands: Vec<&str> = op.split(" ").collect();
+
+    match operands[0] {
+        "PUTFILE" if operands.len() >= 3 => Some(upload_file(client, transport, operands[1], operands[2], &operands[3..])),
+        "GETFILE" if operands.len() == 4 => Some(download_file(client, transport, operands[1], operands[2], operands[3])),
+        "PUTFILE" | "GETFILE" => Some(Err(String::from("error: missing parameters"))),
+        _ => None,
+    }
+}
+
+fn read_chunk(file: &mut fs::File, index: u64, chunk_size: usize, size: u64) -> Result<Vec<u8>, String> {
+    let offset = index * chunk_size as u64;
+    let mut data = vec![0; (size - offset).min(chunk_size as u64) as usize];
+
+    file.seek(SeekFrom::Start(offset))
+        .and_then(|_| file.read_exact(&mut data))
+        .map_err(|e| format!("error: cannot read file. e: {}", e))?;
+
+    Ok(data)
+}
+
+fn upload_file(client: &mut Client, transport: &impl Transport, topic: &str, path: &str, options: &[&str]) -> Result<(), String> {
+    let options = parse_put_options(options)?;
+
+    let mut file = fs::File::open(path).map_err(|e| format!("error: cannot open file. e: {}", e))?;
+    let size = file.metadata().map_err(|e| format!("error: cannot open file. e: {}", e))?.len();
+
+    if size == 0 {
+        return Err(String::from("error: cannot put an empty file"));
+    }
+
+    let pending = client.state.uploads.iter().position(|upload| upload.topic == topic && upload.path == path);
+
+    let pos = match pending {
+        Some(pos) => {
+            println!("info: resuming upload of {} from chunk {}", path, client.state.uploads[pos].next_chunk);
+            pos
+        }
+        None => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+
+            client.state.uploads.push(PendingUpload {
+                topic: String::from(topic),
+                path: String::from(path),
+                upload_id: format!("{}-{}", client.ip, now),
+                next_chunk: 0,
+                key_version: keyring::latest_version(&client.keyring, topic),
+            });
+            client.state.uploads.len() - 1
+        }
+    };
+
+    let upload_id = client.state.uploads[pos].upload_id.clone();
+    let key_version = client.state.uploads[pos].key_version;
+    let mut index = client.state.uploads[pos].next_chunk;
+
+    let chunk_size = keyring::file_chunk_size(key_version);
+    let chunks = size.div_ceil(chunk_size as u64);
+
+    while index < chunks {
+        let mut data = read_chunk(&mut file, index, chunk_size, size)?;
+
+        if let Some(version) = key_version {
+            data = keyring::seal_chunk(&client.keyring, topic, version, &upload_id, index, &data)?;
+        }
+
+        let message = Message::CHUNK {
+            ip: client.ip.clone(),
+            upload_id: upload_id.clone(),
+            index,
+            data: rpubsub::encode_chunk(&data),
+        };
+
+        let next = match send_message_with_retries(transport, &message) {
+            Message::REP { result: Ok(rpubsub::ReplyOption::CHUNKOK(next)) } => next,
+            // The server has the upload up to another chunk, go on from there
+            Message::REP { result: Err(rpubsub::ServiceError::BADCHUNK { expected }) } if expected != index => expected,
+            Message::REP { result: Err(err) } => return Err(format!("error: cannot put file on topic. topic: {}; reason: {:?}", topic, err)),
+            _ => return Err(format!("error: no reply from server. PUTFILE {} {} again to resume", topic, path)),
+        };
+
+        index = next;
+        client.state.uploads[pos].next_chunk = index;
+
+        if let Err(e) = save_state(client) {
+            println!("error: while saving state. e: {}", e);
+        }
+
+        println!("info: sent chunk {}/{}", index, chunks);
+    }
+
+    // Subscribers get the file name as the payload, sealed like the chunks
+    let name = path.rsplit('/').next().unwrap();
+
+    let payload = match key_version {
+        Some(version) => keyring::seal_version(&client.keyring, topic, version, name, rpubsub::Compression::NONE)?,
+        None => String::from(name),
+    };
+
+    let message = Message::COMMIT {
+        ip: client.ip.clone(),
+        upload_id,
+        topic: String::from(topic),
+        payload,
+        options: rpubsub::PutOptions { key_version, ..options },
+    };
+
+    let reply = send_message_with_retries(transport, &message);
+    println!("Received reply {}", reply.to_log_string());
+
+    match reply {
+        Message::REP { result: Ok(_) } => {
+            client.state.uploads.remove(pos);
+
+            if let Err(e) = save_state(client) {
+                println!("error: while saving state. e: {}", e);
+            }
+
+            Ok(())
+        }
+        Message::REP { result: Err(err) } => Err(format!("error: cannot put file on topic. topic: {}; reason: {:?}", topic, err)),
+        _ => Err(format!("error: no reply from server. PUTFILE {} {} again to resume", topic, path)),
+    }
+}
+
+// Downloads go on from the last complete chunk already in the file
+fn download_file(client: &mut Client, transport: &impl Transport, topic: &str, blob_id: &str, path: &str) -> Result<(), String> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| format!("error: cannot open file. e: {}", e))?;
+
+    let len = file.metadata().map_err(|e| format!("error: cannot open file. e: {}", e))?.len();
+    let mut index = len / rpubsub::CHUNK_SIZE as u64;
+
+    // Encrypted files come in chunks holding less of the file, which is only known from
+    // the first chunk received. Their downloads then resume further.
+    let mut chunk_size = None;
+
+    if index > 0 {
+        println!("info: resuming download of {} from chunk {}", blob_id, index);
+    }
+
+    loop {
+        let message = Message::FETCH {
+            ip: client.ip.clone(),
+            topic: String::from(topic),
+            blob_id: String::from(blob_id),
+            index,
+        };
+
+        let chunk = match send_message_with_retries(transport, &message) {
+            Message::REP { result: Ok(rpubsub::ReplyOption::DATA(chunk)) } => chunk,
+            // The file was already complete
+            Message::REP { result: Err(rpubsub::ServiceError::BADCHUNK { expected }) } if expected == index => break,
+            Message::REP { result: Err(err) } => return Err(format!("error: cannot get file from topic. topic: {}; reason: {:?}", topic, err)),
+            _ => return Err(format!("error: no reply from server. GETFILE {} {} {} again to resume", topic, blob_id, path)),
+        };
+
+        let first = chunk_size.is_none();
+        let size = *chunk_size.get_or_insert(keyring::file_chunk_size(chunk.key_version) as u64);
+
+        if first && len / size != index {
+            index = len / size;
+            continue;
+        }
+
+        let mut data = match rpubsub::decode_chunk(&chunk.data) {
+            Some(data) => data,
+            None => return Err(String::from("error: received a corrupted chunk")),
+        };
+
+        if let Some(version) = chunk.key_version {
+            data = keyring::open_chunk(&client.keyring, topic, version, blob_id, index, &data)?;
+        }
+
+        let offset = index * size;
+
+        file.set_len(offset)
+            .and_then(|_| file.seek(SeekFrom::Start(offset)))
+            .and_then(|_| file.write_all(&data))
+            .map_err(|e| format!("error: cannot write file. e: {}", e))?;
+
+        index += 1;
+
+        println!("info: received chunk {}/{}", index, chunk.chunks);
+
+        if index >= chunk.chunks {
+            break;
+        }
+    }
+
+    Ok(())
+}
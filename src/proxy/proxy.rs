@@ -1,31 +1,534 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rpubsub::Endpoint;
+use serde::Deserialize;
 use std::env;
-use rpubsub::SocketAddress;
-//use zmq;
+use std::fs;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+// Sits between the clients and the server, forwarding every message and, as told by the
+// chaos file, dropping, delaying, duplicating or corrupting them, in both directions,
+// cutting the server off the network for a while, and crashing the server when the
+// proxy runs it.
+//
+//     proxy <BIND_ENDPOINT> <SERVER_ENDPOINT> [<CHAOS_FILE>]
+//
+// The clients connect to the proxy instead of the server. Without a chaos file the
+// messages go through untouched.
+
+// What the proxy does to the messages going through. Probabilities are from 0 to 1 and
+// drawn for each message.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Chaos {
+    // Runs with the same seed and the same messages get the same faults. Random when unset.
+    seed:         Option<u64>,
+    drop:         f64,
+    duplicate:    f64,
+    corrupt:      f64,
+    // Each copy of a message is delayed on its own, so delays also reorder them
+    min_delay_ms: u64,
+    max_delay_ms: u64,
+    // The server is cut off the network: the messages on the way are lost, and so is
+    // everything sent to or from it in the next partition_ms milliseconds. The server
+    // process keeps running.
+    partition:    f64,
+    partition_ms: u64,
+    // The command line of the server, e.g. ["target/debug/server", "tcp://127.0.0.1:5555"],
+    // for the proxy to run it. Crashes need it.
+    server_command: Vec<String>,
+    // The server process is killed, losing what it didn't save and the messages on their
+    // way to it, and started again restart_ms milliseconds later. The messages sent to it
+    // meanwhile are lost.
+    crash:          f64,
+    restart_ms:     u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+enum Side {
+    CLIENTS,
+    SERVER,
+}
+
+struct Packet {
+    due:    Instant,
+    // Keeps the packets due at the same time in the order they came
+    order:  u64,
+    to:     Side,
+    frames: Vec<Vec<u8>>,
+}
+
+struct Proxy {
+    chaos:      Chaos,
+    rng:        StdRng,
+    received:   u64,
+    in_flight:  Vec<Packet>,
+    cut_until:  Option<Instant>,
+    // The server process, while the proxy runs it
+    server:     Option<Child>,
+    down_until: Option<Instant>,
+}
+
+fn load_chaos(path: &String) -> Result<Chaos, String> {
+    let content = fs::read(path).map_err(|e| format!("error: couldn't read chaos file {}: {}", path, e))?;
+    let chaos: Chaos = serde_json::from_slice(&content).map_err(|e| format!("error: invalid chaos file {}: {}", path, e))?;
+
+    for (name, probability) in [("drop", chaos.drop), ("duplicate", chaos.duplicate), ("corrupt", chaos.corrupt), ("partition", chaos.partition),
+                                ("crash", chaos.crash)] {
+        if !(0.0..=1.0).contains(&probability) {
+            return Err(format!("error: {} must be between 0 and 1 in chaos file {}", name, path));
+        }
+    }
+
+    if chaos.min_delay_ms > chaos.max_delay_ms {
+        return Err(format!("error: min_delay_ms is over max_delay_ms in chaos file {}", path));
+    }
+
+    if chaos.crash > 0.0 && chaos.server_command.is_empty() {
+        return Err(format!("error: crash needs a server_command in chaos file {}", path));
+    }
+
+    Ok(chaos)
+}
+
+fn start_server(command: &[String]) -> Result<Child, String> {
+    Command::new(&command[0])
+        .args(&command[1..])
+        .spawn()
+        .map_err(|e| format!("error: couldn't start the server {}: {}", command[0], e))
+}
+
+impl Proxy {
+    fn new(chaos: Chaos) -> Result<Self, String> {
+        let seed = chaos.seed.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64);
+        println!("chaos: seed {}", seed);
+
+        let server = if chaos.server_command.is_empty() { None } else { Some(start_server(&chaos.server_command)?) };
+
+        Ok(Self { chaos, rng: StdRng::seed_from_u64(seed), received: 0, in_flight: Vec::new(), cut_until: None, server, down_until: None })
+    }
+
+    // Kills the server process and waits for it to be gone
+    fn stop_server(&mut self) {
+        if let Some(mut server) = self.server.take() {
+            let _ = server.kill();
+            let _ = server.wait();
+        }
+    }
+
+    fn crash_server(&mut self, now: Instant) {
+        self.stop_server();
+
+        let lost = self.in_flight.iter().filter(|packet| packet.to == Side::SERVER).count();
+        self.in_flight.retain(|packet| packet.to == Side::CLIENTS);
+        self.down_until = Some(now + Duration::from_millis(self.chaos.restart_ms));
+
+        println!("chaos: server crashed, {} messages lost on the way, restarting in {}ms", lost + 1, self.chaos.restart_ms);
+    }
+
+    // Starts the crashed server again once restart_ms went by, or tries again restart_ms later
+    fn restart_server(&mut self, now: Instant) {
+        if self.down_until.is_none_or(|down_until| now < down_until) {
+            return;
+        }
+
+        match start_server(&self.chaos.server_command) {
+            Ok(server) => {
+                println!("chaos: server restarted");
+                self.server = Some(server);
+                self.down_until = None;
+            },
+            Err(e) => {
+                println!("{}", e);
+                self.down_until = Some(now + Duration::from_millis(self.chaos.restart_ms));
+            },
+        }
+    }
+
+    fn is_server_cut_off(&mut self, now: Instant) -> bool {
+        match self.cut_until {
+            Some(cut_until) if now < cut_until => true,
+            Some(_) => {
+                println!("chaos: partition healed");
+                self.cut_until = None;
+                false
+            },
+            None => false,
+        }
+    }
+
+    // The frames are [client identity, empty delimiter, message] both ways
+    fn forward(&mut self, to: Side, frames: Vec<Vec<u8>>, now: Instant) {
+        self.received += 1;
+
+        if self.is_server_cut_off(now) {
+            println!("chaos: lost message to {:?}, the server is cut off", to);
+            return;
+        }
+
+        if to == Side::SERVER && self.down_until.is_some() {
+            println!("chaos: lost message to {:?}, the server is down", to);
+            return;
+        }
+
+        if self.rng.gen_bool(self.chaos.partition) {
+            println!("chaos: server cut off for {}ms, {} messages lost on the way", self.chaos.partition_ms, self.in_flight.len() + 1);
+            self.in_flight.clear();
+            self.cut_until = Some(now + Duration::from_millis(self.chaos.partition_ms));
+            return;
+        }
+
+        if self.server.is_some() && self.rng.gen_bool(self.chaos.crash) {
+            self.crash_server(now);
+            return;
+        }
+
+        if self.rng.gen_bool(self.chaos.drop) {
+            println!("chaos: dropped message to {:?}", to);
+            return;
+        }
+
+        let copies = if self.rng.gen_bool(self.chaos.duplicate) {
+            println!("chaos: duplicated message to {:?}", to);
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            let mut frames = frames.clone();
+
+            if self.rng.gen_bool(self.chaos.corrupt) {
+                self.corrupt(&mut frames);
+                println!("chaos: corrupted message to {:?}", to);
+            }
+
+            let delay = self.rng.gen_range(self.chaos.min_delay_ms..=self.chaos.max_delay_ms);
+
+            self.in_flight.push(Packet { due: now + Duration::from_millis(delay), order: self.received, to, frames });
+        }
+    }
+
+    // Overwrites a few random bytes of the message, leaving the envelope alone
+    fn corrupt(&mut self, frames: &mut [Vec<u8>]) {
+        let message = match frames.last_mut() {
+            Some(message) if !message.is_empty() => message,
+            _ => return,
+        };
+
+        for _ in 0..self.rng.gen_range(1..=3) {
+            let pos = self.rng.gen_range(0..message.len());
+            message[pos] = self.rng.gen();
+        }
+    }
+
+    // Takes out the packets that are due, in order
+    fn take_due(&mut self, now: Instant) -> Vec<Packet> {
+        let (mut due, in_flight): (Vec<Packet>, Vec<Packet>) = self.in_flight.drain(..).partition(|packet| packet.due <= now);
+        self.in_flight = in_flight;
+
+        due.sort_by_key(|packet| (packet.due, packet.order));
+        due
+    }
+
+    // How long to wait for messages before the next packet is due or the server is to be
+    // restarted, -1 for as long as it takes
+    fn poll_timeout_ms(&self, now: Instant) -> i64 {
+        match self.in_flight.iter().map(|packet| packet.due).chain(self.down_until).min() {
+            Some(due) => due.saturating_duration_since(now).as_millis() as i64 + 1,
+            None => -1,
+        }
+    }
+}
+
+// The server the proxy runs doesn't outlive it
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.stop_server();
+    }
+}
+
+fn run_proxy(frontend: &zmq::Socket, backend: &zmq::Socket, mut proxy: Proxy) {
+    loop {
+        let mut items = [frontend.as_poll_item(zmq::POLLIN), backend.as_poll_item(zmq::POLLIN)];
+
+        if let Err(e) = zmq::poll(&mut items, proxy.poll_timeout_ms(Instant::now())) {
+            println!("error: couldn't poll the sockets - {}", e);
+            continue;
+        }
+
+        // The server only sees the proxy's key, so the proxy checks the clients' own
+        if items[0].is_readable() {
+            if let Ok((mut frames, user_id)) = rpubsub::recv_multipart_with_user_id(frontend, 0) {
+                if frames.last().is_some_and(|request| rpubsub::is_allowed_sender(request, user_id.as_deref())) {
+                    proxy.forward(Side::SERVER, frames, Instant::now());
+                } else {
+                    rpubsub::reject_sender(&mut frames);
+                    let _ = frontend.send_multipart(frames, 0);
+                }
+            }
+        }
+
+        if items[1].is_readable() {
+            if let Ok(frames) = backend.recv_multipart(0) {
+                proxy.forward(Side::CLIENTS, frames, Instant::now());
+            }
+        }
+
+        proxy.restart_server(Instant::now());
+
+        for packet in proxy.take_due(Instant::now()) {
+            let socket = if packet.to == Side::SERVER { backend } else { frontend };
+
+            if let Err(e) = socket.send_multipart(packet.frames, 0) {
+                println!("error: couldn't send message - {}", e);
+            }
+        }
+    }
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 3 {
+    if args.len() < 3 || args.len() > 4 {
         println!("wrong number of arguments");
-        println!("Usage: server <IP> <BIND_PORT>")
+        println!("Usage: proxy <BIND_ENDPOINT> <SERVER_ENDPOINT> [<CHAOS_FILE>]");
+        return;
     }
 
-    let server_addr = SocketAddress{ip: args[1].clone(), port: args[2].clone().parse::<u16>().unwrap()};
-    let context = zmq::Context::new();
+    let parsed = (
+        Endpoint::parse(&args[1]),
+        Endpoint::parse(&args[2]),
+        args.get(3).map_or(Ok(Chaos::default()), load_chaos),
+        rpubsub::load_curve_config(),
+    );
 
-    let router_socket = match context.socket(zmq::ROUTER) {
-                            Ok(socket) => socket,
-                            Err(e) => panic!("Creating router socket; {}", e),
-                        };
+    let (bind_endpoint, server_endpoint, chaos, curve_config) = match parsed {
+        (Ok(bind_endpoint), Ok(server_endpoint), Ok(chaos), Ok(curve_config)) => (bind_endpoint, server_endpoint, chaos, curve_config),
+        (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => {
+            println!("{}", e);
+            return;
+        },
+    };
 
-    let endpoint = format!("tcp://{}:{}", server_addr.ip, server_addr.port);
+    let context = zmq::Context::new();
 
-    match router_socket.bind(&endpoint) {
-        Ok(()) => println!("Server listening at {}:{}", 
-                            server_addr.ip, server_addr.port),
+    let sockets = (context.socket(zmq::ROUTER), context.socket(zmq::DEALER));
 
-        Err(e) => panic!("Binding socket address {}:{}; {}", 
-                                server_addr.ip, server_addr.port, e)
+    let (frontend, backend) = match sockets {
+        (Ok(frontend), Ok(backend)) => (frontend, backend),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("error: couldn't create socket: {}", e);
+            return;
+        },
     };
-}
\ No newline at end of file
+
+    let _ = backend.set_linger(0);
+
+    // The proxy is a CURVE server to the clients and a CURVE client of the server, with
+    // the same keys
+    let secured = rpubsub::secure_server_socket(&context, &frontend, &curve_config)
+        .and_then(|_| rpubsub::secure_client_socket(&backend, &curve_config))
+        .and_then(|_| rpubsub::bind_to(&frontend, &bind_endpoint))
+        .and_then(|_| rpubsub::connect_to(&backend, &server_endpoint));
+
+    if let Err(e) = secured {
+        println!("{}", e.to_string());
+        return;
+    }
+
+    println!("Proxy listening on {}, forwarding to {}", bind_endpoint, server_endpoint);
+
+    match Proxy::new(chaos) {
+        Ok(proxy) => run_proxy(&frontend, &backend, proxy),
+        Err(e) => println!("{}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy(chaos: Chaos) -> Proxy {
+        Proxy::new(Chaos { seed: Some(7), ..chaos }).unwrap()
+    }
+
+    fn message(n: u8) -> Vec<Vec<u8>> {
+        vec![b"client".to_vec(), Vec::new(), vec![n; 16]]
+    }
+
+    fn messages(packets: &[Packet]) -> Vec<u8> {
+        packets.iter().map(|packet| packet.frames[2][0]).collect()
+    }
+
+    #[test]
+    fn untouched_without_chaos() {
+        let mut proxy = proxy(Chaos::default());
+        let now = Instant::now();
+
+        for n in 0..10 {
+            proxy.forward(Side::SERVER, message(n), now);
+        }
+
+        let due = proxy.take_due(now);
+
+        assert_eq!(messages(&due), (0..10).collect::<Vec<u8>>());
+        assert!(due.iter().enumerate().all(|(n, packet)| packet.to == Side::SERVER && packet.frames == message(n as u8)));
+        assert!(proxy.in_flight.is_empty());
+    }
+
+    #[test]
+    fn drops_every_message() {
+        let mut proxy = proxy(Chaos { drop: 1.0, ..Chaos::default() });
+        let now = Instant::now();
+
+        for n in 0..10 {
+            proxy.forward(Side::CLIENTS, message(n), now);
+        }
+
+        assert!(proxy.take_due(now).is_empty());
+    }
+
+    #[test]
+    fn duplicates_every_message_in_order() {
+        let mut proxy = proxy(Chaos { duplicate: 1.0, ..Chaos::default() });
+        let now = Instant::now();
+
+        for n in 0..5 {
+            proxy.forward(Side::SERVER, message(n), now);
+        }
+
+        assert_eq!(messages(&proxy.take_due(now)), vec![0, 0, 1, 1, 2, 2, 3, 3, 4, 4]);
+    }
+
+    #[test]
+    fn delays_reorder_and_hold_messages_until_due() {
+        let mut proxy = proxy(Chaos { min_delay_ms: 0, max_delay_ms: 100, ..Chaos::default() });
+        let now = Instant::now();
+
+        for n in 0..50 {
+            proxy.forward(Side::SERVER, message(n), now);
+        }
+
+        let early = proxy.take_due(now + Duration::from_millis(50));
+        assert!(early.iter().all(|packet| packet.due <= now + Duration::from_millis(50)));
+        assert!(early.windows(2).all(|pair| (pair[0].due, pair[0].order) <= (pair[1].due, pair[1].order)));
+
+        let late = proxy.take_due(now + Duration::from_millis(100));
+        assert!(proxy.in_flight.is_empty());
+
+        let mut all = messages(&early);
+        all.extend(messages(&late));
+
+        // Every message arrives once, though not in the order it was sent
+        assert_ne!(all, (0..50).collect::<Vec<u8>>());
+        all.sort();
+        assert_eq!(all, (0..50).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn corrupts_only_the_message() {
+        let mut proxy = proxy(Chaos { corrupt: 1.0, ..Chaos::default() });
+        let now = Instant::now();
+
+        for n in 0..20 {
+            proxy.forward(Side::SERVER, message(n), now);
+        }
+
+        let mut corrupted = 0;
+
+        for (n, packet) in proxy.take_due(now).iter().enumerate() {
+            let original = message(n as u8);
+
+            assert_eq!(packet.frames[..2], original[..2]);
+            assert_eq!(packet.frames[2].len(), original[2].len());
+
+            let changed = packet.frames[2].iter().zip(original[2].iter()).filter(|(a, b)| a != b).count();
+            assert!(changed <= 3);
+
+            if changed > 0 {
+                corrupted += 1;
+            }
+        }
+
+        // A byte may be overwritten with the value it had, but not in every message
+        assert!(corrupted > 0);
+
+        // Nothing to corrupt in an empty message
+        let mut empty = vec![b"client".to_vec(), Vec::new(), Vec::new()];
+        proxy.corrupt(&mut empty);
+        assert_eq!(empty, vec![b"client".to_vec(), Vec::new(), Vec::new()]);
+    }
+
+    #[test]
+    fn partition_loses_messages_until_it_heals() {
+        let mut proxy = proxy(Chaos { partition_ms: 1000, max_delay_ms: 10, min_delay_ms: 10, ..Chaos::default() });
+        let now = Instant::now();
+
+        proxy.forward(Side::SERVER, message(0), now);
+
+        proxy.chaos.partition = 1.0;
+        proxy.forward(Side::SERVER, message(1), now);
+        proxy.chaos.partition = 0.0;
+
+        // The message on the way is lost too
+        assert!(proxy.in_flight.is_empty());
+
+        proxy.forward(Side::CLIENTS, message(2), now + Duration::from_millis(999));
+        assert!(proxy.in_flight.is_empty());
+
+        let healed = now + Duration::from_millis(1000);
+        proxy.forward(Side::SERVER, message(3), healed);
+
+        assert_eq!(messages(&proxy.take_due(healed + Duration::from_millis(10))), vec![3]);
+    }
+
+    #[test]
+    fn crash_kills_and_restarts_the_server() {
+        let server_command = vec![String::from("sleep"), String::from("60")];
+        let mut proxy = proxy(Chaos { restart_ms: 1000, min_delay_ms: 10, max_delay_ms: 10, server_command, ..Chaos::default() });
+        let now = Instant::now();
+
+        let first = proxy.server.as_ref().unwrap().id();
+
+        proxy.forward(Side::SERVER, message(0), now);
+        proxy.forward(Side::CLIENTS, message(1), now);
+
+        proxy.chaos.crash = 1.0;
+        proxy.forward(Side::SERVER, message(2), now);
+        proxy.chaos.crash = 0.0;
+
+        // The process is gone with the request on its way, the reply it sent still arrives
+        assert!(proxy.server.is_none());
+        assert_eq!(messages(&proxy.in_flight), vec![1]);
+
+        proxy.forward(Side::SERVER, message(3), now + Duration::from_millis(999));
+        proxy.restart_server(now + Duration::from_millis(999));
+        assert!(proxy.server.is_none());
+
+        let restarted = now + Duration::from_millis(1000);
+        proxy.restart_server(restarted);
+        assert_ne!(proxy.server.as_ref().unwrap().id(), first);
+
+        proxy.forward(Side::SERVER, message(4), restarted);
+        assert_eq!(messages(&proxy.take_due(restarted + Duration::from_millis(10))), vec![1, 4]);
+    }
+
+    #[test]
+    fn same_seed_same_faults() {
+        let chaos = || Chaos { drop: 0.3, duplicate: 0.3, corrupt: 0.3, min_delay_ms: 0, max_delay_ms: 50, ..Chaos::default() };
+        let now = Instant::now();
+
+        let run = |mut proxy: Proxy| {
+            for n in 0..100 {
+                proxy.forward(Side::SERVER, message(n), now);
+            }
+
+            proxy.take_due(now + Duration::from_millis(50)).into_iter().map(|packet| (packet.due, packet.frames)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(proxy(chaos())), run(proxy(chaos())));
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use tokio::io::unix::AsyncFd;
+
+use crate::{CurveConfig, Endpoint, IOError, Message, ReplyOption, ServiceError};
+
+// How long AsyncClient waits for a reply
+const REQUEST_TIMEOUT_MS: u64 = 3000;
+
+// A zmq socket driven by tokio. Instead of blocking, operations wait on the socket's
+// signalling fd, which becomes readable whenever the socket's events may have changed.
+pub struct AsyncSocket {
+    // Declared first so it is dropped before the socket that owns the fd
+    fd:     AsyncFd<RawFd>,
+    socket: zmq::Socket,
+}
+
+impl AsyncSocket {
+    // Must be called from within a tokio runtime
+    pub fn new(socket: zmq::Socket) -> io::Result<Self> {
+        let fd = socket.get_fd()?;
+
+        Ok(Self { fd: AsyncFd::new(fd)?, socket })
+    }
+
+    pub fn socket(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    async fn ready(&self, events: zmq::PollEvents) -> Result<(), zmq::Error> {
+        loop {
+            if self.socket.get_events()?.intersects(events) {
+                return Ok(());
+            }
+
+            // Only fails when the runtime is shutting down
+            let mut guard = self.fd.readable().await.map_err(|_| zmq::Error::ETERM)?;
+            guard.clear_ready();
+        }
+    }
+
+    // Cancel safe: a message is either received whole or left in the socket
+    pub async fn recv_multipart(&self) -> Result<Vec<Vec<u8>>, zmq::Error> {
+        loop {
+            self.ready(zmq::POLLIN).await?;
+
+            match self.socket.recv_multipart(zmq::DONTWAIT) {
+                Err(zmq::Error::EAGAIN) => continue,
+                res => return res,
+            }
+        }
+    }
+
+    // Same as recv_multipart, with the sender's user id, see crate::recv_multipart_with_user_id
+    pub async fn recv_multipart_with_user_id(&self) -> Result<(Vec<Vec<u8>>, Option<String>), zmq::Error> {
+        loop {
+            self.ready(zmq::POLLIN).await?;
+
+            match crate::recv_multipart_with_user_id(&self.socket, zmq::DONTWAIT) {
+                Err(zmq::Error::EAGAIN) => continue,
+                res => return res,
+            }
+        }
+    }
+
+    pub async fn send_multipart(&self, frames: &[Vec<u8>]) -> Result<(), zmq::Error> {
+        loop {
+            self.ready(zmq::POLLOUT).await?;
+
+            match self.socket.send_multipart(frames.iter().map(|frame| frame.as_slice()), zmq::DONTWAIT) {
+                Err(zmq::Error::EAGAIN) => continue,
+                res => return res,
+            }
+        }
+    }
+
+    pub async fn send_message(&self, message: &Message) -> Result<(), IOError> {
+        self.send_multipart(&[message.to_string().into_bytes()]).await.map_err(IOError::ESND)
+    }
+
+    pub async fn receive_message(&self) -> Result<Message, IOError> {
+        let frames = self.recv_multipart().await.map_err(IOError::ERCV)?;
+
+        serde_json::from_slice(frames.last().map_or(&b""[..], |frame| frame)).map_err(IOError::EDSL)
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    IO(IOError),
+    SERVICE(ServiceError),
+    // The server replied with something else than what the request expects
+    UNEXPECTED(Box<Message>),
+}
+
+// Publisher and subscriber for async services, keeping the sequence numbers in memory.
+// It speaks the same protocol as the client application.
+pub struct AsyncClient {
+    socket:           AsyncSocket,
+    ip:               String,
+    sequence_numbers: HashMap<String, u128>,
+    put_counters:     HashMap<String, u128>,
+}
+
+impl AsyncClient {
+    pub fn connect(context: &zmq::Context, endpoint: &Endpoint, curve_config: &CurveConfig, ip: &str) -> Result<Self, IOError> {
+        let socket = context.socket(zmq::REQ).map_err(IOError::ECON)?;
+
+        // A request that timed out doesn't keep the socket from sending the next one,
+        // and its late reply is dropped
+        socket.set_req_relaxed(true)
+            .and_then(|_| socket.set_req_correlate(true))
+            .and_then(|_| socket.set_linger(0))
+            .map_err(IOError::ECON)?;
+
+        crate::secure_client_socket(&socket, curve_config)?;
+        crate::connect_to(&socket, endpoint)?;
+
+        let socket = AsyncSocket::new(socket).map_err(|e| IOError::ESEC(e.to_string()))?;
+
+        Ok(Self { socket, ip: String::from(ip), sequence_numbers: HashMap::new(), put_counters: HashMap::new() })
+    }
+
+    // Takes the client mutably: its REQ socket carries one request at a time, and a second
+    // one sent before the reply of the first would get the first's reply
+    pub async fn request(&mut self, message: &Message) -> Result<Message, IOError> {
+        self.socket.send_message(message).await?;
+
+        match tokio::time::timeout(Duration::from_millis(REQUEST_TIMEOUT_MS), self.socket.receive_message()).await {
+            Ok(reply) => reply,
+            Err(_) => Err(IOError::ERCV(zmq::Error::EAGAIN)),
+        }
+    }
+
+    async fn service_request(&mut self, message: &Message) -> Result<ReplyOption, ClientError> {
+        match self.request(message).await {
+            Ok(Message::REP { result: Ok(reply) }) => Ok(reply),
+            Ok(Message::REP { result: Err(err) }) => Err(ClientError::SERVICE(err)),
+            Ok(reply) => Err(ClientError::UNEXPECTED(Box::new(reply))),
+            Err(e) => Err(ClientError::IO(e)),
+        }
+    }
+
+    pub async fn subscribe(&mut self, topic: &str, filter: Option<String>) -> Result<(), ClientError> {
+        let message = Message::SUB { ip: self.ip.clone(), topic: String::from(topic), filter };
+
+        self.service_request(&message).await?;
+        self.sequence_numbers.entry(String::from(topic)).or_insert(0);
+
+        Ok(())
+    }
+
+    pub async fn unsubscribe(&mut self, topic: &str) -> Result<(), ClientError> {
+        let message = Message::UNSUB { ip: self.ip.clone(), topic: String::from(topic) };
+
+        self.service_request(&message).await?;
+        self.sequence_numbers.remove(topic);
+
+        Ok(())
+    }
+
+    // Puts the update with the topic's next sequence number, going on from the server's count
+    // when it is behind it. ALREAPUT for that sequence number means an earlier attempt of this
+    // PUT, whose reply was lost, was published: it is a success without a receipt.
+    pub async fn put(&mut self, topic: &str, payload: &str, options: crate::PutOptions) -> Result<Option<crate::PutReceipt>, ClientError> {
+        loop {
+            let sequence_num = *self.put_counters.entry(String::from(topic)).or_insert_with(crate::first_sequence_num);
+
+            let message = Message::PUT {
+                ip: self.ip.clone(),
+                topic: String::from(topic),
+                sequence_num,
+                payload: String::from(payload),
+                options: options.clone(),
+                compression: crate::Compression::NONE,
+            };
+
+            match self.service_request(&message).await {
+                Ok(ReplyOption::PUTOK(receipt)) => {
+                    self.put_counters.insert(String::from(topic), sequence_num + 1);
+                    return Ok(Some(receipt));
+                }
+                Ok(reply) => return Err(ClientError::UNEXPECTED(Box::new(Message::REP { result: Ok(reply) }))),
+                Err(ClientError::SERVICE(ServiceError::ALREAPUT { last })) => {
+                    self.put_counters.insert(String::from(topic), last + 1);
+
+                    if last == sequence_num {
+                        return Ok(None);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // The next update of a subscribed topic, acknowledging the previous one
+    pub async fn get(&mut self, topic: &str) -> Result<Option<crate::Delivery>, ClientError> {
+        let sequence_num = match self.sequence_numbers.get(topic) {
+            Some(sequence_num) => *sequence_num,
+            None => return Err(ClientError::SERVICE(ServiceError::NOSUB)),
+        };
+
+        let message = Message::GET { ip: self.ip.clone(), topic: String::from(topic), sequence_num, accept: crate::Compression::NONE };
+
+        match self.service_request(&message).await? {
+            ReplyOption::TUP((Some(delivery), _)) => {
+                self.sequence_numbers.insert(String::from(topic), sequence_num + 1);
+                delivery.decompressed().map(Some).ok_or(ClientError::SERVICE(ServiceError::BADPAYLOAD))
+            }
+            ReplyOption::TUP((None, _)) => Ok(None),
+            reply => Err(ClientError::UNEXPECTED(Box::new(Message::REP { result: Ok(reply) }))),
+        }
+    }
+}
@@ -3,46 +3,334 @@ extern crate strum_macros;
 extern crate serde;
 extern crate serde_json;
 extern crate sha2;
+extern crate base64;
+extern crate lz4_flex;
+extern crate chacha20poly1305;
 
 use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 
 use serde::{Serialize, Deserialize};
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
 
 use strum_macros::{IntoStaticStr};
 
+pub mod transport;
+
+// Async transport on tokio, see async_io.rs
+#[cfg(all(feature = "async", unix))]
+pub mod async_io;
+
 pub struct SocketAddress {
     pub ip:   String,
     pub port: u16,
 }
 
+// Where a socket binds or connects. IPC goes through a file on the same host and
+// INPROC between sockets of the same zmq::Context, e.g. a server and clients embedded
+// in one process.
+pub enum Endpoint {
+    TCP(SocketAddress),
+    IPC(String),
+    INPROC(String),
+}
+
+impl Endpoint {
+    // Parses tcp://<IP>:<PORT>, ipc://<PATH> or inproc://<NAME>
+    pub fn parse(endpoint: &str) -> Result<Endpoint, String> {
+        match endpoint.split_once("://") {
+            Some(("tcp", address)) => match address.rsplit_once(':').map(|(ip, port)| (ip, port.parse::<u16>())) {
+                Some((ip, Ok(port))) if !ip.is_empty() => Ok(Endpoint::TCP(SocketAddress { ip: String::from(ip), port })),
+                _ => Err(format!("error: invalid endpoint {}, expected tcp://<IP>:<PORT>", endpoint)),
+            },
+            Some(("ipc", path)) if !path.is_empty() => Ok(Endpoint::IPC(String::from(path))),
+            Some(("inproc", name)) if !name.is_empty() => Ok(Endpoint::INPROC(String::from(name))),
+            _ => Err(format!("error: invalid endpoint {}, expected tcp://<IP>:<PORT>, ipc://<PATH> or inproc://<NAME>", endpoint)),
+        }
+    }
+
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Endpoint::TCP(socket_address) => write!(f, "tcp://{}:{}", socket_address.ip, socket_address.port),
+            Endpoint::IPC(path) => write!(f, "ipc://{}", path),
+            Endpoint::INPROC(name) => write!(f, "inproc://{}", name),
+        }
+    }
+}
+
 pub type Topic = String;
 pub type SequenceNum = u128; 
 pub type UpdateContent = String;
 pub type MessageHash = String;
+pub type GroupName = String;
+pub type QueueName = String;
+pub type Timestamp = u128;
+pub type Headers = HashMap<String, String>;
+pub type BlobId = String;
+
+// Large payloads travel in chunks of this many bytes, the last one may be shorter
+pub const CHUNK_SIZE: usize = 1 << 20;
+// Payloads shorter than this aren't worth compressing
+pub const COMPRESSION_MIN_BYTES: usize = 256;
+// Compressed payloads may not decompress to more than this many bytes, larger ones
+// are sent and stored as they are, or should go through PUTFILE
+pub const MAX_DECOMPRESSED_BYTES: usize = 16 << 20;
+
+// The key the state files are encrypted with, base64, is read from the first variable
+// or from the file the second one names
+pub const STATE_KEY_VAR: &str = "RPUBSUB_STATE_KEY";
+pub const STATE_KEY_FILE_VAR: &str = "RPUBSUB_STATE_KEY_FILE";
+// Encrypted state files start with this, followed by the nonce and the ciphertext
+const ENCRYPTED_STATE_HEADER: &[u8] = b"RPUBSUB-ENC1\n";
+const NONCE_SIZE: usize = 12;
+// Bytes a blob chunk grows by when it is encrypted: the nonce and the tag
+pub const SEALED_CHUNK_OVERHEAD: usize = NONCE_SIZE + 16;
+
+// CURVE key files: the application's own keypair, the server public key the client
+// pins, and the client public keys the server lets in (none means any client)
+pub const CURVE_KEY_FILE_VAR: &str = "RPUBSUB_CURVE_KEY_FILE";
+pub const CURVE_SERVER_KEY_FILE_VAR: &str = "RPUBSUB_CURVE_SERVER_KEY_FILE";
+pub const CURVE_CLIENTS_FILE_VAR: &str = "RPUBSUB_CURVE_CLIENTS_FILE";
+// Client id of the keys that may make requests for any client, like a proxy's
+pub const ANY_CLIENT: &str = "*";
+const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
+
+#[derive(Clone)]
+pub struct StateKey([u8; 32]);
+
+// Keeps the key out of the logs
+impl std::fmt::Debug for StateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("StateKey(..)")
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, IntoStaticStr)]
 pub enum Message {
-    GET   { ip: String, topic: Topic, sequence_num: SequenceNum },
-    PUT   { ip: String, topic: Topic, sequence_num: SequenceNum, payload: UpdateContent },
-    SUB   { ip: String, topic: Topic },
+    GET   { ip: String, topic: Topic, sequence_num: SequenceNum, #[serde(default)] accept: Compression },
+    PUT   { ip: String, topic: Topic, sequence_num: SequenceNum, payload: UpdateContent, #[serde(default)] options: PutOptions,
+            #[serde(default)] compression: Compression },
+    SUB   { ip: String, topic: Topic, #[serde(default)] filter: Option<String> },
     UNSUB { ip: String, topic: Topic },
     UP    { ip: String, sequence_nums: HashMap<Topic, SequenceNum> },
+    JOIN  { ip: String, topic: Topic, group: GroupName },
+    LEAVE { ip: String, topic: Topic, group: GroupName },
+    GGET  { ip: String, topic: Topic, group: GroupName, ack: Option<SequenceNum>, #[serde(default)] accept: Compression },
+    QPUT  { ip: String, queue: QueueName, sequence_num: SequenceNum, payload: UpdateContent, #[serde(default)] compression: Compression },
+    QGET  { ip: String, queue: QueueName, #[serde(default)] accept: Compression },
+    QACK  { ip: String, queue: QueueName, message_id: SequenceNum },
+    QNACK { ip: String, queue: QueueName, message_id: SequenceNum },
+    CONF  { ip: String, topic: Topic, option: TopicOption },
+    TXPUT { ip: String, sequence_num: SequenceNum, updates: Vec<TxUpdate> },
+    // data is base64, see encode_chunk
+    CHUNK { ip: String, upload_id: BlobId, index: u64, data: String },
+    COMMIT { ip: String, upload_id: BlobId, topic: Topic, payload: UpdateContent, #[serde(default)] options: PutOptions },
+    FETCH { ip: String, topic: Topic, blob_id: BlobId, index: u64 },
     REP   { result: Result<ReplyOption, ServiceError> },
     NOMSG
 }
 
+// How a payload is encoded. Compressed payloads are base64, so they still fit in JSON.
+// Requests that get updates back say which one they accept, the server only uses it
+// when it makes the payload smaller.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum Compression {
+    #[default]
+    NONE,
+    LZ4
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct PutOptions {
+    // Milliseconds since the epoch before which subscribers don't see the update
+    pub deliver_at: Option<Timestamp>,
+    // Same as deliver_at, relative to when the server receives the update
+    pub delay_ms:   Option<u64>,
+    // For how long after being added to the topic the update is still worth delivering
+    pub ttl_ms:     Option<u64>,
+    // Publisher-defined attributes (content type, correlation id...) handed to the subscribers
+    pub headers:    Headers,
+    // On compacted topics only the latest update with a given key is kept
+    pub key:        Option<String>,
+    // Pending updates of a higher priority are delivered first, 0 by default
+    pub priority:    u8,
+    // Version of the topic key the payload is encrypted with, None if it is plaintext.
+    // The server only passes it on to the subscribers.
+    pub key_version: Option<u32>,
+}
+
+// One of the updates of a TXPUT, which are all added or none is
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxUpdate {
+    pub topic:   Topic,
+    pub payload: UpdateContent,
+    #[serde(default)]
+    pub options: PutOptions,
+    #[serde(default)]
+    pub compression: Compression,
+}
+
+// An update as handed to a subscriber. seq and timestamp are assigned by the server
+// when it accepts the update: seq grows by one with every update of the topic.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Delivery {
+    pub payload:     UpdateContent,
+    #[serde(default)]
+    pub headers:     Headers,
+    #[serde(default)]
+    pub seq:         SequenceNum,
+    #[serde(default)]
+    pub timestamp:   Timestamp,
+    // Large payload to FETCH chunk by chunk
+    #[serde(default)]
+    pub blob:        Option<BlobInfo>,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub key_version: Option<u32>,
+}
+
+impl Delivery {
+    pub fn compressed(mut self, compression: Compression) -> Delivery {
+        if self.compression == Compression::NONE {
+            (self.payload, self.compression) = compress_payload(&self.payload, compression);
+        }
+
+        self
+    }
+
+    pub fn decompressed(mut self) -> Option<Delivery> {
+        self.payload = decompress_payload(&self.payload, self.compression)?;
+        self.compression = Compression::NONE;
+
+        Some(self)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlobInfo {
+    pub id:          BlobId,
+    pub size:        u64,
+    pub chunks:      u64,
+    // Version of the topic key the chunks are encrypted with, like the update's payload
+    #[serde(default)]
+    pub key_version: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chunk {
+    pub index:       u64,
+    pub chunks:      u64,
+    pub data:        String,
+    #[serde(default)]
+    pub key_version: Option<u32>,
+}
+
+// Reply to an accepted PUT. Scheduled updates only get their seq once they are due.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PutReceipt {
+    pub seq:          Option<SequenceNum>,
+    pub timestamp:    Timestamp,
+    // Subscribers of the topic are lagging behind and the publisher should slow down
+    #[serde(default)]
+    pub backpressure: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug, IntoStaticStr)]
 pub enum ReplyOption {
     NoOk,
-    TUP((Option<UpdateContent>, SequenceNum))
+    TUP((Option<Delivery>, SequenceNum)),
+    PUTOK(PutReceipt),
+    // One receipt per update of a TXPUT, in the same order
+    TXOK(Vec<PutReceipt>),
+    // Index of the next chunk the server expects for the upload
+    CHUNKOK(u64),
+    DATA(Chunk)
+}
+
+#[derive(Serialize, Deserialize, Debug, IntoStaticStr)]
+pub enum TopicOption {
+    DeadLetter(Option<Topic>),
+    Compaction(bool),
+    RateLimit(Option<RateLimit>),
+    // Payload bytes the topic may be storing at once
+    Quota(Option<usize>),
+    Watermarks(Option<Watermarks>)
+}
+
+// Number of updates waiting in a topic queue at which PUT replies start reporting
+// backpressure (high), stop reporting it (low) and PUTs are rejected (hard)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Watermarks {
+    pub high: usize,
+    pub low:  usize,
+    pub hard: Option<usize>,
 }
 
+// Token bucket: rate tokens are added every second, up to burst, and each update takes one
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub rate:  f64,
+    pub burst: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, IntoStaticStr)]
+pub enum DeadLetterReason {
+    MAXDELIV,
+    EXPIRED,
+    EVICTED
+}
+
+// Payload of the updates republished on a dead-letter topic
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeadLetter {
+    pub topic:          Topic,
+    pub reason:         DeadLetterReason,
+    pub delivery_count: u32,
+    pub payload:        UpdateContent,
+    #[serde(default)]
+    pub headers:        Headers,
+    // seq and timestamp of the update in the original topic
+    #[serde(default)]
+    pub seq:            SequenceNum,
+    #[serde(default)]
+    pub timestamp:      Timestamp,
+    // The payload is still encrypted with that version of the original topic's key
+    #[serde(default)]
+    pub key_version:    Option<u32>,
+}
+
+// Z85 CURVE keys. Public key files, given to the other side, have no secret.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CurveKeys {
+    pub public: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct CurveConfig {
+    pub keys:       Option<CurveKeys>,
+    pub server_key: Option<String>,
+    // [public key] = the client id, the ip of its requests, or ANY_CLIENT
+    pub clients:    Option<HashMap<String, String>>,
+}
+
+#[derive(Debug)]
 pub enum IOError {
     ECON(zmq::Error),
     EBIN(zmq::Error),
     ERCV(zmq::Error),
     ESND(zmq::Error),
     EDSL(serde_json::Error),
+    ESEC(String),
 }
 
 impl IOError {
@@ -53,6 +341,7 @@ impl IOError {
             IOError::ERCV(e) => format!("error: couldn't receive message - {}", e),
             IOError::ESND(e) => format!("error: couldn't send message - {}", e),
             IOError::EDSL(e) => format!("error: received unknown message - {}", e),
+            IOError::ESEC(e) => format!("error: couldn't set up CURVE security - {}", e),
         };
         return str;
     }
@@ -63,18 +352,367 @@ pub enum ServiceError {
     NOTOPIC,
     NOSUB,
     ALREASUB,
-    ALREAPUT,
+    // The sequence number was already used. last is the last one the server accepted from
+    // the publisher, which a publisher that lost count goes on from
+    ALREAPUT { last: SequenceNum },
+    NOQUEUE,
+    NOLEASE,
+    BADCONF,
+    BADFILTER,
+    // The publisher went over a rate limit and should wait that long before retrying
+    THROTTLED { retry_after_ms: u64 },
+    OVERQUOTA,
+    // The topic queue is over its hard watermark
+    OVERLOADED,
+    NOUPLOAD,
+    // Chunks must be sent in order, the upload goes on from the expected one
+    BADCHUNK { expected: u64 },
+    NOBLOB,
+    // The payload couldn't be decompressed
+    BADPAYLOAD,
+    // The ip of the request isn't the client id the sender's CURVE key is listed with
+    BADSENDER,
     UNKNOMSG
 }
 
 impl Message {
+    // The client the request is made for
+    pub fn ip(&self) -> Option<&String> {
+        match self {
+            Message::GET { ip, .. }
+            | Message::PUT { ip, .. }
+            | Message::SUB { ip, .. }
+            | Message::UNSUB { ip, .. }
+            | Message::UP { ip, .. }
+            | Message::JOIN { ip, .. }
+            | Message::LEAVE { ip, .. }
+            | Message::GGET { ip, .. }
+            | Message::QPUT { ip, .. }
+            | Message::QGET { ip, .. }
+            | Message::QACK { ip, .. }
+            | Message::QNACK { ip, .. }
+            | Message::CONF { ip, .. }
+            | Message::TXPUT { ip, .. }
+            | Message::CHUNK { ip, .. }
+            | Message::COMMIT { ip, .. }
+            | Message::FETCH { ip, .. } => Some(ip),
+            Message::REP { .. } | Message::NOMSG => None,
+        }
+    }
+
     pub fn to_string(&self) -> String {
         serde_json::to_string(self).unwrap()
     }
+
+    // The message as it is logged: file chunks are left out, only their length is kept
+    pub fn to_log_string(&self) -> String {
+        let stripped = |data: &String| format!("<{} chars>", data.len());
+
+        match self {
+            Message::CHUNK { ip, upload_id, index, data } => {
+                Message::CHUNK { ip: ip.clone(), upload_id: upload_id.clone(), index: *index, data: stripped(data) }.to_string()
+            },
+            Message::REP { result: Ok(ReplyOption::DATA(chunk)) } => {
+                Message::REP { result: Ok(ReplyOption::DATA(Chunk { data: stripped(&chunk.data), ..chunk.clone() })) }.to_string()
+            },
+            message => message.to_string(),
+        }
+    }
+}
+
+// Compresses the payload if that makes it smaller, returning how it ended up encoded.
+// Payloads over MAX_DECOMPRESSED_BYTES are left as they are, as nobody would decompress them.
+pub fn compress_payload(payload: &str, compression: Compression) -> (String, Compression) {
+    if compression == Compression::NONE || payload.len() < COMPRESSION_MIN_BYTES || payload.len() > MAX_DECOMPRESSED_BYTES {
+        return (String::from(payload), Compression::NONE);
+    }
+
+    let compressed = encode_chunk(&compress_bytes(payload.as_bytes()));
+
+    if compressed.len() < payload.len() {
+        (compressed, compression)
+    } else {
+        (String::from(payload), Compression::NONE)
+    }
 }
 
-pub fn bind_to(socket: &zmq::Socket, socket_address: &SocketAddress) -> Result<(), IOError> {
-    let endpoint = format!("tcp://{}:{}", socket_address.ip, socket_address.port);
+pub fn decompress_payload(payload: &str, compression: Compression) -> Option<String> {
+    match compression {
+        Compression::NONE => Some(String::from(payload)),
+        Compression::LZ4 => String::from_utf8(decompress_bytes(&decode_chunk(payload)?)?).ok(),
+    }
+}
+
+// LZ4 with the size prepended
+pub fn compress_bytes(data: &[u8]) -> Vec<u8> {
+    lz4_flex::compress_prepend_size(data)
+}
+
+pub fn decompress_bytes(compressed: &[u8]) -> Option<Vec<u8>> {
+    // The size is the sender's word, it is checked before allocating for it
+    let size = u32::from_le_bytes(compressed.get(..4)?.try_into().ok()?) as usize;
+
+    if size > MAX_DECOMPRESSED_BYTES {
+        return None;
+    }
+
+    lz4_flex::decompress_size_prepended(compressed).ok()
+}
+
+// Where a publisher with no count of its PUTs, because it is new or lost its state, starts
+// numbering them. The clock is past the numbers it used before unless it put more than
+// one a millisecond, and ALREAPUT tells it where to go on from otherwise.
+pub fn first_sequence_num() -> SequenceNum {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis()
+}
+
+// The state key set in the environment, if any
+pub fn load_state_key() -> Result<Option<StateKey>, String> {
+    let encoded = match (std::env::var(STATE_KEY_VAR), std::env::var(STATE_KEY_FILE_VAR)) {
+        (Ok(encoded), _) => encoded,
+        (Err(_), Ok(path)) => std::fs::read_to_string(&path).map_err(|e| format!("error: couldn't read state key file {}: {}", path, e))?,
+        _ => return Ok(None),
+    };
+
+    match decode_chunk(encoded.trim()).map(<[u8; 32]>::try_from) {
+        Some(Ok(key)) => Ok(Some(StateKey(key))),
+        _ => Err(String::from("error: the state key must be 32 bytes in base64")),
+    }
+}
+
+pub fn is_encrypted_state(data: &[u8]) -> bool {
+    data.starts_with(ENCRYPTED_STATE_HEADER)
+}
+
+// Contents of a state file, encrypted if there is a key
+pub fn seal_state(key: Option<&StateKey>, plaintext: Vec<u8>) -> Vec<u8> {
+    let key = match key {
+        Some(key) => key,
+        None => return plaintext,
+    };
+
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = ChaCha20Poly1305::new(&key.0.into()).encrypt(&nonce, plaintext.as_slice()).unwrap();
+
+    [ENCRYPTED_STATE_HEADER, nonce.as_slice(), &ciphertext].concat()
+}
+
+// Plaintext state files are read as they are, so they can be encrypted by saving them
+// again once a key is set
+pub fn open_state(key: Option<&StateKey>, data: Vec<u8>) -> Result<Vec<u8>, String> {
+    if !is_encrypted_state(&data) {
+        return Ok(data);
+    }
+
+    let key = match key {
+        Some(key) => key,
+        None => return Err(format!("error: the state is encrypted, set {} or {}", STATE_KEY_VAR, STATE_KEY_FILE_VAR)),
+    };
+
+    let data = &data[ENCRYPTED_STATE_HEADER.len()..];
+
+    if data.len() < NONCE_SIZE {
+        return Err(String::from("error: the state file is corrupted"));
+    }
+
+    ChaCha20Poly1305::new(&key.0.into())
+        .decrypt(Nonce::from_slice(&data[..NONCE_SIZE]), &data[NONCE_SIZE..])
+        .map_err(|_| String::from("error: couldn't decrypt the state, wrong key or corrupted file"))
+}
+
+// A chunk of a blob file encrypted with the state key. The associated data names the
+// chunk, so chunks can't be swapped around in the file.
+pub fn seal_blob_chunk(key: &StateKey, aad: &[u8], data: &[u8]) -> Vec<u8> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = ChaCha20Poly1305::new(&key.0.into()).encrypt(&nonce, Payload { msg: data, aad }).unwrap();
+
+    [nonce.as_slice(), &ciphertext].concat()
+}
+
+pub fn open_blob_chunk(key: &StateKey, aad: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < NONCE_SIZE {
+        return None;
+    }
+
+    ChaCha20Poly1305::new(&key.0.into()).decrypt(Nonce::from_slice(&data[..NONCE_SIZE]), Payload { msg: &data[NONCE_SIZE..], aad }).ok()
+}
+
+pub fn encode_chunk(data: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(data)
+}
+
+pub fn decode_chunk(data: &str) -> Option<Vec<u8>> {
+    base64::engine::general_purpose::STANDARD.decode(data).ok()
+}
+
+fn read_key_file<T: serde::de::DeserializeOwned>(var: &str) -> Result<Option<T>, String> {
+    let path = match std::env::var(var) {
+        Ok(path) => path,
+        Err(_) => return Ok(None),
+    };
+
+    let content = std::fs::read(&path).map_err(|e| format!("error: couldn't read key file {}: {}", path, e))?;
+
+    match serde_json::from_slice(&content) {
+        Ok(keys) => Ok(Some(keys)),
+        Err(e) => Err(format!("error: invalid key file {}: {}", path, e)),
+    }
+}
+
+// The CURVE key files set in the environment. Without any, connections are plaintext.
+pub fn load_curve_config() -> Result<CurveConfig, String> {
+    let server_key: Option<CurveKeys> = read_key_file(CURVE_SERVER_KEY_FILE_VAR)?;
+
+    Ok(CurveConfig {
+        keys: read_key_file(CURVE_KEY_FILE_VAR)?,
+        server_key: server_key.map(|keys| keys.public),
+        clients: read_key_file(CURVE_CLIENTS_FILE_VAR)?,
+    })
+}
+
+// Writes a new keypair to path and its public key alone to path.pub
+pub fn generate_curve_keys(path: &str) -> Result<(), String> {
+    let pair = zmq::CurveKeyPair::new().map_err(|e| format!("error: couldn't generate keys: {}", e))?;
+
+    let keys = CurveKeys {
+        public: zmq::z85_encode(&pair.public_key).unwrap(),
+        secret: Some(zmq::z85_encode(&pair.secret_key).unwrap()),
+    };
+    let public_keys = CurveKeys { public: keys.public.clone(), secret: None };
+
+    // Only the owner may read the secret key, even for a moment, so the file is created
+    // that way rather than changed after it is written. An older file is restricted too.
+    std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)
+        .and_then(|mut file| {
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+            file.write_all(serde_json::to_string(&keys).unwrap().as_bytes())
+        })
+        .and_then(|_| std::fs::write(format!("{}.pub", path), serde_json::to_string(&public_keys).unwrap()))
+        .map_err(|e| format!("error: couldn't write key file: {}", e))
+}
+
+fn decode_curve_key(key: &str) -> Result<Vec<u8>, IOError> {
+    match zmq::z85_decode(key) {
+        Ok(key) if key.len() == 32 => Ok(key),
+        _ => Err(IOError::ESEC(format!("invalid key {}", key))),
+    }
+}
+
+// Answers the ZAP requests of the context's CURVE sockets, letting in only the clients
+// whose public key is listed. Their client id is the user id of their messages.
+fn start_zap_handler(context: &zmq::Context, clients: HashMap<String, String>) -> Result<(), IOError> {
+    let socket = context.socket(zmq::REP).map_err(|e| IOError::ESEC(e.to_string()))?;
+    socket.bind(ZAP_ENDPOINT).map_err(IOError::EBIN)?;
+
+    std::thread::spawn(move || loop {
+        let request = match socket.recv_multipart(0) {
+            Ok(request) => request,
+            Err(_) => return,
+        };
+
+        // version, request id, domain, address, identity, mechanism, client public key
+        let client_key = request.get(6).and_then(|key| zmq::z85_encode(key).ok());
+        let client_id = client_key.and_then(|key| clients.get(&key));
+        let (status, text) = if client_id.is_some() { ("200", "OK") } else { ("400", "unknown client key") };
+
+        // version, request id, status code, status text, user id, metadata
+        let reply: Vec<&[u8]> = vec![b"1.0", request.get(1).map_or(&b""[..], |id| id), status.as_bytes(), text.as_bytes(),
+                                     client_id.map_or(&b""[..], |id| id.as_bytes()), b""];
+
+        if socket.send_multipart(reply, 0).is_err() {
+            return;
+        }
+    });
+
+    Ok(())
+}
+
+// Makes the socket a CURVE server if the config has a keypair. Must be called before bind_to.
+pub fn secure_server_socket(context: &zmq::Context, socket: &zmq::Socket, config: &CurveConfig) -> Result<(), IOError> {
+    let keys = match &config.keys {
+        Some(keys) => keys,
+        None => return Ok(()),
+    };
+
+    let secret = match &keys.secret {
+        Some(secret) => decode_curve_key(secret)?,
+        None => return Err(IOError::ESEC(String::from("the key file has no secret key"))),
+    };
+
+    if let Some(clients) = &config.clients {
+        start_zap_handler(context, clients.clone())?;
+    }
+
+    socket.set_curve_server(true)
+        .and_then(|_| socket.set_curve_secretkey(&secret))
+        .and_then(|_| socket.set_zap_domain("rpubsub"))
+        .map_err(|e| IOError::ESEC(e.to_string()))
+}
+
+// Makes the socket a CURVE client of the pinned server key, if there is one, with the
+// config's keypair or, without one, a throwaway keypair. Must be called before connect_to.
+pub fn secure_client_socket(socket: &zmq::Socket, config: &CurveConfig) -> Result<(), IOError> {
+    let server_key = match &config.server_key {
+        Some(server_key) => decode_curve_key(server_key)?,
+        None => return Ok(()),
+    };
+
+    let (public, secret) = match &config.keys {
+        Some(CurveKeys { public, secret: Some(secret) }) => (decode_curve_key(public)?, decode_curve_key(secret)?),
+        Some(_) => return Err(IOError::ESEC(String::from("the key file has no secret key"))),
+        None => {
+            let pair = zmq::CurveKeyPair::new().map_err(|e| IOError::ESEC(e.to_string()))?;
+            (pair.public_key.to_vec(), pair.secret_key.to_vec())
+        }
+    };
+
+    socket.set_curve_serverkey(&server_key)
+        .and_then(|_| socket.set_curve_publickey(&public))
+        .and_then(|_| socket.set_curve_secretkey(&secret))
+        .map_err(|e| IOError::ESEC(e.to_string()))
+}
+
+// Receives a multipart message along with the user id the ZAP handler gave its sender,
+// if any
+pub fn recv_multipart_with_user_id(socket: &zmq::Socket, flags: i32) -> Result<(Vec<Vec<u8>>, Option<String>), zmq::Error> {
+    let mut frames = Vec::new();
+
+    loop {
+        let mut frame = socket.recv_msg(flags)?;
+        let user_id = frame.gets("User-Id").filter(|user_id| !user_id.is_empty()).map(String::from);
+        let more = frame.get_more();
+
+        frames.push(frame.to_vec());
+
+        if !more {
+            return Ok((frames, user_id));
+        }
+    }
+}
+
+// With a clients file, a client only makes requests for the client id its key is listed
+// with. Requests that can't be read are let through, to be answered as such.
+pub fn is_allowed_sender(request: &[u8], user_id: Option<&str>) -> bool {
+    let user_id = match user_id {
+        Some(user_id) if user_id != ANY_CLIENT => user_id,
+        _ => return true,
+    };
+
+    match serde_json::from_slice::<Message>(request) {
+        Ok(message) => message.ip().is_none_or(|ip| ip == user_id),
+        Err(_) => true,
+    }
+}
+
+// The reply to a request is_allowed_sender turned down, in place of the request
+pub fn reject_sender(frames: &mut [Vec<u8>]) {
+    if let Some(request) = frames.last_mut() {
+        *request = Message::REP { result: Err(ServiceError::BADSENDER) }.to_string().into_bytes();
+    }
+}
+
+pub fn bind_to(socket: &zmq::Socket, endpoint: &Endpoint) -> Result<(), IOError> {
+    let endpoint = endpoint.to_string();
 
     match socket.bind(&endpoint) {
         Ok(()) => Ok(()),
@@ -83,8 +721,8 @@ pub fn bind_to(socket: &zmq::Socket, socket_address: &SocketAddress) -> Result<(
     }
 }
 
-pub fn connect_to(socket: &zmq::Socket, socket_address: &SocketAddress) -> Result<(), IOError> {
-    let endpoint = format!("tcp://{}:{}", socket_address.ip, socket_address.port);
+pub fn connect_to(socket: &zmq::Socket, endpoint: &Endpoint) -> Result<(), IOError> {
+    let endpoint = endpoint.to_string();
 
     match socket.connect(&endpoint) {
         Ok(()) => Ok(()),
@@ -111,10 +749,44 @@ pub fn receive_message_from(socket: &zmq::Socket) -> Result<Message, IOError> {
         Err(e) => return Err(IOError::ERCV(e)),
     };
     
-    let res: Result<Message, serde_json::Error> = serde_json::from_str(&msg.as_str().unwrap());
+    // A message that isn't UTF-8 is as unknown as one that isn't JSON
+    let res: Result<Message, serde_json::Error> = serde_json::from_slice(&msg);
     
     return match res {
         Ok(message) => Ok(message),
         Err(e) => Err(IOError::EDSL(e)),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A state sealed with a key only opens with that key, and a plaintext state, written
+    // before there was a key, is read as it is so it can be saved again encrypted
+    #[test]
+    fn sealed_state_needs_its_key() {
+        let key = StateKey([7; 32]);
+        let plaintext = br#"{"topics": {"orders": {}}}"#.to_vec();
+
+        let sealed = seal_state(Some(&key), plaintext.clone());
+        assert!(is_encrypted_state(&sealed));
+        assert!(!sealed.windows(6).any(|window| window == b"orders"));
+
+        // Every save gets its own nonce
+        assert_ne!(seal_state(Some(&key), plaintext.clone()), sealed);
+
+        assert_eq!(open_state(Some(&key), sealed.clone()).unwrap(), plaintext);
+        assert!(open_state(Some(&StateKey([8; 32])), sealed.clone()).is_err());
+        assert!(open_state(None, sealed.clone()).is_err());
+
+        let mut tampered = sealed;
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open_state(Some(&key), tampered).is_err());
+
+        // Migration from a plaintext state file
+        assert!(!is_encrypted_state(&plaintext));
+        assert_eq!(open_state(Some(&key), plaintext.clone()).unwrap(), plaintext);
+        assert_eq!(seal_state(None, plaintext.clone()), plaintext);
+    }
+}
@@ -0,0 +1,399 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::{IOError, Message};
+
+// A request-reply connection. On the requesting side receive returns the reply to the
+// last message sent, on the replying side send answers the last message received.
+pub trait Transport {
+    fn send(&self, message: &Message) -> Result<(), IOError>;
+
+    // Fails with ERCV(EAGAIN) when nothing arrived in time
+    fn receive(&self) -> Result<Message, IOError>;
+}
+
+// REQ and REP sockets already keep track of who to reply to
+impl Transport for zmq::Socket {
+    fn send(&self, message: &Message) -> Result<(), IOError> {
+        crate::send_message_to(self, message)
+    }
+
+    fn receive(&self) -> Result<Message, IOError> {
+        crate::receive_message_from(self)
+    }
+}
+
+// What the in-memory network does to each message it carries
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    // Probabilities, from 0 to 1
+    pub drop:         f64,
+    pub duplicate:    f64,
+    // Held back until the messages sent after it within max_delay_ms have arrived
+    pub reorder:      f64,
+    pub min_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+struct Packet {
+    deliver_at:  u64,
+    // Breaks ties between packets due at the same time, in sending order
+    order:       u64,
+    from:        String,
+    to:          String,
+    // Pairs a reply with its request, like a REQ socket with correlation on
+    request_id:  u64,
+    // Serialized, as on the wire
+    content:     String,
+}
+
+type Service = Box<dyn FnMut(&MemoryTransport)>;
+
+struct Network {
+    now:       u64,
+    sent:      u64,
+    rng:       StdRng,
+    faults:    Faults,
+    in_flight: Vec<Packet>,
+    // One for every address that is connected or served
+    inboxes:   HashMap<String, VecDeque<Packet>>,
+    // None while the service is handling a request
+    services:  HashMap<String, Option<Service>>,
+}
+
+// Deterministic network living in memory, on a virtual clock in milliseconds. The same
+// seed and the same calls always lose, delay and duplicate the same messages. Time only
+// moves on while a requester waits for its reply, or with advance.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    network: Rc<RefCell<Network>>,
+}
+
+impl MemoryNetwork {
+    pub fn new(seed: u64, faults: Faults) -> Self {
+        let network = Network {
+            now: 0,
+            sent: 0,
+            rng: StdRng::seed_from_u64(seed),
+            faults,
+            in_flight: Vec::new(),
+            inboxes: HashMap::new(),
+            services: HashMap::new(),
+        };
+
+        Self { network: Rc::new(RefCell::new(network)) }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.network.borrow().now
+    }
+
+    pub fn set_faults(&self, faults: Faults) {
+        self.network.borrow_mut().faults = faults;
+    }
+
+    // Requests sent to the address are handed to the service as they arrive. It gets
+    // the replying side of the connection, to receive the request and send the reply.
+    pub fn serve(&self, address: &str, service: impl FnMut(&MemoryTransport) + 'static) {
+        let mut network = self.network.borrow_mut();
+
+        network.inboxes.entry(String::from(address)).or_default();
+        network.services.insert(String::from(address), Some(Box::new(service)));
+    }
+
+    // From then on messages to the address are lost, as if it had crashed
+    pub fn stop(&self, address: &str) {
+        let mut network = self.network.borrow_mut();
+
+        network.inboxes.remove(address);
+        network.services.remove(address);
+    }
+
+    // The requesting side of a connection from one address to another
+    pub fn connect(&self, from: &str, to: &str, timeout_ms: u64) -> MemoryTransport {
+        self.network.borrow_mut().inboxes.entry(String::from(from)).or_default();
+
+        MemoryTransport {
+            network: self.clone(),
+            address: String::from(from),
+            peer: RefCell::new(String::from(to)),
+            request_id: Cell::new(None),
+            timeout_ms,
+            replying: false,
+        }
+    }
+
+    // Delivers everything due in the next ms milliseconds
+    pub fn advance(&self, ms: u64) {
+        let deadline = self.now() + ms;
+
+        while self.deliver_next(deadline) {}
+
+        self.network.borrow_mut().now = deadline;
+    }
+
+    fn transmit(&self, from: &str, to: &str, request_id: u64, content: String) {
+        let mut network = self.network.borrow_mut();
+        let network = &mut *network;
+
+        if network.rng.gen_bool(network.faults.drop) {
+            return;
+        }
+
+        let copies = if network.rng.gen_bool(network.faults.duplicate) { 2 } else { 1 };
+
+        for _ in 0..copies {
+            let (min_delay, max_delay) = (network.faults.min_delay_ms, network.faults.max_delay_ms.max(network.faults.min_delay_ms));
+            let mut delay = network.rng.gen_range(min_delay..=max_delay);
+
+            if network.rng.gen_bool(network.faults.reorder) {
+                delay += max_delay + 1;
+            }
+
+            network.sent += 1;
+            network.in_flight.push(Packet {
+                deliver_at: network.now + delay,
+                order: network.sent,
+                from: String::from(from),
+                to: String::from(to),
+                request_id,
+                content: content.clone(),
+            });
+        }
+    }
+
+    // Moves the clock to the next packet due by the deadline and delivers it. Returns
+    // false when there is none.
+    fn deliver_next(&self, deadline: u64) -> bool {
+        let packet = {
+            let mut network = self.network.borrow_mut();
+
+            let next = network.in_flight.iter().enumerate()
+                .filter(|(_, packet)| packet.deliver_at <= deadline)
+                .min_by_key(|(_, packet)| (packet.deliver_at, packet.order))
+                .map(|(pos, _)| pos);
+
+            let packet = match next {
+                Some(pos) => network.in_flight.swap_remove(pos),
+                None => return false,
+            };
+
+            network.now = network.now.max(packet.deliver_at);
+            packet
+        };
+
+        let to = packet.to.clone();
+
+        match self.network.borrow_mut().inboxes.get_mut(&to) {
+            Some(inbox) => inbox.push_back(packet),
+            None => return true,
+        }
+
+        // The service is taken out while it runs, as it sends through the network
+        let service = match self.network.borrow_mut().services.get_mut(&to) {
+            Some(service) => service.take(),
+            None => None,
+        };
+
+        if let Some(mut service) = service {
+            let transport = MemoryTransport {
+                network: self.clone(),
+                address: to.clone(),
+                peer: RefCell::new(String::new()),
+                request_id: Cell::new(None),
+                timeout_ms: 0,
+                replying: true,
+            };
+
+            service(&transport);
+
+            if let Some(slot) = self.network.borrow_mut().services.get_mut(&to) {
+                slot.get_or_insert(service);
+            }
+        }
+
+        true
+    }
+
+    fn take_packet(&self, address: &String) -> Option<Packet> {
+        self.network.borrow_mut().inboxes.get_mut(address).and_then(|inbox| inbox.pop_front())
+    }
+}
+
+// One side of a connection on a MemoryNetwork
+pub struct MemoryTransport {
+    network:    MemoryNetwork,
+    address:    String,
+    peer:       RefCell<String>,
+    // The request sent and waiting for its reply, or on the replying side the one to answer
+    request_id: Cell<Option<u64>>,
+    timeout_ms: u64,
+    replying:   bool,
+}
+
+impl MemoryTransport {
+    pub fn address(&self) -> &String {
+        &self.address
+    }
+
+    // Who sent the request being answered, or who requests are sent to
+    pub fn peer(&self) -> String {
+        self.peer.borrow().clone()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send(&self, message: &Message) -> Result<(), IOError> {
+        let request_id = if self.replying {
+            match self.request_id.take() {
+                Some(request_id) => request_id,
+                None => return Err(IOError::ESND(zmq::Error::EFSM)),
+            }
+        } else {
+            let mut network = self.network.network.borrow_mut();
+            network.sent += 1;
+
+            self.request_id.set(Some(network.sent));
+            network.sent
+        };
+
+        self.network.transmit(&self.address, &self.peer.borrow(), request_id, message.to_string());
+
+        Ok(())
+    }
+
+    // The requesting side waits up to its timeout on the virtual clock, dropping replies
+    // to earlier requests. The replying side doesn't wait.
+    fn receive(&self) -> Result<Message, IOError> {
+        let deadline = self.network.now() + self.timeout_ms;
+
+        loop {
+            while let Some(packet) = self.network.take_packet(&self.address) {
+                if self.replying {
+                    *self.peer.borrow_mut() = packet.from;
+                    self.request_id.set(Some(packet.request_id));
+                } else if self.request_id.get() == Some(packet.request_id) {
+                    self.request_id.set(None);
+                } else {
+                    continue;
+                }
+
+                return serde_json::from_str(&packet.content).map_err(IOError::EDSL);
+            }
+
+            if self.replying || !self.network.deliver_next(deadline) {
+                break;
+            }
+        }
+
+        if !self.replying {
+            self.network.network.borrow_mut().now = deadline;
+        }
+
+        Err(IOError::ERCV(zmq::Error::EAGAIN))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    fn request(topic: &str) -> Message {
+        Message::SUB { ip: String::from("client"), topic: String::from(topic), filter: None }
+    }
+
+    fn topic_of(message: &Message) -> String {
+        match message {
+            Message::SUB { topic, .. } => topic.clone(),
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    // A network with a server sending every request back, and the number of requests it got
+    fn echo_network(seed: u64, faults: Faults) -> (MemoryNetwork, Rc<Cell<usize>>) {
+        let network = MemoryNetwork::new(seed, faults);
+        let served = Rc::new(Cell::new(0));
+
+        let counter = served.clone();
+        network.serve("server", move |transport| {
+            let message = transport.receive().unwrap();
+            counter.set(counter.get() + 1);
+            transport.send(&message).unwrap();
+        });
+
+        (network, served)
+    }
+
+    // What the client got for each of its requests, with the number of requests the
+    // server got and the time it all took
+    fn play(seed: u64) -> (Vec<Option<String>>, usize, u64) {
+        let faults = Faults { drop: 0.2, duplicate: 0.2, reorder: 0.2, min_delay_ms: 1, max_delay_ms: 20 };
+        let (network, served) = echo_network(seed, faults);
+        let client = network.connect("client", "server", 100);
+
+        let replies = (0..50).map(|index| {
+            let topic = index.to_string();
+            client.send(&request(&topic)).unwrap();
+
+            client.receive().ok().map(|reply| {
+                assert_eq!(topic_of(&reply), topic);
+                topic
+            })
+        }).collect();
+
+        (replies, served.get(), network.now())
+    }
+
+    // The same seed loses, duplicates and delays the same messages, and a reply is
+    // always the one to the last request, never a late one to an earlier request
+    #[test]
+    fn faults_are_the_same_for_the_same_seed() {
+        let (replies, served, now) = play(7);
+
+        assert_eq!(play(7), (replies.clone(), served, now));
+        assert_ne!(play(8).0, replies);
+
+        assert!(replies.iter().any(Option::is_none));
+        assert!(replies.iter().any(Option::is_some));
+    }
+
+    // Duplicates reach the server again, while the client only takes one reply, and lost
+    // messages time out
+    #[test]
+    fn faults_apply_to_every_message() {
+        let (network, served) = echo_network(1, Faults { duplicate: 1.0, min_delay_ms: 5, max_delay_ms: 5, ..Default::default() });
+        let client = network.connect("client", "server", 100);
+
+        client.send(&request("twice")).unwrap();
+        assert_eq!(topic_of(&client.receive().unwrap()), "twice");
+        assert_eq!(network.now(), 10);
+
+        // The second copy of the request reaches the server, its replies are dropped
+        network.advance(100);
+        assert_eq!(served.get(), 2);
+
+        client.send(&request("again")).unwrap();
+        assert_eq!(topic_of(&client.receive().unwrap()), "again");
+
+        // On the virtual clock, after the client's timeout
+        network.set_faults(Faults { drop: 1.0, ..Default::default() });
+        let before = network.now();
+        client.send(&request("lost")).unwrap();
+        assert!(matches!(client.receive(), Err(IOError::ERCV(zmq::Error::EAGAIN))));
+        assert_eq!(network.now(), before + 100);
+
+        // And so do requests to a server that stopped
+        network.set_faults(Faults::default());
+        network.stop("server");
+        client.send(&request("nobody")).unwrap();
+        assert!(client.receive().is_err());
+        assert_eq!(served.get(), 4);
+    }
+}
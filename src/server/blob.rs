@@ -0,0 +1,268 @@
+use std::collections::{ HashMap, HashSet };
+use std::fs;
+use std::io::{ Read, Seek, SeekFrom, Write };
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+
+use crate::{ State, save_state, current_time_ms };
+
+// Uploads that get no chunk for this long are given up on
+const UPLOAD_TIMEOUT_MS: u128 = 3600000;
+// Blobs are kept this long after their update left its topic
+const BLOB_GRACE_MS: u128 = 3600000;
+
+// Large payload being uploaded chunk by chunk. Its data goes straight to the blob file,
+// only the progress is kept in the state.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Upload {
+    owner:      String,
+    chunks:     u64,
+    size:       u64,
+    updated_at: u128,
+    // The chunks are encrypted with the state key, see write_chunk
+    #[serde(default)]
+    sealed:     bool
+}
+
+pub type Uploads = HashMap<rpubsub::BlobId, Upload>;
+
+// Blob attached to an update. It outlives the update for a while, so the subscribers
+// that got the update can still fetch it once everybody acknowledged it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredBlob {
+    pub topic:   rpubsub::Topic,
+    pub info:    rpubsub::BlobInfo,
+    // When no update referred to it anymore
+    released_at: Option<u128>,
+    #[serde(default)]
+    sealed:      bool
+}
+
+impl StoredBlob {
+    pub fn new(topic: &rpubsub::Topic, info: &rpubsub::BlobInfo, sealed: bool) -> Self {
+        Self { topic: topic.clone(), info: info.clone(), released_at: None, sealed }
+    }
+}
+
+pub type Blobs = HashMap<rpubsub::BlobId, StoredBlob>;
+
+// Blobs live next to the state file. Their ids come from the clients, so the file
+// names are the ids in hex.
+fn blob_path(path: &String, blob_id: &rpubsub::BlobId) -> PathBuf {
+    let name: String = blob_id.bytes().map(|byte| format!("{:02x}", byte)).collect();
+
+    PathBuf::from(path).with_file_name("blobs").join(name)
+}
+
+// With a state key, each chunk is encrypted on its own and takes a slot a bit larger
+// than a chunk in the file
+fn chunk_slot(sealed: bool) -> u64 {
+    (rpubsub::CHUNK_SIZE + if sealed { rpubsub::SEALED_CHUNK_OVERHEAD } else { 0 }) as u64
+}
+
+fn chunk_aad(blob_id: &rpubsub::BlobId, index: u64) -> Vec<u8> {
+    format!("{}:{}", blob_id, index).into_bytes()
+}
+
+// Writes the chunk at its place in the blob file, so a chunk sent twice after a crash
+// simply overwrites itself. Returns the index of the next chunk expected.
+pub fn add_chunk(state: &mut State, ip: &String, upload_id: &rpubsub::BlobId, index: u64, data: &str, path: &String) -> Result<u64, rpubsub::ServiceError> {
+    let expected = match state.uploads.get(upload_id) {
+        Some(upload) if upload.owner.eq(ip) => upload.chunks,
+        Some(_) => return Err(rpubsub::ServiceError::NOUPLOAD),
+        None => 0,
+    };
+
+    // Already stored, the client didn't get the reply
+    if index < expected {
+        return Ok(expected);
+    }
+
+    if index > expected {
+        return Err(rpubsub::ServiceError::BADCHUNK { expected });
+    }
+
+    let data = match rpubsub::decode_chunk(data) {
+        Some(data) if !data.is_empty() && data.len() <= rpubsub::CHUNK_SIZE => data,
+        _ => return Err(rpubsub::ServiceError::BADCHUNK { expected }),
+    };
+
+    // Only the last chunk may be short
+    if state.uploads.get(upload_id).is_some_and(|upload| upload.size % rpubsub::CHUNK_SIZE as u64 != 0) {
+        return Err(rpubsub::ServiceError::BADCHUNK { expected });
+    }
+
+    let blob_path = blob_path(path, upload_id);
+
+    // A new upload can't take the id of a blob that is still around
+    if expected == 0 && blob_path.exists() {
+        return Err(rpubsub::ServiceError::NOUPLOAD);
+    }
+
+    // An upload keeps to how it started, even if the state key was set meanwhile
+    let sealed = state.uploads.get(upload_id).map_or(state.state_key.is_some(), |upload| upload.sealed);
+
+    let stored = match (sealed, &state.state_key) {
+        (true, Some(key)) => rpubsub::seal_blob_chunk(key, &chunk_aad(upload_id, index), &data),
+        (true, None) => return Err(rpubsub::ServiceError::NOUPLOAD),
+        (false, _) => data.clone(),
+    };
+
+    let offset = index * chunk_slot(sealed);
+
+    let written = fs::create_dir_all(blob_path.parent().unwrap())
+        .and_then(|_| fs::OpenOptions::new().create(true).truncate(false).write(true).open(&blob_path))
+        .and_then(|mut file| {
+            file.set_len(offset)?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&stored)
+        });
+
+    if written.is_err() {
+        return Err(rpubsub::ServiceError::BADCHUNK { expected });
+    }
+
+    state.uploads.insert(upload_id.clone(), Upload {
+        owner: ip.clone(),
+        chunks: index + 1,
+        size: index * rpubsub::CHUNK_SIZE as u64 + data.len() as u64,
+        updated_at: current_time_ms(),
+        sealed
+    });
+
+    save_state(state, path);
+
+    Ok(index + 1)
+}
+
+// The blob the client uploaded so far, to attach to an update. The upload is over once
+// it is removed from the state.
+pub fn uploaded_blob(state: &State, ip: &String, topic: &rpubsub::Topic, upload_id: &rpubsub::BlobId) -> Result<StoredBlob, rpubsub::ServiceError> {
+    match state.uploads.get(upload_id) {
+        Some(upload) if upload.owner.eq(ip) => {
+            let info = rpubsub::BlobInfo { id: upload_id.clone(), size: upload.size, chunks: upload.chunks, key_version: None };
+            Ok(StoredBlob::new(topic, &info, upload.sealed))
+        },
+        _ => Err(rpubsub::ServiceError::NOUPLOAD),
+    }
+}
+
+pub fn read_chunk(blob: &StoredBlob, key: Option<&rpubsub::StateKey>, index: u64, path: &String) -> Result<rpubsub::Chunk, rpubsub::ServiceError> {
+    let info = &blob.info;
+
+    // There is no chunk from info.chunks on
+    if index >= info.chunks {
+        return Err(rpubsub::ServiceError::BADCHUNK { expected: info.chunks });
+    }
+
+    let size = (info.size - index * rpubsub::CHUNK_SIZE as u64).min(rpubsub::CHUNK_SIZE as u64) as usize;
+    let mut stored = vec![0; size + if blob.sealed { rpubsub::SEALED_CHUNK_OVERHEAD } else { 0 }];
+
+    let read = fs::File::open(blob_path(path, &info.id)).and_then(|mut file| {
+        file.seek(SeekFrom::Start(index * chunk_slot(blob.sealed)))?;
+        file.read_exact(&mut stored)
+    });
+
+    let data = match (read, blob.sealed, key) {
+        (Ok(_), false, _) => Some(stored),
+        (Ok(_), true, Some(key)) => rpubsub::open_blob_chunk(key, &chunk_aad(&info.id, index), &stored),
+        _ => None,
+    };
+
+    match data {
+        Some(data) => Ok(rpubsub::Chunk { index, chunks: info.chunks, data: rpubsub::encode_chunk(&data), key_version: info.key_version }),
+        None => Err(rpubsub::ServiceError::NOBLOB),
+    }
+}
+
+// Gives up on the uploads that stalled and deletes the blob files that neither an
+// upload nor an update refers to anymore, once their grace period is over
+pub fn remove_unused_blobs(state: &mut State, referenced: HashSet<rpubsub::BlobId>, path: &String) {
+    let now = current_time_ms();
+
+    let uploads = state.uploads.len();
+    state.uploads.retain(|_, upload| now.saturating_sub(upload.updated_at) < UPLOAD_TIMEOUT_MS);
+
+    let mut changed = state.uploads.len() != uploads;
+
+    for (blob_id, blob) in state.blobs.iter_mut() {
+        let released_at = if referenced.contains(blob_id) { None } else { blob.released_at.or(Some(now)) };
+
+        changed |= released_at != blob.released_at;
+        blob.released_at = released_at;
+    }
+
+    let blobs = state.blobs.len();
+    state.blobs.retain(|_, blob| blob.released_at.is_none_or(|released_at| now.saturating_sub(released_at) < BLOB_GRACE_MS));
+
+    if changed || state.blobs.len() != blobs {
+        save_state(state, path);
+    }
+
+    let blob_dir = PathBuf::from(path).with_file_name("blobs");
+
+    let entries = match fs::read_dir(&blob_dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    let in_use: HashSet<PathBuf> = referenced.iter()
+        .chain(state.blobs.keys())
+        .chain(state.uploads.keys())
+        .map(|blob_id| blob_path(path, blob_id))
+        .collect();
+
+    for entry in entries.flatten() {
+        if !in_use.contains(&entry.path()) {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A blob stays fetchable for the grace period after its update was acknowledged by
+    // everybody, then it goes along with its file
+    #[test]
+    fn blobs_outlive_their_update_for_the_grace_period() {
+        let dir = std::env::temp_dir().join(format!("rpubsub-blob-grace-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("state.json").to_string_lossy().into_owned();
+        let mut state = State::new();
+        let (topic, subscriber, publisher, blob_id) = (String::from("uploads"), String::from("c0"), String::from("c1"), String::from("report"));
+
+        crate::add_topic(&mut state, &topic);
+        crate::add_subscription(&mut state, &topic, &subscriber, &None, &path).unwrap();
+
+        add_chunk(&mut state, &publisher, &blob_id, 0, &rpubsub::encode_chunk(b"quarterly"), &path).unwrap();
+        crate::publish_blob(&mut state, &topic, &publisher, &blob_id, "report.pdf", &rpubsub::PutOptions::default(), &path).unwrap();
+
+        crate::get_next_subscriber_update(&mut state, &topic, &subscriber, 0, &path).unwrap();
+        crate::get_next_subscriber_update(&mut state, &topic, &subscriber, 1, &path).unwrap();
+        assert!(state.topics.get(&topic).unwrap().update_queue.is_empty());
+
+        crate::remove_unused_blobs(&mut state, &path);
+
+        let released_at = state.blobs.get(&blob_id).unwrap().released_at.unwrap();
+        let chunk = crate::fetch_blob_chunk(&state, &topic, &subscriber, &blob_id, 0, &path).unwrap();
+        assert_eq!(rpubsub::decode_chunk(&chunk.data), Some(b"quarterly".to_vec()));
+
+        // Still within the grace period, even when looked at again
+        crate::remove_unused_blobs(&mut state, &path);
+        assert_eq!(state.blobs.get(&blob_id).unwrap().released_at, Some(released_at));
+        assert!(blob_path(&path, &blob_id).exists());
+
+        state.blobs.get_mut(&blob_id).unwrap().released_at = Some(released_at - BLOB_GRACE_MS);
+        crate::remove_unused_blobs(&mut state, &path);
+
+        assert!(state.blobs.is_empty());
+        assert!(!blob_path(&path, &blob_id).exists());
+        assert!(matches!(crate::fetch_blob_chunk(&state, &topic, &subscriber, &blob_id, 0, &path), Err(rpubsub::ServiceError::NOBLOB)));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,391 @@
+use serde_json::Value;
+
+// Subscription filters over the headers and JSON payload of updates, e.g.
+//
+//     region == "eu" && (level >= 3 || !(urgent == false))
+//
+// A name refers to the header with that name or, if there is none, to the field of
+// the payload at that dotted path. Comparisons on missing fields are false.
+
+// Filters come from clients and are parsed and evaluated recursively, so their size and
+// the nesting of parentheses and ! are bounded to keep a filter from exhausting the stack
+const MAX_FILTER_LEN: usize = 4096;
+const MAX_FILTER_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+    Str(String),
+    Num(f64),
+    Bool(bool)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Cmp(String, Op, Literal)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Lit(Literal),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen
+}
+
+#[derive(Debug, Clone)]
+pub struct Filter {
+    expr: Expr
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i+1).copied();
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let (token, len) = match (c, next) {
+            ('(', _) => (Token::LParen, 1),
+            (')', _) => (Token::RParen, 1),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('=', Some('=')) => (Token::Op(Op::Eq), 2),
+            ('!', Some('=')) => (Token::Op(Op::Ne), 2),
+            ('<', Some('=')) => (Token::Op(Op::Le), 2),
+            ('>', Some('=')) => (Token::Op(Op::Ge), 2),
+            ('<', _) => (Token::Op(Op::Lt), 1),
+            ('>', _) => (Token::Op(Op::Gt), 1),
+            ('!', _) => (Token::Not, 1),
+
+            ('"', _) => {
+                let end = match chars[i+1..].iter().position(|&c| c == '"') {
+                    Some(end) => i + 1 + end,
+                    None => return Err(String::from("unterminated string")),
+                };
+
+                (Token::Lit(Literal::Str(chars[i+1..end].iter().collect())), end + 1 - i)
+            },
+
+            _ if c.is_ascii_digit() || c == '-' => {
+                let len = chars[i..].iter().take_while(|c| c.is_ascii_digit() || **c == '.' || **c == '-').count();
+                let text: String = chars[i..i+len].iter().collect();
+
+                match text.parse::<f64>() {
+                    Ok(num) => (Token::Lit(Literal::Num(num)), len),
+                    Err(_) => return Err(format!("invalid number {}", text)),
+                }
+            },
+
+            _ if c.is_alphanumeric() || c == '_' => {
+                let len = chars[i..].iter().take_while(|c| c.is_alphanumeric() || **c == '_' || **c == '-' || **c == '.').count();
+                let text: String = chars[i..i+len].iter().collect();
+
+                match text.as_str() {
+                    "true" => (Token::Lit(Literal::Bool(true)), len),
+                    "false" => (Token::Lit(Literal::Bool(false)), len),
+                    _ => (Token::Name(text), len),
+                }
+            },
+
+            _ => return Err(format!("unexpected character {}", c)),
+        };
+
+        tokens.push(token);
+        i += len;
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos:    usize,
+    depth:  usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_and()?;
+
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_unary()?;
+
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn enter(&mut self) -> Result<(), String> {
+        self.depth += 1;
+
+        if self.depth > MAX_FILTER_DEPTH {
+            return Err(format!("filter nested deeper than {}", MAX_FILTER_DEPTH));
+        }
+
+        Ok(())
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Not) => {
+                self.enter()?;
+                let expr = Expr::Not(Box::new(self.parse_unary()?));
+                self.depth -= 1;
+
+                Ok(expr)
+            },
+
+            Some(Token::LParen) => {
+                self.enter()?;
+                let expr = self.parse_or()?;
+                self.depth -= 1;
+
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err(String::from("missing )")),
+                }
+            },
+
+            Some(Token::Name(name)) => match (self.next(), self.next()) {
+                (Some(Token::Op(op)), Some(Token::Lit(literal))) => Ok(Expr::Cmp(name, op, literal)),
+                _ => Err(format!("expected a comparison after {}", name)),
+            },
+
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err(String::from("unexpected end of filter")),
+        }
+    }
+}
+
+fn compare<T: PartialOrd>(lhs: T, op: Op, rhs: T) -> bool {
+    match op {
+        Op::Eq => lhs == rhs,
+        Op::Ne => lhs != rhs,
+        Op::Lt => lhs < rhs,
+        Op::Le => lhs <= rhs,
+        Op::Gt => lhs > rhs,
+        Op::Ge => lhs >= rhs,
+    }
+}
+
+fn lookup<'a>(payload: &'a Option<Value>, path: &str) -> Option<&'a Value> {
+    let mut value = payload.as_ref()?;
+
+    for key in path.split('.') {
+        value = value.get(key)?;
+    }
+
+    Some(value)
+}
+
+// Header values are strings, but may be compared with numbers and booleans
+fn compare_header(header: &str, op: Op, literal: &Literal) -> bool {
+    match literal {
+        Literal::Str(s) => compare(header, op, s.as_str()),
+        Literal::Num(n) => header.parse::<f64>().is_ok_and(|h| compare(h, op, *n)),
+        Literal::Bool(b) => header.parse::<bool>().is_ok_and(|h| compare(h, op, *b)),
+    }
+}
+
+fn compare_value(value: &Value, op: Op, literal: &Literal) -> bool {
+    match (value, literal) {
+        (Value::String(v), Literal::Str(s)) => compare(v.as_str(), op, s.as_str()),
+        (Value::Number(v), Literal::Num(n)) => v.as_f64().is_some_and(|v| compare(v, op, *n)),
+        (Value::Bool(v), Literal::Bool(b)) => compare(*v, op, *b),
+        _ => false,
+    }
+}
+
+impl Expr {
+    fn eval(&self, payload: &Option<Value>, headers: &rpubsub::Headers) -> bool {
+        match self {
+            Expr::Or(lhs, rhs) => lhs.eval(payload, headers) || rhs.eval(payload, headers),
+            Expr::And(lhs, rhs) => lhs.eval(payload, headers) && rhs.eval(payload, headers),
+            Expr::Not(expr) => !expr.eval(payload, headers),
+            Expr::Cmp(name, op, literal) => match headers.get(name) {
+                Some(header) => compare_header(header, *op, literal),
+                None => lookup(payload, name).is_some_and(|value| compare_value(value, *op, literal)),
+            },
+        }
+    }
+}
+
+impl Filter {
+    pub fn parse(source: &str) -> Result<Filter, String> {
+        if source.len() > MAX_FILTER_LEN {
+            return Err(format!("filter longer than {} bytes", MAX_FILTER_LEN));
+        }
+
+        let mut parser = Parser { tokens: tokenize(source)?, pos: 0, depth: 0 };
+
+        let expr = parser.parse_or()?;
+
+        if parser.pos < parser.tokens.len() {
+            return Err(format!("unexpected {:?}", parser.tokens[parser.pos]));
+        }
+
+        Ok(Filter { expr })
+    }
+
+    pub fn matches(&self, payload: &str, headers: &rpubsub::Headers) -> bool {
+        // Payloads that aren't JSON can still be filtered on their headers
+        let payload = serde_json::from_str(payload).ok();
+
+        self.expr.eval(&payload, headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> rpubsub::Headers {
+        pairs.iter().map(|(name, value)| (String::from(*name), String::from(*value))).collect()
+    }
+
+    fn matches(source: &str, payload: &str, pairs: &[(&str, &str)]) -> bool {
+        Filter::parse(source).unwrap().matches(payload, &headers(pairs))
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let payload = r#"{"a": 1, "b": 0, "c": 0}"#;
+
+        assert!(matches("a == 1 || b == 1 && c == 1", payload, &[]));
+        assert!(matches("b == 1 && c == 1 || a == 1", payload, &[]));
+        assert!(!matches("(a == 1 || b == 1) && c == 1", payload, &[]));
+    }
+
+    #[test]
+    fn not_applies_to_what_follows() {
+        let payload = r#"{"a": 1, "b": 0}"#;
+
+        assert!(!matches("!a == 1 && b == 0", payload, &[]));
+        assert!(matches("!(a == 1 && b == 1)", payload, &[]));
+        assert!(matches("!!a == 1", payload, &[]));
+    }
+
+    #[test]
+    fn quoted_strings_keep_what_looks_like_syntax() {
+        let payload = r#"{"text": "x && (y || !z) == 1", "empty": ""}"#;
+
+        assert!(matches(r#"text == "x && (y || !z) == 1""#, payload, &[]));
+        assert!(matches(r#"empty == """#, payload, &[]));
+        assert!(matches(r#"text != "x""#, payload, &[]));
+    }
+
+    #[test]
+    fn headers_are_compared_as_what_they_hold() {
+        let pairs = [("region", "eu"), ("level", "3"), ("urgent", "true")];
+
+        assert!(matches(r#"region == "eu""#, "", &pairs));
+        assert!(matches("level >= 3 && level < 3.5", "", &pairs));
+        assert!(matches("urgent == true", "", &pairs));
+        assert!(!matches("region > 3", "", &pairs));
+        assert!(!matches("urgent == 1", "", &pairs));
+    }
+
+    #[test]
+    fn headers_come_before_the_payload() {
+        assert!(matches(r#"region == "eu""#, r#"{"region": "us"}"#, &[("region", "eu")]));
+        assert!(matches(r#"region == "us""#, r#"{"region": "us"}"#, &[("level", "3")]));
+    }
+
+    #[test]
+    fn payload_fields_are_found_by_path_and_type() {
+        let payload = r#"{"order": {"total": 12.5, "paid": false, "id": "o-1"}, "n": -2}"#;
+
+        assert!(matches("order.total > 10 && order.paid == false", payload, &[]));
+        assert!(matches(r#"order.id == "o-1""#, payload, &[]));
+        assert!(matches("n == -2", payload, &[]));
+        // Values are only compared with literals of their type
+        assert!(!matches(r#"order.total == "12.5""#, payload, &[]));
+        assert!(!matches("order.paid == 0", payload, &[]));
+    }
+
+    #[test]
+    fn missing_fields_compare_false() {
+        assert!(!matches("missing == 1", r#"{"a": 1}"#, &[]));
+        assert!(!matches("missing != 1", r#"{"a": 1}"#, &[]));
+        assert!(!matches("a.b == 1", r#"{"a": 1}"#, &[]));
+        assert!(matches("!(missing == 1)", r#"{"a": 1}"#, &[]));
+    }
+
+    #[test]
+    fn payloads_that_arent_json_only_match_on_headers() {
+        assert!(!matches("a == 1", "not json", &[]));
+        assert!(matches("a == 1", "not json", &[("a", "1")]));
+    }
+
+    #[test]
+    fn malformed_filters_are_rejected() {
+        let malformed = ["", "a", "a ==", "== 1", "a == b", "a == 1 &&", "&& a == 1", "(a == 1", "a == 1)", "a == 1 b == 2",
+                         "a == 1.2.3", r#"a == "open"#, "a = 1", "a & b", "a == 1 # 2", "()"];
+
+        for source in malformed {
+            assert!(Filter::parse(source).is_err(), "{:?} parsed", source);
+        }
+    }
+
+    #[test]
+    fn deep_and_long_filters_are_rejected() {
+        let nested = |depth: usize| format!("{}a == 1{}", "(".repeat(depth), ")".repeat(depth));
+
+        assert!(matches(&nested(MAX_FILTER_DEPTH), r#"{"a": 1}"#, &[]));
+        assert!(Filter::parse(&nested(MAX_FILTER_DEPTH + 1)).is_err());
+        assert!(Filter::parse(&format!("{}a == 1", "!".repeat(MAX_FILTER_DEPTH + 1))).is_err());
+
+        // Deep enough to overflow the stack if the nesting wasn't bounded
+        assert!(Filter::parse(&"(".repeat(200000)).is_err());
+
+        let chain = vec!["a == 1"; MAX_FILTER_LEN / 10].join(" && ");
+        assert!(chain.len() <= MAX_FILTER_LEN);
+        assert!(matches(&chain, r#"{"a": 1}"#, &[]));
+        assert!(Filter::parse(&format!("{} && a == 1 && a == 1", chain)).is_err());
+    }
+}
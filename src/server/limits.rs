@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+
+// Limits that apply to a publisher, read from the limits file given to the server, e.g.
+//
+//     { "default": { "rate": { "rate": 100, "burst": 200 } },
+//       "clients": { "noisy": { "rate": { "rate": 1, "burst": 5 }, "quota_bytes": 65536 } } }
+//
+// Clients that aren't listed get the default limits, which are none if it is missing.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct ClientLimits {
+    pub rate:        Option<rpubsub::RateLimit>,
+    // Payload bytes of the client's updates the server may be storing at once
+    pub quota_bytes: Option<usize>
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub default: ClientLimits,
+    pub clients: HashMap<String, ClientLimits>
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens:     f64,
+    updated_at: u128
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    clients: HashMap<String, TokenBucket>,
+    topics:  HashMap<rpubsub::Topic, TokenBucket>
+}
+
+// Token buckets of the clients and topics that have a rate limit. They only live in
+// memory: after a restart every bucket starts full. Clones share the buckets, so a
+// client's limits hold across the shards of the server's state.
+#[derive(Debug, Default, Clone)]
+pub struct Limits {
+    config:  Arc<LimitsConfig>,
+    buckets: Arc<Mutex<Buckets>>
+}
+
+pub fn load_limits(path: &String) -> Result<LimitsConfig, String> {
+    let content = fs::read(path).map_err(|err| err.to_string())?;
+
+    serde_json::from_slice(&content).map_err(|err| err.to_string())
+}
+
+// Adds the tokens the bucket earned since it was last used
+fn refill(bucket: &mut TokenBucket, limit: &rpubsub::RateLimit, now: u128) {
+    let elapsed_s = now.saturating_sub(bucket.updated_at) as f64 / 1000.0;
+
+    bucket.tokens = (bucket.tokens + elapsed_s * limit.rate).min(limit.burst);
+    bucket.updated_at = now;
+}
+
+// In how long the bucket will have count tokens, if it doesn't have them yet
+fn retry_after_ms(bucket: &TokenBucket, limit: &rpubsub::RateLimit, count: usize) -> Option<u64> {
+    let missing = count as f64 - bucket.tokens;
+
+    if missing <= 0.0 {
+        None
+    } else if limit.rate <= 0.0 || count as f64 > limit.burst {
+        // The bucket can never hold that many tokens
+        Some(u64::MAX)
+    } else {
+        Some((missing / limit.rate * 1000.0).ceil() as u64)
+    }
+}
+
+impl Limits {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self { config: Arc::new(config), buckets: Arc::default() }
+    }
+
+    pub fn client_limits(&self, ip: &String) -> &ClientLimits {
+        self.config.clients.get(ip).unwrap_or(&self.config.default)
+    }
+
+    // Takes tokens for count updates from the client and for each topic's updates from
+    // the topic, or none at all if any of the buckets is short. The error is how long
+    // the publisher should wait before retrying.
+    pub fn take_tokens(&self, ip: &String, topics: &Vec<(rpubsub::Topic, usize, Option<rpubsub::RateLimit>)>, now: u128) -> Result<(), u64> {
+        let count = topics.iter().map(|(_, count, _)| count).sum();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let client_limit = self.client_limits(ip).rate;
+        let mut retry_after = None;
+
+        if let Some(limit) = &client_limit {
+            let bucket = buckets.clients.entry(ip.clone()).or_insert(TokenBucket { tokens: limit.burst, updated_at: now });
+            refill(bucket, limit, now);
+            retry_after = retry_after_ms(bucket, limit, count);
+        }
+
+        for (topic, count, limit) in topics {
+            if let Some(limit) = limit {
+                let bucket = buckets.topics.entry(topic.clone()).or_insert(TokenBucket { tokens: limit.burst, updated_at: now });
+                refill(bucket, limit, now);
+                retry_after = retry_after.max(retry_after_ms(bucket, limit, *count));
+            }
+        }
+
+        if let Some(retry_after) = retry_after {
+            return Err(retry_after);
+        }
+
+        if client_limit.is_some() {
+            buckets.clients.get_mut(ip).unwrap().tokens -= count as f64;
+        }
+
+        for (topic, count, limit) in topics {
+            if limit.is_some() {
+                buckets.topics.get_mut(topic).unwrap().tokens -= *count as f64;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+#[derive(Debug, Default)]
+struct Generations {
+    // Bumped by every save_state
+    requested: u64,
+    // Last generation the persister wrote to disk
+    written:   u64
+}
+
+// Group commit of the state file. With a persister, save_state only asks for the state
+// to be written: the persister thread writes it once for every change made meanwhile,
+// and workers wait for the generation they made before replying, so a reply still
+// means the change is on disk.
+#[derive(Debug, Default, Clone)]
+pub struct Persister {
+    generations: Arc<(Mutex<Generations>, Condvar)>
+}
+
+impl Persister {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns the generation that will hold the change
+    pub(crate) fn request(&self) -> u64 {
+        let (generations, changed) = &*self.generations;
+
+        let mut generations = generations.lock().unwrap();
+        generations.requested += 1;
+        changed.notify_all();
+
+        generations.requested
+    }
+
+    // Must be read while holding the state, to match what the state holds
+    pub fn requested(&self) -> u64 {
+        self.generations.0.lock().unwrap().requested
+    }
+
+    // Blocks until there is something to write
+    pub fn wait_requested(&self) {
+        let (generations, changed) = &*self.generations;
+
+        let _unused = changed.wait_while(generations.lock().unwrap(), |generations| generations.requested <= generations.written).unwrap();
+    }
+
+    pub fn set_written(&self, generation: u64) {
+        let (generations, changed) = &*self.generations;
+
+        let mut generations = generations.lock().unwrap();
+        generations.written = generations.written.max(generation);
+        changed.notify_all();
+    }
+
+    // Blocks until the given generation, or a later one, is on disk
+    pub fn wait_written(&self, generation: u64) {
+        let (generations, changed) = &*self.generations;
+
+        let _unused = changed.wait_while(generations.lock().unwrap(), |generations| generations.written < generation).unwrap();
+    }
+}
@@ -0,0 +1,420 @@
+// Property tests of a topic: random sequences of subscriptions, puts and acknowledgements
+// are played on the topic and on a reference model that only keeps, for each subscriber,
+// the updates it still has to get. After every operation the replies must match and:
+//
+// - every update a subscriber waits on is in the queue, counted once per such subscriber
+// - every subscriber is on the oldest update it didn't acknowledge
+// - the bytes counted for each publisher are those of its updates in the queue
+// - after an update is acknowledged or a subscriber leaves, the queue doesn't start with
+//   updates nobody waits on
+// - the state file holds the state in memory
+//
+// A failing seed prints the operations that led to the failure.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use super::*;
+
+const TOPIC: &str = "topic";
+const CLIENTS: usize = 4;
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+enum Op {
+    SUB(usize),
+    UNSUB(usize),
+    PUT(usize),
+    // The same PUT again, as when the reply was lost
+    REPUT(usize),
+    GET(usize),
+    // A GET whose reply is lost, the client doesn't move on
+    LOSTGET(usize),
+    // The client reconnecting with its sequence number
+    UP(usize),
+}
+
+// What the operation got, Err with the name of the error
+type Outcome = Result<Option<rpubsub::SequenceNum>, &'static str>;
+
+#[derive(Default)]
+struct ModelSubscriber {
+    // seqs of the updates published since it subscribed that it didn't acknowledge
+    waiting:  VecDeque<rpubsub::SequenceNum>,
+    held:     Option<rpubsub::SequenceNum>,
+    last_ack: Option<rpubsub::SequenceNum>,
+}
+
+#[derive(Default)]
+struct Model {
+    subscribers: HashMap<String, ModelSubscriber>,
+    publishers:  HashMap<String, rpubsub::SequenceNum>,
+    next_seq:    rpubsub::SequenceNum,
+}
+
+impl Model {
+    fn subscribe(&mut self, ip: &String) -> Outcome {
+        if self.subscribers.contains_key(ip) {
+            return Err("ALREASUB");
+        }
+
+        self.subscribers.insert(ip.clone(), ModelSubscriber::default());
+        Ok(None)
+    }
+
+    fn unsubscribe(&mut self, ip: &String) -> Outcome {
+        match self.subscribers.remove(ip) {
+            Some(_) => Ok(None),
+            None => Err("NOSUB"),
+        }
+    }
+
+    fn put(&mut self, ip: &String, sequence_num: rpubsub::SequenceNum) -> Outcome {
+        if self.publishers.get(ip).is_some_and(|last| sequence_num <= *last) {
+            return Err("ALREAPUT");
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        for subscriber in self.subscribers.values_mut() {
+            subscriber.waiting.push_back(seq);
+        }
+
+        self.publishers.insert(ip.clone(), sequence_num);
+        Ok(Some(seq))
+    }
+
+    // Returns whether an update was acknowledged
+    fn ack(&mut self, ip: &String, sequence_num: rpubsub::SequenceNum) -> Result<bool, &'static str> {
+        let subscriber = self.subscribers.get_mut(ip).ok_or("NOSUB")?;
+
+        match subscriber.last_ack {
+            None => subscriber.last_ack = Some(sequence_num),
+
+            Some(last) if sequence_num == last + 1 => {
+                subscriber.last_ack = Some(sequence_num);
+
+                if let Some(held) = subscriber.held.take() {
+                    assert_eq!(subscriber.waiting.pop_front(), Some(held), "model: {} acknowledged an update out of order", ip);
+                    return Ok(true);
+                }
+            },
+
+            Some(_) => (),
+        }
+
+        Ok(false)
+    }
+
+    fn get(&mut self, ip: &String, sequence_num: rpubsub::SequenceNum) -> (Outcome, bool) {
+        let acked = match self.ack(ip, sequence_num) {
+            Ok(acked) => acked,
+            Err(e) => return (Err(e), false),
+        };
+
+        let subscriber = self.subscribers.get_mut(ip).unwrap();
+
+        if subscriber.held.is_none() {
+            subscriber.held = subscriber.waiting.front().copied();
+        }
+
+        (Ok(subscriber.held), acked)
+    }
+}
+
+// Plays operations on a topic and on the model side by side
+struct Run {
+    state:            State,
+    path:             String,
+    model:            Model,
+    clients:          Vec<String>,
+    // What the client applications keep: the sequence numbers of their subscriptions
+    // and their put counters
+    sequence_numbers: Vec<Option<rpubsub::SequenceNum>>,
+    put_counters:     Vec<rpubsub::SequenceNum>,
+    ops:              Vec<Op>,
+}
+
+fn service_error(e: rpubsub::ServiceError) -> &'static str {
+    e.into()
+}
+
+impl Run {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("rpubsub-properties-{}-{}.json", name, std::process::id())).to_string_lossy().into_owned();
+
+        let _ = fs::remove_file(&path);
+
+        let mut state = State::new();
+        add_topic(&mut state, &String::from(TOPIC));
+
+        Self {
+            state,
+            path,
+            model: Model::default(),
+            clients: (0..CLIENTS).map(|i| format!("c{}", i)).collect(),
+            sequence_numbers: vec![None; CLIENTS],
+            put_counters: vec![0; CLIENTS],
+            ops: Vec::new(),
+        }
+    }
+
+    fn fail(&self, message: String) -> ! {
+        panic!("{}\nafter {:?}", message, self.ops);
+    }
+
+    fn apply(&mut self, op: Op) {
+        self.ops.push(op.clone());
+
+        let topic = String::from(TOPIC);
+
+        // Whether updates may have left the queue
+        let (expected, got, removed) = match op {
+            Op::SUB(client) => {
+                let ip = self.clients[client].clone();
+                let got = add_subscription(&mut self.state, &topic, &ip, &None, &self.path).map(|_| None).map_err(service_error);
+
+                // The client subscribes again as if it had lost the reply to ALREASUB
+                if got.is_ok() || got == Err("ALREASUB") {
+                    self.sequence_numbers[client].get_or_insert(0);
+                }
+
+                (self.model.subscribe(&ip), got, false)
+            },
+
+            Op::UNSUB(client) => {
+                let ip = self.clients[client].clone();
+                let got = remove_subscription(&mut self.state, &topic, &ip, &self.path).map(|_| None).map_err(service_error);
+
+                self.sequence_numbers[client] = None;
+
+                let removed = got.is_ok();
+                (self.model.unsubscribe(&ip), got, removed)
+            },
+
+            Op::PUT(client) | Op::REPUT(client) => {
+                let ip = self.clients[client].clone();
+
+                if matches!(op, Op::PUT(_)) {
+                    self.put_counters[client] += 1;
+                }
+
+                let sequence_num = self.put_counters[client];
+                let got = publish_update(&mut self.state, &topic, &ip, sequence_num, &format!("{}/{}", ip, sequence_num), &rpubsub::PutOptions::default(), &self.path)
+                    .map(|receipt| receipt.seq)
+                    .map_err(service_error);
+
+                (self.model.put(&ip, sequence_num), got, false)
+            },
+
+            Op::GET(client) | Op::LOSTGET(client) => {
+                let ip = self.clients[client].clone();
+                let sequence_num = self.sequence_numbers[client].unwrap_or(0);
+
+                let got = get_next_subscriber_update(&mut self.state, &topic, &ip, sequence_num, &self.path)
+                    .map(|(delivery, _)| delivery.map(|delivery| delivery.seq))
+                    .map_err(service_error);
+
+                if let (Ok(Some(_)), Op::GET(_), Some(sequence_num)) = (&got, &op, self.sequence_numbers[client].as_mut()) {
+                    *sequence_num += 1;
+                }
+
+                let (expected, acked) = self.model.get(&ip, sequence_num);
+                (expected, got, acked)
+            },
+
+            Op::UP(client) => {
+                let ip = self.clients[client].clone();
+                let sequence_num = self.sequence_numbers[client].unwrap_or(0);
+
+                let got = update_subscriber_update_ack(&mut self.state, &topic, &ip, sequence_num, &self.path).map(|_| None).map_err(service_error);
+                let expected = self.model.ack(&ip, sequence_num);
+
+                (expected.map(|_| None), got, expected == Ok(true))
+            },
+        };
+
+        if got != expected {
+            self.fail(format!("got {:?}, the model expected {:?}", got, expected));
+        }
+
+        self.check(removed);
+    }
+
+    fn check(&self, removed: bool) {
+        let topic_info = self.state.topics.get(TOPIC).unwrap();
+        let queue = &topic_info.update_queue;
+
+        if !queue.iter().zip(queue.iter().skip(1)).all(|(a, b)| a.seq < b.seq) {
+            self.fail(format!("queue out of order: {:?}", queue.iter().map(|update| update.seq).collect::<Vec<_>>()));
+        }
+
+        for update in queue {
+            let waiting = self.model.subscribers.values().filter(|subscriber| subscriber.waiting.contains(&update.seq)).count();
+
+            if update.pending_updates != waiting {
+                self.fail(format!("update {} pending for {}, {} subscribers wait on it", update.seq, update.pending_updates, waiting));
+            }
+        }
+
+        let mut stored: HashMap<String, usize> = HashMap::new();
+
+        for update in queue {
+            *stored.entry(update.publisher.clone()).or_insert(0) += update.stored_size();
+        }
+
+        if topic_info.stored_bytes != stored {
+            self.fail(format!("stored bytes counted as {:?}, the queue holds {:?}", topic_info.stored_bytes, stored));
+        }
+
+        if removed && queue.front().is_some_and(|update| update.pending_updates == 0) {
+            self.fail(format!("update {} left at the front of the queue with nobody waiting on it", queue.front().unwrap().seq));
+        }
+
+        if topic_info.subscriptions.len() != self.model.subscribers.len() {
+            self.fail(format!("{} subscriptions, the model has {}", topic_info.subscriptions.len(), self.model.subscribers.len()));
+        }
+
+        for (ip, subscriber) in &self.model.subscribers {
+            let subscription_info = match topic_info.subscriptions.get(ip) {
+                Some(subscription_info) => subscription_info,
+                None => self.fail(format!("{} lost its subscription", ip)),
+            };
+
+            for seq in &subscriber.waiting {
+                if update_pos(queue, *seq).is_none() {
+                    self.fail(format!("update {} that {} waits on left the queue", seq, ip));
+                }
+            }
+
+            let oldest = subscription_info.topic_update_idx.map(|idx| queue.get(idx).map(|update| update.seq));
+
+            if oldest != subscriber.waiting.front().map(|seq| Some(*seq)) {
+                self.fail(format!("{} is on {:?}, its oldest update left is {:?}", ip, oldest, subscriber.waiting.front()));
+            }
+
+            if subscription_info.current != subscriber.held || subscription_info.last_recv_sequence_num != subscriber.last_ack {
+                self.fail(format!("{} holds {:?} acknowledged up to {:?}, the model {:?} up to {:?}", ip, subscription_info.current,
+                                  subscription_info.last_recv_sequence_num, subscriber.held, subscriber.last_ack));
+            }
+        }
+
+        // Compared parsed, as the subscriptions are written in any order
+        let parse = |content: &[u8]| serde_json::from_slice::<serde_json::Value>(content).ok();
+        let saved = fs::read(&self.path).ok().and_then(|content| parse(&content));
+
+        if saved.is_some() && saved != parse(&serialize_state(&self.state)) {
+            self.fail(String::from("the state file is behind the state"));
+        }
+    }
+
+    fn cleanup(&self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn random_op(rng: &mut StdRng) -> Op {
+    let client = rng.gen_range(0..CLIENTS);
+
+    match rng.gen_range(0..100) {
+        0..=9 => Op::SUB(client),
+        10..=17 => Op::UNSUB(client),
+        18..=44 => Op::PUT(client),
+        45..=49 => Op::REPUT(client),
+        50..=84 => Op::GET(client),
+        85..=92 => Op::LOSTGET(client),
+        _ => Op::UP(client),
+    }
+}
+
+fn play(name: &str, ops: Vec<Op>) {
+    let mut run = Run::new(name);
+
+    for op in ops {
+        run.apply(op);
+    }
+
+    run.cleanup();
+}
+
+#[test]
+fn random_operations() {
+    for seed in 0..50 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let ops = (0..200).map(|_| random_op(&mut rng)).collect();
+
+        play(&format!("random-{}", seed), ops);
+    }
+}
+
+// c0 leaves while on the second of four updates c1 waits on, with c2 behind c1
+#[test]
+fn unsubscribe_in_the_middle_of_the_queue() {
+    play("unsub-middle", vec![
+        Op::SUB(0), Op::SUB(1), Op::PUT(3), Op::PUT(3), Op::SUB(2), Op::PUT(3), Op::PUT(3),
+        Op::GET(0), Op::GET(0), Op::GET(1),
+        Op::UNSUB(0),
+        Op::GET(1), Op::GET(1), Op::GET(2), Op::GET(2), Op::GET(1), Op::GET(1), Op::GET(2), Op::GET(2),
+    ]);
+}
+
+// The last subscriber on the front update acknowledges it, the others move down with the queue
+#[test]
+fn ack_the_front_update() {
+    play("ack-front", vec![
+        Op::SUB(0), Op::SUB(1), Op::PUT(3), Op::PUT(3), Op::PUT(3),
+        Op::GET(1), Op::GET(1), Op::GET(1),
+        Op::GET(0), Op::GET(0),
+        Op::UP(0), Op::GET(0), Op::GET(1), Op::GET(0),
+    ]);
+}
+
+// Subscribers that leave or get there before any update, and clients that aren't subscribed
+#[test]
+fn subscribers_without_updates() {
+    play("no-updates", vec![
+        Op::GET(0), Op::UP(0), Op::UNSUB(0),
+        Op::SUB(0), Op::GET(0), Op::UNSUB(0), Op::GET(0),
+        Op::SUB(0), Op::SUB(0), Op::PUT(1), Op::REPUT(1), Op::GET(0), Op::LOSTGET(0), Op::UNSUB(0), Op::PUT(1),
+    ]);
+}
+
+// Updates from a state file written before they had a seq are numbered on load, and
+// subscribers get them in order
+#[test]
+fn updates_without_seq_in_the_state_file() {
+    let path = std::env::temp_dir().join(format!("rpubsub-properties-legacy-{}.json", std::process::id())).to_string_lossy().into_owned();
+    let topic = String::from(TOPIC);
+    let subscriber = String::from("c0");
+
+    let mut state = State::new();
+    add_topic(&mut state, &topic);
+    add_subscription(&mut state, &topic, &subscriber, &None, &path).unwrap();
+
+    for payload in ["a", "b", "c"] {
+        add_update(&mut state, &topic, &String::from("c1"), &String::from(payload), &rpubsub::PutOptions::default(), &None, current_time_ms(), &path).unwrap();
+    }
+
+    let topic_info = state.topics.get_mut(&topic).unwrap();
+    topic_info.next_update_seq = 0;
+
+    for update in topic_info.update_queue.iter_mut() {
+        update.seq = 0;
+    }
+
+    restore_state(&mut state);
+
+    for (sequence_num, payload) in ["a", "b", "c"].iter().enumerate() {
+        let (delivery, _) = get_next_subscriber_update(&mut state, &topic, &subscriber, sequence_num as rpubsub::SequenceNum, &path).unwrap();
+        assert_eq!(delivery.map(|delivery| delivery.payload), Some(String::from(*payload)));
+    }
+
+    let seq = add_update(&mut state, &topic, &String::from("c1"), &String::from("d"), &rpubsub::PutOptions::default(), &None, current_time_ms(), &path).unwrap();
+    assert_eq!(seq, 3);
+
+    let _ = fs::remove_file(&path);
+}
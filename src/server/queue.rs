@@ -0,0 +1,160 @@
+use std::collections::{ HashMap, VecDeque };
+use serde::{Deserialize, Serialize};
+
+use crate::{ State, save_state, current_time_ms };
+
+// How long a leased message stays hidden from other workers before it is requeued
+const VISIBILITY_TIMEOUT_MS: u128 = 30000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Lease {
+    worker:   String,
+    deadline: u128
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct QueueMessage {
+    id:             rpubsub::SequenceNum,
+    content:        rpubsub::UpdateContent,
+    // None while the message waits for a worker
+    lease:          Option<Lease>,
+    delivery_count: u32,
+    #[serde(default)]
+    received_at:    rpubsub::Timestamp
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct QueueInfo {
+    messages:        VecDeque<QueueMessage>,
+    next_message_id: rpubsub::SequenceNum,
+    // Sequence number of the last QPUT of each publisher
+    #[serde(default)]
+    publishers:      HashMap<String, rpubsub::SequenceNum>
+}
+
+impl QueueInfo {
+    pub fn new() -> Self {
+        Self { messages: VecDeque::new(), next_message_id: 0, publishers: HashMap::new() }
+    }
+}
+
+pub type Queues = HashMap<rpubsub::QueueName, QueueInfo>;
+
+fn is_available(message: &QueueMessage, now: u128) -> bool {
+    match &message.lease {
+        Some(lease) => lease.deadline <= now,
+        None => true,
+    }
+}
+
+fn leased_message_pos(queue_info: &QueueInfo, ip: &String, message_id: rpubsub::SequenceNum) -> Option<usize> {
+    queue_info.messages.iter().position(|message| {
+        message.id == message_id && message.lease.as_ref().is_some_and(|lease| lease.worker.eq(ip))
+    })
+}
+
+// A QPUT sent again, because its reply was lost, gets ALREAPUT like a PUT instead of
+// enqueuing the message twice
+pub fn add_message(state: &mut State, queue: &rpubsub::QueueName, ip: &str, sequence_num: rpubsub::SequenceNum, content: &str, path: &String)
+                                                                -> Result<rpubsub::SequenceNum, rpubsub::ServiceError> {
+    // Messages wait in the queue until a worker shows up, so queues are created on the first put
+    let queue_info = state.queues.entry(queue.clone()).or_default();
+
+    if let Some(&last) = queue_info.publishers.get(ip).filter(|last| sequence_num <= **last) {
+        return Err(rpubsub::ServiceError::ALREAPUT { last });
+    }
+
+    let id = queue_info.next_message_id;
+
+    queue_info.messages.push_back(QueueMessage {
+        id,
+        content: String::from(content),
+        lease: None,
+        delivery_count: 0,
+        received_at: current_time_ms()
+    });
+    queue_info.next_message_id += 1;
+    queue_info.publishers.insert(String::from(ip), sequence_num);
+
+    save_state(state, path);
+
+    Ok(id)
+}
+
+// Leases the oldest message that isn't held by a worker. Messages whose lease
+// expired are handed out again.
+pub fn lease_next_message(state: &mut State, queue: &rpubsub::QueueName, ip: &str, path: &String)
+                                                                -> Result<(Option<rpubsub::Delivery>, rpubsub::SequenceNum), rpubsub::ServiceError> {
+    if !state.queues.contains_key(queue) {
+        return Err(rpubsub::ServiceError::NOQUEUE);
+    }
+
+    let queue_info = state.queues.get_mut(queue).unwrap();
+
+    let now = current_time_ms();
+
+    let res = match queue_info.messages.iter_mut().find(|message| is_available(message, now)) {
+        Some(message) => {
+            message.lease = Some(Lease { worker: String::from(ip), deadline: now + VISIBILITY_TIMEOUT_MS });
+            message.delivery_count += 1;
+
+            let delivery = rpubsub::Delivery {
+                payload: message.content.clone(),
+                headers: rpubsub::Headers::new(),
+                seq: message.id,
+                timestamp: message.received_at,
+                blob: None,
+                compression: rpubsub::Compression::NONE,
+                key_version: None
+            };
+
+            (Some(delivery), message.id)
+        },
+
+        None => (None, 0),
+    };
+
+    save_state(state, path);
+
+    Ok(res)
+}
+
+pub fn ack_message(state: &mut State, queue: &rpubsub::QueueName, ip: &String, message_id: rpubsub::SequenceNum, path: &String) -> Result<(), rpubsub::ServiceError> {
+    if !state.queues.contains_key(queue) {
+        return Err(rpubsub::ServiceError::NOQUEUE);
+    }
+
+    let queue_info = state.queues.get_mut(queue).unwrap();
+
+    match leased_message_pos(queue_info, ip, message_id) {
+        Some(pos) => {
+            queue_info.messages.remove(pos);
+
+            save_state(state, path);
+
+            Ok(())
+        },
+
+        None => Err(rpubsub::ServiceError::NOLEASE)
+    }
+}
+
+pub fn requeue_message(state: &mut State, queue: &rpubsub::QueueName, ip: &String, message_id: rpubsub::SequenceNum, path: &String) -> Result<(), rpubsub::ServiceError> {
+    if !state.queues.contains_key(queue) {
+        return Err(rpubsub::ServiceError::NOQUEUE);
+    }
+
+    let queue_info = state.queues.get_mut(queue).unwrap();
+
+    match leased_message_pos(queue_info, ip, message_id) {
+        Some(pos) => {
+            queue_info.messages.get_mut(pos).unwrap().lease = None;
+
+            save_state(state, path);
+
+            Ok(())
+        },
+
+        None => Err(rpubsub::ServiceError::NOLEASE)
+    }
+}
@@ -1,190 +1,87 @@
-use std::{env, collections::HashMap};
-use std::fs;
-use serde::{Deserialize, Serialize};
+use std::env;
 
-use rpubsub::{SocketAddress};
+use rpubsub::{Endpoint};
+use topic::service;
 
+// Worker threads handling requests, unless RPUBSUB_WORKERS says otherwise
+const DEFAULT_WORKERS: usize = 4;
 
+fn main() {
+    let args: Vec<String> = env::args().collect();
 
-pub struct Server {
-    pub socket_address: SocketAddress,
-    pub state_path: String,
-    pub state: topic::State,
-}
-
-fn get_state_file_content(server: &mut Server) {
-    let server_path = String::from("./data/server_data/");
-    server.state_path = server_path.clone() + "state.json";
-
-    let res = fs::read_dir(&server_path);
-    const WITH_STATE: bool = true;
-
-    if res.is_err() {
-        println!("info: server state not found. creating one..");
-        fs::create_dir_all(&server_path);
-        let serialized_state = serde_json::to_string(&server.state).unwrap();
-
-        fs::write(&server.state_path, serialized_state);
-
-    } else {
-        println!("info: server state found. backing up..");
-        let state_json = fs::read(&server.state_path);
-        //String::from_utf8(fs::read(client_path + "state.json").unwrap()).unwrap();
-        //
-        server.state = serde_json::from_slice(&state_json.unwrap().as_slice()).unwrap();
-    }
-}
-
-fn process_get(server: &mut Server, topic: &rpubsub::Topic, ip: &String, sequence_num: rpubsub::SequenceNum) -> 
-                                                                Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
-    let res = topic::get_next_subscriber_update(&mut server.state, topic, ip, sequence_num, &server.state_path);
-    return match res {
-        Ok(opt) => Ok(rpubsub::ReplyOption::TUP(opt)),
-        Err(err) => Err(err),
-    }
-}
-
-fn process_put(server: &mut Server, topic: &rpubsub::Topic, content: &rpubsub::UpdateContent, _sequence_num: rpubsub::SequenceNum) -> 
-                                                                Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
-    let res = topic::add_update(&mut server.state, topic, content, &server.state_path);
-    return match res {
-        Ok(_) => Ok(rpubsub::ReplyOption::NoOk),
-        Err(err) => Err(err),
-    }
-}
-
-fn process_sub(server: &mut Server, topic: &rpubsub::Topic, ip: &String) -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
-    let res = topic::add_subscription(&mut server.state, topic, ip, &server.state_path);
-    return match res {
-        Ok(_) => Ok(rpubsub::ReplyOption::NoOk),
-        Err(err) => Err(err),
-    }
-}
-
-fn process_unsub(server: &mut Server, topic: &rpubsub::Topic, ip: &String) -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
-    let res = topic::remove_subscription(&mut server.state, topic, ip, &server.state_path);
-    return match res {
-        Ok(_) => Ok(rpubsub::ReplyOption::NoOk),
-        Err(err) => Err(err),
-    }
-}
-
-fn process_up(server: &mut Server, ip: &String, sequence_nums: &HashMap<rpubsub::Topic, rpubsub::SequenceNum>) 
-                                                        -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
-    for pair in sequence_nums {
-        let res = topic::update_subscriber_update_ack(&mut server.state, pair.0, ip, *pair.1,  &server.state_path);
-        if res.is_err() {
-            return Err(res.err().unwrap());
+    if args.len() == 3 && args[1] == "keygen" {
+        match rpubsub::generate_curve_keys(&args[2]) {
+            Ok(_) => println!("info: CURVE keys written to {} and {}.pub", args[2], args[2]),
+            Err(e) => println!("{}", e),
         }
+        return;
     }
 
-    return Ok(rpubsub::ReplyOption::NoOk);
-}
-
-
-fn process_request(server: &mut Server, request: &rpubsub::Message) -> (rpubsub::Message, String) {
-    let mut client_ip = String::from("<UNKNOWN>");
-
-    let result = match request {
-        rpubsub::Message::GET { ip, sequence_num, topic } => { 
-            client_ip = ip.clone(); process_get(server, &topic, &ip, *sequence_num) 
-        },
-
-        // TODO SEQUENCE ON PUT FOR IDEMPOTENCE
-        rpubsub::Message::PUT { ip, sequence_num, topic, payload } => { 
-            client_ip = ip.clone(); process_put(server, &topic, &payload, *sequence_num)
-        },
+    // The server binds to an endpoint, or to an IP and port over TCP
+    let (endpoint, limits_path) = match args.get(1).map(|arg| arg.contains("://")) {
+        Some(true) if args.len() <= 3 => (Endpoint::parse(&args[1]), args.get(2)),
+        Some(false) if args.len() == 3 || args.len() == 4 => (Endpoint::parse(&format!("tcp://{}:{}", args[1], args[2])), args.get(3)),
+        _ => {
+            println!("wrong number of arguments");
+            println!("Usage: server <IP> <BIND_PORT> [<LIMITS_FILE>]");
+            println!("       server <ENDPOINT> [<LIMITS_FILE>]");
+            println!("       server keygen <KEY_FILE>");
+            return;
+        }
+    };
 
-        rpubsub::Message::SUB { ip, topic } => { 
-            client_ip = ip.clone(); process_sub(server, &topic, &ip)
+    let endpoint = match endpoint {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            println!("{}", e);
+            return;
         },
+    };
 
-        rpubsub::Message::UNSUB { ip, topic } => { 
-            client_ip = ip.clone(); process_unsub(server, &topic, &ip) 
+    let state_key = match rpubsub::load_state_key() {
+        Ok(state_key) => state_key,
+        Err(e) => {
+            println!("{}", e);
+            return;
         },
+    };
 
-        rpubsub::Message::UP { ip, sequence_nums } => {
-            client_ip = ip.clone(); process_up(server, &ip, &sequence_nums)
+    let limits = match limits_path.map(topic::limits::load_limits) {
+        Some(Ok(config)) => Some(config),
+        Some(Err(e)) => {
+            println!("error: couldn't read limits file: {}", e);
+            return;
         },
-
-        rpubsub::Message::NOMSG => {
-            Err(rpubsub::ServiceError::UNKNOMSG)
-        }
-        // This one never happens
-        _ => Ok(rpubsub::ReplyOption::NoOk)
+        None => None,
     };
 
-    (rpubsub::Message::REP { result: result }, client_ip)
-}
-
-fn main() {
-    let args: Vec<String> = env::args().collect();
-
-    if args.len() != 3 {
-        println!("wrong number of arguments");
-        println!("Usage: server <IP> <BIND_PORT>");
-        return;
-    }
-    let mut server = Server {
-        socket_address: SocketAddress{ 
-            ip: args[1].clone(), 
-            port: args[2].clone().parse::<u16>().unwrap() 
+    let curve_config = match rpubsub::load_curve_config() {
+        Ok(curve_config) => curve_config,
+        Err(e) => {
+            println!("{}", e);
+            return;
         },
-        state_path: String::new(),
-        state: topic::State { topics: topic::Topics::new() },
     };
 
-
-    let context = zmq::Context::new();
-
-    let rep_socket = match context.socket(zmq::REP) {
-                            Ok(socket) => socket,
-                            Err(e) => {
-                                println!("error: couldn't create socket: {}", e);
-                                return;
-                            },
-                        };
-
-
-    get_state_file_content(&mut server);
-
-    match rpubsub::bind_to(&rep_socket, &server.socket_address) {
-        Ok(_) => println!("Server listening on {}:{}", server.socket_address.ip, server.socket_address.port),
-        Err(e) => { 
-            println!("{}", e.to_string().as_str());
+    let workers = match env::var("RPUBSUB_WORKERS").map(|workers| workers.parse::<usize>()) {
+        Err(_) => DEFAULT_WORKERS,
+        Ok(Ok(workers)) if workers > 0 => workers,
+        Ok(_) => {
+            println!("error: RPUBSUB_WORKERS must be a positive number");
             return;
         },
-    }
-
-    loop {
-        let request = match rpubsub::receive_message_from(&rep_socket) {
-            Ok(message) => {
-                println!("Received request: {}", message.to_string());
-                Some(message)
-            },
-            Err(e) => {
-                println!("{}", e.to_string());
-
-                match e {
-                    rpubsub::IOError::EDSL(_) => Some(rpubsub::Message::NOMSG),
-                    _ => None
-                }
-            },
-        };
-
-        if request.is_none() {
-            continue;
-        }
+    };
 
-        let (reply, client_ip) = process_request(&mut server, &request.unwrap());
+    let config = service::Config {
+        endpoint,
+        state_dir: String::from("./data/server_data/"),
+        workers,
+        limits,
+        curve_config,
+        state_key,
+    };
 
-        match rpubsub::send_message_to(&rep_socket, &reply) {
-            Ok(_) => {
-                println!("Sent reply to client {}: {}", client_ip, reply.to_string());
-            },
-            Err(e) => {
-                println!("{}", e.to_string());
-            },
-        };
+    if let Err(e) = service::run(&zmq::Context::new(), config) {
+        println!("{}", e);
     }
-}
\ No newline at end of file
+}
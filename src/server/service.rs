@@ -0,0 +1,1035 @@
+// The server's request handling: a ROUTER frontend hands the requests to a pool of
+// workers that apply them to the state. The server binary runs it, and other programs
+// may embed it with run, sharing their zmq::Context with clients that connect over inproc.
+
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+#[cfg(not(feature = "async"))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use rpubsub::{Endpoint};
+
+// How often the server releases scheduled updates and drops expired ones
+const TICK_MS: u64 = 500;
+const PERSIST_RETRY_MS: u64 = 1000;
+
+// Servers started in this process, which keeps apart the inproc endpoints of their
+// workers when they share a context
+#[cfg(not(feature = "async"))]
+static SERVERS: AtomicUsize = AtomicUsize::new(0);
+
+// What the server runs with. The server binary reads it from its arguments and environment.
+pub struct Config {
+    pub endpoint:     Endpoint,
+    // Directory of the state file and the blobs, created if missing
+    pub state_dir:    String,
+    pub workers:      usize,
+    pub limits:       Option<crate::limits::LimitsConfig>,
+    pub curve_config: rpubsub::CurveConfig,
+    pub state_key:    Option<rpubsub::StateKey>,
+}
+
+pub(crate) struct Server {
+    pub state_path: String,
+    pub state: crate::State,
+}
+
+pub(crate) fn get_state_file_content(server: &mut Server, server_path: &str) -> Result<(), String> {
+    let server_path = String::from(server_path);
+    server.state_path = server_path.clone() + "state.json";
+
+    let res = fs::read_dir(&server_path);
+    const WITH_STATE: bool = true;
+
+    let state_key = server.state.state_key.clone();
+
+    if res.is_err() {
+        println!("info: server state not found. creating one..");
+        fs::create_dir_all(&server_path);
+        let serialized_state = serde_json::to_vec(&server.state).unwrap();
+
+        fs::write(&server.state_path, rpubsub::seal_state(state_key.as_ref(), serialized_state));
+
+    } else {
+        println!("info: server state found. backing up..");
+        let state_json = fs::read(&server.state_path).map_err(|e| format!("error: couldn't read the server state: {}", e))?;
+        let encrypted = rpubsub::is_encrypted_state(&state_json);
+        //String::from_utf8(fs::read(client_path + "state.json").unwrap()).unwrap();
+        //
+        let state_json = rpubsub::open_state(state_key.as_ref(), state_json)?;
+        server.state = serde_json::from_slice(&state_json.as_slice()).unwrap();
+        server.state.state_key = state_key;
+
+        crate::restore_state(&mut server.state);
+
+        // Existing plaintext state is encrypted as soon as there is a key
+        if !encrypted && server.state.state_key.is_some() {
+            println!("info: encrypting plaintext server state..");
+            crate::save_state(&mut server.state, &server.state_path);
+        }
+    }
+
+    Ok(())
+}
+
+// Payloads are handed to the topics as the publisher wrote them
+fn decompress(content: &rpubsub::UpdateContent, compression: rpubsub::Compression) -> Result<rpubsub::UpdateContent, rpubsub::ServiceError> {
+    match rpubsub::decompress_payload(content, compression) {
+        Some(content) => Ok(content),
+        None => Err(rpubsub::ServiceError::BADPAYLOAD),
+    }
+}
+
+fn process_get(server: &mut Server, topic: &rpubsub::Topic, ip: &String, sequence_num: rpubsub::SequenceNum, accept: rpubsub::Compression) -> 
+                                                                Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let res = crate::get_next_subscriber_update(&mut server.state, topic, ip, sequence_num, &server.state_path);
+    match res {
+        Ok((delivery, seq)) => Ok(rpubsub::ReplyOption::TUP((delivery.map(|delivery| delivery.compressed(accept)), seq))),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_put(server: &mut Server, topic: &rpubsub::Topic, ip: &String, content: &rpubsub::UpdateContent, sequence_num: rpubsub::SequenceNum,
+                                options: &rpubsub::PutOptions, compression: rpubsub::Compression) -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let content = decompress(content, compression)?;
+    let res = crate::publish_update(&mut server.state, topic, ip, sequence_num, &content, options, &server.state_path);
+    match res {
+        Ok(receipt) => Ok(rpubsub::ReplyOption::PUTOK(receipt)),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_txput(server: &mut Server, ip: &String, sequence_num: rpubsub::SequenceNum, updates: &Vec<rpubsub::TxUpdate>)
+                                                        -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let mut decompressed = Vec::new();
+
+    for update in updates {
+        decompressed.push(rpubsub::TxUpdate {
+            topic: update.topic.clone(),
+            payload: decompress(&update.payload, update.compression)?,
+            options: update.options.clone(),
+            compression: rpubsub::Compression::NONE
+        });
+    }
+
+    let res = crate::publish_transaction(&mut server.state, ip, sequence_num, &decompressed, &server.state_path);
+    match res {
+        Ok(receipts) => Ok(rpubsub::ReplyOption::TXOK(receipts)),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_chunk(server: &mut Server, ip: &String, upload_id: &rpubsub::BlobId, index: u64, data: &str) -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let res = crate::blob::add_chunk(&mut server.state, ip, upload_id, index, data, &server.state_path);
+    match res {
+        Ok(next_index) => Ok(rpubsub::ReplyOption::CHUNKOK(next_index)),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_commit(server: &mut Server, ip: &String, upload_id: &rpubsub::BlobId, topic: &rpubsub::Topic, content: &rpubsub::UpdateContent,
+                                options: &rpubsub::PutOptions) -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let res = crate::publish_blob(&mut server.state, topic, ip, upload_id, content, options, &server.state_path);
+    match res {
+        Ok(receipt) => Ok(rpubsub::ReplyOption::PUTOK(receipt)),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_fetch(server: &mut Server, topic: &rpubsub::Topic, ip: &String, blob_id: &rpubsub::BlobId, index: u64) -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let res = crate::fetch_blob_chunk(&server.state, topic, ip, blob_id, index, &server.state_path);
+    match res {
+        Ok(chunk) => Ok(rpubsub::ReplyOption::DATA(chunk)),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_sub(server: &mut Server, topic: &rpubsub::Topic, ip: &String, filter: &Option<String>) -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let res = crate::add_subscription(&mut server.state, topic, ip, filter, &server.state_path);
+    match res {
+        Ok(_) => Ok(rpubsub::ReplyOption::NoOk),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_unsub(server: &mut Server, topic: &rpubsub::Topic, ip: &String) -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let res = crate::remove_subscription(&mut server.state, topic, ip, &server.state_path);
+    match res {
+        Ok(_) => Ok(rpubsub::ReplyOption::NoOk),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_up(server: &mut Server, ip: &String, sequence_nums: &HashMap<rpubsub::Topic, rpubsub::SequenceNum>) 
+                                                        -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    for pair in sequence_nums {
+        let res = crate::update_subscriber_update_ack(&mut server.state, pair.0, ip, *pair.1,  &server.state_path);
+        if res.is_err() {
+            return Err(res.err().unwrap());
+        }
+    }
+
+    return Ok(rpubsub::ReplyOption::NoOk);
+}
+
+fn process_join(server: &mut Server, topic: &rpubsub::Topic, group: &rpubsub::GroupName, ip: &String) -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let res = crate::add_group_member(&mut server.state, topic, group, ip, &server.state_path);
+    match res {
+        Ok(_) => Ok(rpubsub::ReplyOption::NoOk),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_leave(server: &mut Server, topic: &rpubsub::Topic, group: &rpubsub::GroupName, ip: &String) -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let res = crate::remove_group_member(&mut server.state, topic, group, ip, &server.state_path);
+    match res {
+        Ok(_) => Ok(rpubsub::ReplyOption::NoOk),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_gget(server: &mut Server, topic: &rpubsub::Topic, group: &rpubsub::GroupName, ip: &String, ack: Option<rpubsub::SequenceNum>,
+                                accept: rpubsub::Compression) -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let res = crate::get_next_group_update(&mut server.state, topic, group, ip, ack, &server.state_path);
+    match res {
+        Ok((delivery, seq)) => Ok(rpubsub::ReplyOption::TUP((delivery.map(|delivery| delivery.compressed(accept)), seq))),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_qput(server: &mut Server, queue: &rpubsub::QueueName, ip: &str, sequence_num: rpubsub::SequenceNum, content: &rpubsub::UpdateContent,
+                    compression: rpubsub::Compression) -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let content = decompress(content, compression)?;
+    let res = crate::queue::add_message(&mut server.state, queue, ip, sequence_num, &content, &server.state_path);
+    match res {
+        Ok(_) => Ok(rpubsub::ReplyOption::NoOk),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_qget(server: &mut Server, queue: &rpubsub::QueueName, ip: &str, accept: rpubsub::Compression) -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let res = crate::queue::lease_next_message(&mut server.state, queue, ip, &server.state_path);
+    match res {
+        Ok((delivery, seq)) => Ok(rpubsub::ReplyOption::TUP((delivery.map(|delivery| delivery.compressed(accept)), seq))),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_qack(server: &mut Server, queue: &rpubsub::QueueName, ip: &String, message_id: rpubsub::SequenceNum) -> 
+                                                                Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let res = crate::queue::ack_message(&mut server.state, queue, ip, message_id, &server.state_path);
+    match res {
+        Ok(_) => Ok(rpubsub::ReplyOption::NoOk),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_qnack(server: &mut Server, queue: &rpubsub::QueueName, ip: &String, message_id: rpubsub::SequenceNum) -> 
+                                                                Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let res = crate::queue::requeue_message(&mut server.state, queue, ip, message_id, &server.state_path);
+    match res {
+        Ok(_) => Ok(rpubsub::ReplyOption::NoOk),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_conf(server: &mut Server, topic: &rpubsub::Topic, option: &rpubsub::TopicOption) -> Result<rpubsub::ReplyOption, rpubsub::ServiceError> {
+    let res = crate::configure_topic(&mut server.state, topic, option, &server.state_path);
+    match res {
+        Ok(_) => Ok(rpubsub::ReplyOption::NoOk),
+        Err(err) => Err(err),
+    }
+}
+
+fn process_request(server: &mut Server, request: &rpubsub::Message) -> (rpubsub::Message, String) {
+    let mut client_ip = String::from("<UNKNOWN>");
+
+    let result = match request {
+        rpubsub::Message::GET { ip, sequence_num, topic, accept } => { 
+            client_ip = ip.clone(); process_get(server, topic, ip, *sequence_num, *accept) 
+        },
+
+        rpubsub::Message::PUT { ip, sequence_num, topic, payload, options, compression } => { 
+            client_ip = ip.clone(); process_put(server, topic, ip, payload, *sequence_num, options, *compression)
+        },
+
+        rpubsub::Message::SUB { ip, topic, filter } => { 
+            client_ip = ip.clone(); process_sub(server, topic, ip, filter)
+        },
+
+        rpubsub::Message::UNSUB { ip, topic } => { 
+            client_ip = ip.clone(); process_unsub(server, topic, ip) 
+        },
+
+        rpubsub::Message::UP { ip, sequence_nums } => {
+            client_ip = ip.clone(); process_up(server, ip, sequence_nums)
+        },
+
+        rpubsub::Message::JOIN { ip, topic, group } => {
+            client_ip = ip.clone(); process_join(server, topic, group, ip)
+        },
+
+        rpubsub::Message::LEAVE { ip, topic, group } => {
+            client_ip = ip.clone(); process_leave(server, topic, group, ip)
+        },
+
+        rpubsub::Message::GGET { ip, topic, group, ack, accept } => {
+            client_ip = ip.clone(); process_gget(server, topic, group, ip, *ack, *accept)
+        },
+
+        rpubsub::Message::QPUT { ip, queue, sequence_num, payload, compression } => {
+            client_ip = ip.clone(); process_qput(server, queue, ip, *sequence_num, payload, *compression)
+        },
+
+        rpubsub::Message::QGET { ip, queue, accept } => {
+            client_ip = ip.clone(); process_qget(server, queue, ip, *accept)
+        },
+
+        rpubsub::Message::QACK { ip, queue, message_id } => {
+            client_ip = ip.clone(); process_qack(server, queue, ip, *message_id)
+        },
+
+        rpubsub::Message::QNACK { ip, queue, message_id } => {
+            client_ip = ip.clone(); process_qnack(server, queue, ip, *message_id)
+        },
+
+        rpubsub::Message::CONF { ip, topic, option } => {
+            client_ip = ip.clone(); process_conf(server, topic, option)
+        },
+
+        rpubsub::Message::TXPUT { ip, sequence_num, updates } => {
+            client_ip = ip.clone(); process_txput(server, ip, *sequence_num, updates)
+        },
+
+        rpubsub::Message::CHUNK { ip, upload_id, index, data } => {
+            client_ip = ip.clone(); process_chunk(server, ip, upload_id, *index, data)
+        },
+
+        rpubsub::Message::COMMIT { ip, upload_id, topic, payload, options } => {
+            client_ip = ip.clone(); process_commit(server, ip, upload_id, topic, payload, options)
+        },
+
+        rpubsub::Message::FETCH { ip, topic, blob_id, index } => {
+            client_ip = ip.clone(); process_fetch(server, topic, ip, blob_id, *index)
+        },
+
+        rpubsub::Message::NOMSG => {
+            Err(rpubsub::ServiceError::UNKNOMSG)
+        }
+        // This one never happens
+        _ => Ok(rpubsub::ReplyOption::NoOk)
+    };
+
+    (rpubsub::Message::REP { result }, client_ip)
+}
+
+// The state, split by topic and queue so that requests on different topics don't wait
+// for each other. Each shard is a State holding some of the topics and queues, the first
+// one also holds the transactions, uploads and blobs. Requests that may change more than
+// one topic run on the whole state, put back together while they hold every shard.
+pub(crate) struct Shards {
+    state_path: String,
+    shards:     Vec<Mutex<Server>>,
+    // Held by the requests on the whole state, and by the persister while it copies the
+    // shards, so that its copy never has such a request half done
+    whole:      Mutex<()>,
+}
+
+fn key_index(key: &str, count: usize) -> usize {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    key.hash(&mut hasher);
+
+    (hasher.finish() % count as u64) as usize
+}
+
+// The topic or queue of the requests that may run on a single shard
+fn shard_key(request: &rpubsub::Message) -> Option<&str> {
+    match request {
+        rpubsub::Message::GET { topic, .. }
+        | rpubsub::Message::PUT { topic, .. }
+        | rpubsub::Message::SUB { topic, .. }
+        | rpubsub::Message::UNSUB { topic, .. }
+        | rpubsub::Message::JOIN { topic, .. }
+        | rpubsub::Message::LEAVE { topic, .. }
+        | rpubsub::Message::GGET { topic, .. } => Some(topic),
+        rpubsub::Message::QPUT { queue, .. }
+        | rpubsub::Message::QGET { queue, .. }
+        | rpubsub::Message::QACK { queue, .. }
+        | rpubsub::Message::QNACK { queue, .. } => Some(queue),
+        _ => None,
+    }
+}
+
+// Requests on a topic that still need the whole state: those moving updates to the
+// topic's dead-letter topic, and PUTs of a publisher whose quota covers all the topics
+fn needs_whole_state(state: &crate::State, request: &rpubsub::Message) -> bool {
+    match request {
+        rpubsub::Message::PUT { ip, .. } if state.limits.client_limits(ip).quota_bytes.is_some() => true,
+        rpubsub::Message::GET { topic, .. }
+        | rpubsub::Message::PUT { topic, .. }
+        | rpubsub::Message::SUB { topic, .. }
+        | rpubsub::Message::UNSUB { topic, .. }
+        | rpubsub::Message::JOIN { topic, .. }
+        | rpubsub::Message::LEAVE { topic, .. }
+        | rpubsub::Message::GGET { topic, .. } => crate::has_dead_letter_topic(state, topic),
+        _ => false,
+    }
+}
+
+// Puts the topics and queues of all the shards in the first one
+fn merge(shards: &mut [&mut Server]) {
+    let (first, others) = shards.split_first_mut().unwrap();
+
+    for other in others {
+        first.state.topics.extend(other.state.topics.drain());
+        first.state.queues.extend(other.state.queues.drain());
+        first.state.generation = first.state.generation.max(other.state.generation);
+    }
+}
+
+// Moves the topics and queues of the first shard to the shards they belong to, which
+// may have changed as of its last generation
+fn split(shards: &mut [&mut Server]) {
+    let count = shards.len();
+    let (first, others) = shards.split_first_mut().unwrap();
+
+    for other in others.iter_mut() {
+        other.state.generation = first.state.generation;
+    }
+
+    for (topic, topic_info) in std::mem::take(&mut first.state.topics) {
+        match key_index(&topic, count) {
+            0 => first.state.topics.insert(topic, topic_info),
+            index => others[index - 1].state.topics.insert(topic, topic_info),
+        };
+    }
+
+    for (queue, queue_info) in std::mem::take(&mut first.state.queues) {
+        match key_index(&queue, count) {
+            0 => first.state.queues.insert(queue, queue_info),
+            index => others[index - 1].state.queues.insert(queue, queue_info),
+        };
+    }
+}
+
+impl Shards {
+    pub(crate) fn new(server: Server, count: usize) -> Self {
+        let Server { state_path, state } = server;
+
+        let mut shards: Vec<Server> = (1..count)
+            .map(|_| Server {
+                state_path: state_path.clone(),
+                state: crate::State { limits: state.limits.clone(), state_key: state.state_key.clone(), persister: state.persister.clone(), ..crate::State::new() },
+            })
+            .collect();
+        shards.insert(0, Server { state_path: state_path.clone(), state });
+
+        split(&mut shards.iter_mut().collect::<Vec<_>>());
+
+        Self { state_path, shards: shards.into_iter().map(Mutex::new).collect(), whole: Mutex::new(()) }
+    }
+
+    // Handles the request, and tells which generation of the state file holds what the
+    // reply is based on: the last change of the shard, or of any shard for the requests
+    // on the whole state
+    fn process(&self, request: &rpubsub::Message) -> (rpubsub::Message, String, u64) {
+        if let Some(key) = shard_key(request) {
+            let mut shard = self.shards[key_index(key, self.shards.len())].lock().unwrap();
+
+            if !needs_whole_state(&shard.state, request) {
+                let (reply, client_ip) = process_request(&mut shard, request);
+
+                return (reply, client_ip, shard.state.generation);
+            }
+        }
+
+        self.with_whole_state(|server| {
+            let (reply, client_ip) = process_request(server, request);
+
+            (reply, client_ip, server.state.generation)
+        })
+    }
+
+    fn with_whole_state<R>(&self, f: impl FnOnce(&mut Server) -> R) -> R {
+        let _whole = self.whole.lock().unwrap();
+        let mut guards: Vec<MutexGuard<Server>> = self.shards.iter().map(|shard| shard.lock().unwrap()).collect();
+        let mut shards: Vec<&mut Server> = guards.iter_mut().map(|shard| &mut **shard).collect();
+
+        merge(&mut shards);
+        let result = f(shards[0]);
+        split(&mut shards);
+
+        result
+    }
+
+    // The content of the state file, and the generation of the last change it holds. The
+    // shards are copied one at a time, the others going on with their requests, and the
+    // copy is serialized and encrypted without holding any of them.
+    fn snapshot(&self, persister: &crate::persist::Persister) -> (u64, Vec<u8>) {
+        let (generation, copies) = {
+            let _whole = self.whole.lock().unwrap();
+            let generation = persister.requested();
+
+            (generation, self.shards.iter().map(|shard| shard.lock().unwrap().state.clone()).collect::<Vec<_>>())
+        };
+
+        let mut copies = copies.into_iter();
+        let mut state = copies.next().unwrap();
+
+        for copy in copies {
+            state.topics.extend(copy.topics);
+            state.queues.extend(copy.queues);
+        }
+
+        (generation, crate::serialize_state(&state))
+    }
+}
+
+#[cfg(not(feature = "async"))]
+fn worker_endpoint(id: usize, index: usize) -> String {
+    format!("inproc://rpubsub-worker-{}-{}", id, index)
+}
+
+// What the request works on. Requests on the same topic (or queue, or from the same
+// client when they span several topics) go to the same worker, which handles them in
+// the order they arrived.
+fn routing_key(request: &rpubsub::Message) -> &str {
+    match request {
+        rpubsub::Message::GET { topic, .. }
+        | rpubsub::Message::PUT { topic, .. }
+        | rpubsub::Message::SUB { topic, .. }
+        | rpubsub::Message::UNSUB { topic, .. }
+        | rpubsub::Message::JOIN { topic, .. }
+        | rpubsub::Message::LEAVE { topic, .. }
+        | rpubsub::Message::GGET { topic, .. }
+        | rpubsub::Message::CONF { topic, .. }
+        | rpubsub::Message::COMMIT { topic, .. }
+        | rpubsub::Message::FETCH { topic, .. } => topic,
+        rpubsub::Message::QPUT { queue, .. }
+        | rpubsub::Message::QGET { queue, .. }
+        | rpubsub::Message::QACK { queue, .. }
+        | rpubsub::Message::QNACK { queue, .. } => queue,
+        rpubsub::Message::UP { ip, .. }
+        | rpubsub::Message::TXPUT { ip, .. }
+        | rpubsub::Message::CHUNK { ip, .. } => ip,
+        _ => "",
+    }
+}
+
+fn worker_index(request: &[u8], workers: usize) -> usize {
+    let request: rpubsub::Message = match serde_json::from_slice(request) {
+        Ok(request) => request,
+        Err(_) => return 0,
+    };
+
+    key_index(routing_key(&request), workers)
+}
+
+// Requests that can't be read are still answered, as NOMSG
+fn read_request(received: Result<rpubsub::Message, rpubsub::IOError>) -> Option<rpubsub::Message> {
+    match received {
+        Ok(message) => {
+            println!("Received request: {}", message.to_log_string());
+            Some(message)
+        },
+        Err(rpubsub::IOError::EDSL(e)) => {
+            println!("{}", rpubsub::IOError::EDSL(e).to_string());
+            Some(rpubsub::Message::NOMSG)
+        },
+        Err(e) => {
+            println!("{}", e.to_string());
+            None
+        },
+    }
+}
+
+// The state is only held while a request is handled in memory: the worker then waits
+// for the state file to hold its changes before replying, letting the other workers go
+// on, so that one write covers the changes of many requests
+fn handle_request(shards: &Shards, persister: &crate::persist::Persister, request: &rpubsub::Message) -> (rpubsub::Message, String) {
+    let (reply, client_ip, generation) = shards.process(request);
+
+    persister.wait_written(generation);
+
+    (reply, client_ip)
+}
+
+// Answers the next request that comes through the transport
+#[cfg(not(feature = "async"))]
+pub(crate) fn serve_request(transport: &impl rpubsub::transport::Transport, shards: &Shards, persister: &crate::persist::Persister) {
+    let request = match read_request(transport.receive()) {
+        Some(request) => request,
+        None => return,
+    };
+
+    let (reply, client_ip) = handle_request(shards, persister, &request);
+
+    match transport.send(&reply) {
+        Ok(_) => {
+            println!("Sent reply to client {}: {}", client_ip, reply.to_log_string());
+        },
+        Err(e) => {
+            println!("{}", e.to_string());
+        },
+    };
+}
+
+// A REP socket behind the frontend's DEALER, which keeps the client's envelope for the reply
+#[cfg(not(feature = "async"))]
+fn run_worker(context: zmq::Context, id: usize, index: usize, shards: Arc<Shards>, persister: crate::persist::Persister) {
+    let socket = context.socket(zmq::REP).unwrap();
+    socket.connect(&worker_endpoint(id, index)).unwrap();
+
+    loop {
+        serve_request(&socket, &shards, &persister);
+    }
+}
+
+// Writes the state whenever it changed, once for all the changes made while the
+// previous write was going on. A failed write is retried, and the workers waiting on it
+// don't reply until it goes through
+fn run_persister(shards: Arc<Shards>, persister: crate::persist::Persister) {
+    loop {
+        persister.wait_requested();
+
+        let (generation, content) = shards.snapshot(&persister);
+
+        match crate::write_state(&content, &shards.state_path) {
+            Ok(_) => persister.set_written(generation),
+            Err(e) => {
+                println!("{}", e);
+                thread::sleep(Duration::from_millis(PERSIST_RETRY_MS));
+            },
+        }
+    }
+}
+
+fn run_ticks(shards: Arc<Shards>) {
+    loop {
+        thread::sleep(Duration::from_millis(TICK_MS));
+
+        shards.with_whole_state(|server| {
+            crate::release_scheduled_updates(&mut server.state, &server.state_path);
+            crate::remove_expired_updates(&mut server.state, &server.state_path);
+            crate::compact_topics(&mut server.state, &server.state_path);
+            crate::remove_unused_blobs(&mut server.state, &server.state_path);
+        });
+    }
+}
+
+// Hands each request to the worker thread of its topic and each reply back to its client
+#[cfg(not(feature = "async"))]
+fn run_frontend(context: &zmq::Context, router_socket: &zmq::Socket, workers: usize, shards: Arc<Shards>, persister: crate::persist::Persister)
+                                                                -> Result<(), String> {
+    let id = SERVERS.fetch_add(1, Ordering::Relaxed);
+    let mut worker_sockets = Vec::new();
+
+    for index in 0..workers {
+        let socket = context.socket(zmq::DEALER).unwrap();
+
+        if let Err(e) = socket.bind(&worker_endpoint(id, index)) {
+            return Err(format!("error: couldn't bind the worker socket - {}", e));
+        }
+
+        worker_sockets.push(socket);
+    }
+
+    for index in 0..workers {
+        let (context, shards, persister) = (context.clone(), shards.clone(), persister.clone());
+        thread::spawn(move || run_worker(context, id, index, shards, persister));
+    }
+
+    loop {
+        let mut items = vec![router_socket.as_poll_item(zmq::POLLIN)];
+        items.extend(worker_sockets.iter().map(|socket| socket.as_poll_item(zmq::POLLIN)));
+
+        if let Err(e) = zmq::poll(&mut items, -1) {
+            println!("error: couldn't poll the sockets - {}", e);
+            continue;
+        }
+
+        // [client identity, empty delimiter, request]
+        if items[0].is_readable() {
+            if let Ok((mut frames, user_id)) = rpubsub::recv_multipart_with_user_id(router_socket, 0) {
+                if frames.last().is_some_and(|request| rpubsub::is_allowed_sender(request, user_id.as_deref())) {
+                    let worker = frames.last().map_or(0, |request| worker_index(request, workers));
+                    let _ = worker_sockets[worker].send_multipart(frames, 0);
+                } else {
+                    rpubsub::reject_sender(&mut frames);
+                    let _ = router_socket.send_multipart(frames, 0);
+                }
+            }
+        }
+
+        for (index, socket) in worker_sockets.iter().enumerate() {
+            if items[index + 1].is_readable() {
+                if let Ok(frames) = socket.recv_multipart(0) {
+                    if let Err(e) = router_socket.send_multipart(frames, 0) {
+                        println!("error: couldn't send message - {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Same as run_frontend on a tokio runtime: the router is polled asynchronously and each
+// worker is a task, handing the requests of its topics one at a time to the blocking pool
+#[cfg(feature = "async")]
+fn run_async_frontend(router_socket: zmq::Socket, workers: usize, shards: Arc<Shards>, persister: crate::persist::Persister) -> Result<(), String> {
+    use tokio::sync::mpsc;
+
+    let runtime = match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => return Err(format!("error: couldn't start the async runtime - {}", e)),
+    };
+
+    runtime.block_on(async move {
+        let router_socket = match rpubsub::async_io::AsyncSocket::new(router_socket) {
+            Ok(socket) => socket,
+            Err(e) => return Err(format!("error: couldn't register the socket - {}", e)),
+        };
+
+        let (reply_sender, mut replies) = mpsc::unbounded_channel::<(Vec<Vec<u8>>, String, String)>();
+
+        let worker_senders: Vec<mpsc::UnboundedSender<Vec<Vec<u8>>>> = (0..workers)
+            .map(|_| {
+                let (sender, mut requests) = mpsc::unbounded_channel::<Vec<Vec<u8>>>();
+                let (shards, persister, reply_sender) = (shards.clone(), persister.clone(), reply_sender.clone());
+
+                tokio::spawn(async move {
+                    while let Some(mut frames) = requests.recv().await {
+                        let request = match read_request(serde_json::from_slice(&frames.pop().unwrap_or_default()).map_err(rpubsub::IOError::EDSL)) {
+                            Some(request) => request,
+                            None => continue,
+                        };
+                        let (shards, persister) = (shards.clone(), persister.clone());
+
+                        let (reply, client_ip) = match tokio::task::spawn_blocking(move || handle_request(&shards, &persister, &request)).await {
+                            Ok(handled) => handled,
+                            Err(e) => {
+                                println!("error: request handler failed - {}", e);
+                                continue;
+                            },
+                        };
+
+                        frames.push(reply.to_string().into_bytes());
+                        let _ = reply_sender.send((frames, client_ip, reply.to_log_string()));
+                    }
+                });
+
+                sender
+            })
+            .collect();
+
+        loop {
+            tokio::select! {
+                // [client identity, empty delimiter, request]
+                frames = router_socket.recv_multipart_with_user_id() => match frames {
+                    Ok((mut frames, user_id)) if !frames.last().is_some_and(|request| rpubsub::is_allowed_sender(request, user_id.as_deref())) => {
+                        rpubsub::reject_sender(&mut frames);
+                        let _ = router_socket.send_multipart(&frames).await;
+                    },
+                    Ok((frames, _)) => {
+                        let worker = frames.last().map_or(0, |request| worker_index(request, workers));
+                        let _ = worker_senders[worker].send(frames);
+                    },
+                    Err(e) => println!("{}", rpubsub::IOError::ERCV(e).to_string()),
+                },
+                Some((frames, client_ip, reply)) = replies.recv() => match router_socket.send_multipart(&frames).await {
+                    Ok(_) => println!("Sent reply to client {}: {}", client_ip, reply),
+                    Err(e) => println!("{}", rpubsub::IOError::ESND(e).to_string()),
+                },
+            }
+        }
+    })
+}
+
+
+// Runs the server on the given context, so that clients on the same context can reach
+// it over inproc. Only returns if the server couldn't start.
+pub fn run(context: &zmq::Context, config: Config) -> Result<(), String> {
+    let mut server = Server {
+        state_path: String::new(),
+        state: crate::State::new(),
+    };
+
+    server.state.state_key = config.state_key;
+
+    get_state_file_content(&mut server, &config.state_dir)?;
+
+    if let Some(limits) = config.limits {
+        server.state.limits = crate::limits::Limits::new(limits);
+    }
+
+    let router_socket = context.socket(zmq::ROUTER).map_err(|e| format!("error: couldn't create socket: {}", e))?;
+
+    rpubsub::secure_server_socket(context, &router_socket, &config.curve_config).map_err(|e| e.to_string())?;
+    rpubsub::bind_to(&router_socket, &config.endpoint).map_err(|e| e.to_string())?;
+
+    println!("Server listening on {}", config.endpoint);
+
+    let persister = crate::persist::Persister::new();
+    server.state.persister = Some(persister.clone());
+
+    // One shard per worker: the requests of a worker's topics all go to its shard
+    let shards = Arc::new(Shards::new(server, config.workers));
+
+    {
+        let (shards, persister) = (shards.clone(), persister.clone());
+        thread::spawn(move || run_persister(shards, persister));
+    }
+
+    {
+        let shards = shards.clone();
+        thread::spawn(move || run_ticks(shards));
+    }
+
+    #[cfg(not(feature = "async"))]
+    return run_frontend(context, &router_socket, config.workers, shards, persister);
+
+    #[cfg(feature = "async")]
+    return run_async_frontend(router_socket, config.workers, shards, persister);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use rpubsub::transport::Transport;
+    use rpubsub::{Message, ReplyOption};
+
+    use super::*;
+
+    // Runs a server on the context at inproc://<name>, and returns the directory of its state
+    fn start_server(context: &zmq::Context, name: &str) -> std::path::PathBuf {
+        start_server_on(context, name, Endpoint::INPROC(String::from(name)), rpubsub::CurveConfig::default())
+    }
+
+    fn start_server_on(context: &zmq::Context, name: &str, endpoint: Endpoint, curve_config: rpubsub::CurveConfig) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}/", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let config = Config {
+            endpoint,
+            state_dir:    dir.to_string_lossy().into_owned(),
+            workers:      2,
+            limits:       None,
+            curve_config,
+            state_key:    None,
+        };
+
+        let context = context.clone();
+        thread::spawn(move || run(&context, config));
+
+        dir
+    }
+
+    // A client on the same context reaches the server over inproc, without the network
+    #[test]
+    fn inproc_round_trip() {
+        const SERVER: &str = "rpubsub-test-server";
+
+        let context = zmq::Context::new();
+        let dir = start_server(&context, SERVER);
+
+        let socket = context.socket(zmq::REQ).unwrap();
+        rpubsub::connect_to(&socket, &Endpoint::INPROC(String::from(SERVER))).unwrap();
+
+        let request = |message: Message| {
+            Transport::send(&socket, &message).unwrap();
+
+            match Transport::receive(&socket).unwrap() {
+                Message::REP { result } => result,
+                reply => panic!("unexpected reply {:?}", reply),
+            }
+        };
+
+        let (ip, topic) = (String::from("embedded"), String::from("news"));
+
+        assert!(matches!(request(Message::SUB { ip: ip.clone(), topic: topic.clone(), filter: None }), Ok(ReplyOption::NoOk)));
+        assert!(matches!(request(Message::PUT {
+            ip: ip.clone(),
+            topic: topic.clone(),
+            sequence_num: rpubsub::first_sequence_num(),
+            payload: String::from("hello"),
+            options: rpubsub::PutOptions::default(),
+            compression: rpubsub::Compression::default(),
+        }), Ok(ReplyOption::PUTOK(_))));
+
+        match request(Message::GET { ip, topic, sequence_num: 0, accept: rpubsub::Compression::default() }) {
+            Ok(ReplyOption::TUP((Some(delivery), _))) => assert_eq!(delivery.payload, "hello"),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // Over CURVE, only clients with a listed key get through, and only for their own
+    // client id. A client that doesn't pin the server's key gets nothing.
+    #[test]
+    fn curve_clients_are_authenticated() {
+        const SERVER: &str = "rpubsub-test-curve-server";
+
+        // libzmq built without libsodium has no CURVE
+        if !zmq::has("curve").unwrap_or(false) {
+            println!("info: libzmq has no CURVE, skipping");
+            return;
+        }
+
+        let context = zmq::Context::new();
+        let socket_path = std::env::temp_dir().join(format!("{}-{}.ipc", SERVER, std::process::id()));
+        let endpoint = || Endpoint::IPC(socket_path.to_string_lossy().into_owned());
+
+        let server_keys = zmq::CurveKeyPair::new().unwrap();
+        let alice_keys = zmq::CurveKeyPair::new().unwrap();
+        let encode = |key: &[u8]| zmq::z85_encode(key).unwrap();
+
+        let server_config = rpubsub::CurveConfig {
+            keys:       Some(rpubsub::CurveKeys { public: encode(&server_keys.public_key), secret: Some(encode(&server_keys.secret_key)) }),
+            server_key: None,
+            clients:    Some(HashMap::from([(encode(&alice_keys.public_key), String::from("alice"))])),
+        };
+
+        let dir = start_server_on(&context, SERVER, endpoint(), server_config);
+
+        let connect = |keys: Option<&zmq::CurveKeyPair>, server_key: Option<String>| {
+            let socket = context.socket(zmq::REQ).unwrap();
+            socket.set_rcvtimeo(1000).unwrap();
+            socket.set_linger(0).unwrap();
+
+            let config = rpubsub::CurveConfig {
+                keys: keys.map(|keys| rpubsub::CurveKeys { public: encode(&keys.public_key), secret: Some(encode(&keys.secret_key)) }),
+                server_key,
+                clients: None,
+            };
+
+            rpubsub::secure_client_socket(&socket, &config).unwrap();
+            rpubsub::connect_to(&socket, &endpoint()).unwrap();
+            socket
+        };
+
+        let sub = |ip: &str| Message::SUB { ip: String::from(ip), topic: String::from("news"), filter: None };
+
+        let alice = connect(Some(&alice_keys), Some(encode(&server_keys.public_key)));
+
+        Transport::send(&alice, &sub("alice")).unwrap();
+        assert!(matches!(Transport::receive(&alice).unwrap(), Message::REP { result: Ok(ReplyOption::NoOk) }));
+
+        Transport::send(&alice, &sub("bob")).unwrap();
+        assert!(matches!(Transport::receive(&alice).unwrap(), Message::REP { result: Err(rpubsub::ServiceError::BADSENDER) }));
+
+        // A key the server doesn't know, and no CURVE at all
+        for stranger in [connect(None, Some(encode(&server_keys.public_key))), connect(None, None)] {
+            Transport::send(&stranger, &sub("alice")).unwrap();
+            assert!(Transport::receive(&stranger).is_err());
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_file(&socket_path);
+    }
+
+    // The async client puts and gets through the async frontend. Its PUTs go on from the
+    // server's count when they are behind it, and a PUT the server already has is a
+    // success, published once.
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_client_round_trip() {
+        const SERVER: &str = "rpubsub-test-async-server";
+
+        let context = zmq::Context::new();
+        let dir = start_server(&context, SERVER);
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        runtime.block_on(async {
+            let endpoint = Endpoint::INPROC(String::from(SERVER));
+            let mut client = rpubsub::async_io::AsyncClient::connect(&context, &endpoint, &rpubsub::CurveConfig::default(), "async").unwrap();
+            let ahead = rpubsub::first_sequence_num() + 1000;
+
+            let put = |sequence_num, payload: &str| Message::PUT {
+                ip: String::from("async"),
+                topic: String::from("news"),
+                sequence_num,
+                payload: String::from(payload),
+                options: rpubsub::PutOptions::default(),
+                compression: rpubsub::Compression::default(),
+            };
+
+            client.subscribe("news", None).await.unwrap();
+
+            // Put by an earlier run of the client, ahead of its clock
+            assert!(matches!(client.request(&put(ahead, "first")).await.unwrap(), Message::REP { result: Ok(ReplyOption::PUTOK(_)) }));
+            assert!(client.put("news", "second", rpubsub::PutOptions::default()).await.unwrap().is_some());
+
+            // The next PUT of put, as if the server got it and its reply was lost
+            assert!(matches!(client.request(&put(ahead + 2, "third")).await.unwrap(), Message::REP { result: Ok(ReplyOption::PUTOK(_)) }));
+            assert!(client.put("news", "third", rpubsub::PutOptions::default()).await.unwrap().is_none());
+
+            for payload in ["first", "second", "third"] {
+                assert_eq!(client.get("news").await.unwrap().map(|delivery| delivery.payload), Some(String::from(payload)));
+            }
+
+            assert!(client.get("news").await.unwrap().is_none());
+        });
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // Requests on the topics of different shards, and a transaction spanning them, all
+    // end up in the state the persister writes, and the topics back in their shards
+    #[test]
+    fn shards_are_saved_together() {
+        let dir = std::env::temp_dir().join(format!("rpubsub-service-shards-{}/", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut server = Server { state_path: String::new(), state: crate::State::new() };
+        get_state_file_content(&mut server, &dir.to_string_lossy()).unwrap();
+
+        let persister = crate::persist::Persister::new();
+        server.state.persister = Some(persister.clone());
+
+        let shards = Shards::new(server, 4);
+        let (ip, topics) = (String::from("publisher"), (0..8).map(|topic| format!("topic-{}", topic)).collect::<Vec<_>>());
+
+        for topic in &topics {
+            let sub = Message::SUB { ip: ip.clone(), topic: topic.clone(), filter: None };
+            let put = Message::PUT {
+                ip: ip.clone(),
+                topic: topic.clone(),
+                sequence_num: rpubsub::first_sequence_num(),
+                payload: String::from("alone"),
+                options: rpubsub::PutOptions::default(),
+                compression: rpubsub::Compression::default(),
+            };
+
+            assert!(matches!(shards.process(&sub).0, Message::REP { result: Ok(ReplyOption::NoOk) }));
+            assert!(matches!(shards.process(&put).0, Message::REP { result: Ok(ReplyOption::PUTOK(_)) }));
+        }
+
+        let updates = topics.iter()
+            .map(|topic| rpubsub::TxUpdate { topic: topic.clone(), payload: String::from("together"), options: rpubsub::PutOptions::default(),
+                                             compression: rpubsub::Compression::default() })
+            .collect();
+        let txput = Message::TXPUT { ip: ip.clone(), sequence_num: rpubsub::first_sequence_num(), updates };
+
+        assert!(matches!(shards.process(&txput).0, Message::REP { result: Ok(ReplyOption::TXOK(_)) }));
+
+        for (index, shard) in shards.shards.iter().enumerate() {
+            assert!(shard.lock().unwrap().state.topics.keys().all(|topic| key_index(topic, 4) == index));
+        }
+
+        let (generation, content) = shards.snapshot(&persister);
+        let state: crate::State = serde_json::from_slice(&rpubsub::open_state(None, content).unwrap()).unwrap();
+
+        assert_eq!(generation, persister.requested());
+        assert_eq!(state.topics.len(), topics.len());
+        assert!(state.topics.values().all(|topic_info| topic_info.update_queue.len() == 2));
+        assert!(state.transactions.contains_key(&ip));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,347 @@
+// Deterministic simulation of the server and its clients on a MemoryNetwork. The clients
+// subscribe, put and get at random while the network loses, delays, duplicates and
+// reorders messages and both sides crash, then the updates each client handed to its
+// application are checked:
+//
+// - none is lost: a subscriber gets every update acknowledged on its topics
+// - none is duplicated
+// - they come in the order of the topic
+//
+// Publishers, clients that only put, may also lose their state file and start counting
+// their PUTs over. The PUT they were retrying may then be published twice, as they can't
+// tell whether it already was.
+//
+// A failing seed replays the same run every time.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rpubsub::transport::{Faults, MemoryNetwork, MemoryTransport};
+use rpubsub::{Message, ReplyOption, ServiceError};
+
+use crate::service::{get_state_file_content, serve_request, Server, Shards};
+
+const SERVER: &str = "server";
+// Requests an application makes for one thing before giving up on the run
+const ATTEMPTS: usize = 50;
+
+struct Config {
+    clients:         usize,
+    // Clients that put but don't subscribe
+    publishers:      usize,
+    topics:          usize,
+    steps:           usize,
+    faults:          Faults,
+    // Chances, at each step, that the server or the client taking the step crashes
+    server_crash:    f64,
+    client_crash:    f64,
+    // Chances, at each step, that the publisher taking the step loses its state file
+    publisher_reset: f64,
+}
+
+// A client application, with what it published and what it was handed
+struct SimClient {
+    client:      client::Client,
+    transport:   MemoryTransport,
+    subscriber:  bool,
+    published:   u64,
+    // Put again until it is acknowledged
+    pending_put: Option<(String, String)>,
+    // [topic] = (seq, payload) of the updates handed to the application
+    received:    HashMap<String, Vec<(rpubsub::SequenceNum, String)>>,
+}
+
+struct Simulation {
+    rng:       StdRng,
+    network:   MemoryNetwork,
+    dir:       String,
+    topics:    Vec<String>,
+    clients:   Vec<SimClient>,
+    // [topic] = payloads whose PUT was acknowledged
+    published: HashMap<String, HashSet<String>>,
+    // Payloads a publisher was retrying when it lost its state
+    retried:   HashSet<String>,
+}
+
+fn start_server(network: &MemoryNetwork, dir: &str) {
+    let mut server = Server {
+        state_path: String::new(),
+        state: crate::State::new(),
+    };
+
+    get_state_file_content(&mut server, dir).unwrap();
+
+    // Without a persister thread each change writes the state file right away, which
+    // only holds it all with a single shard
+    let shards = Shards::new(server, 1);
+    let persister = crate::persist::Persister::new();
+
+    network.serve(SERVER, move |transport| serve_request(transport, &shards, &persister));
+}
+
+// Starts the client from its state file, as the client application does
+fn start_client(network: &MemoryNetwork, ip: &str) -> (client::Client, MemoryTransport) {
+    let mut client = client::Client::new(ip);
+    let with_state = client::get_state_file_content(&mut client).unwrap();
+
+    let transport = network.connect(ip, SERVER, client::TIMEOUT_MS as u64);
+
+    if with_state {
+        let message = Message::UP { ip: String::from(ip), sequence_nums: client.state.sequence_numbers.clone() };
+        client::send_message_with_retries(&transport, &message);
+    }
+
+    (client, transport)
+}
+
+impl Simulation {
+    fn new(name: &str, seed: u64, config: &Config) -> Self {
+        let network = MemoryNetwork::new(seed, config.faults.clone());
+        let dir = std::env::temp_dir().join(format!("rpubsub-sim-{}-{}-{}/", name, seed, std::process::id())).to_string_lossy().into_owned();
+
+        let _ = fs::remove_dir_all(&dir);
+        start_server(&network, &dir);
+
+        let clients = (0..config.clients + config.publishers)
+            .map(|i| {
+                let ip = format!("sim-{}-{}-{}-{}", name, seed, std::process::id(), i);
+                let _ = fs::remove_dir_all(format!("./data/clients_data/{}/", ip));
+
+                let (client, transport) = start_client(&network, &ip);
+                SimClient { client, transport, subscriber: i < config.clients, published: 0, pending_put: None, received: HashMap::new() }
+            })
+            .collect();
+
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            network,
+            dir,
+            topics: (0..config.topics).map(|i| format!("topic-{}", i)).collect(),
+            clients,
+            published: HashMap::new(),
+            retried: HashSet::new(),
+        }
+    }
+
+    fn operation(&mut self, index: usize, line: String) -> (Message, Message) {
+        let sim_client = &mut self.clients[index];
+
+        let request = client::process_operation(&mut sim_client.client, &line).unwrap();
+        let reply = client::send_request(&mut sim_client.client, &sim_client.transport, &request).unwrap();
+
+        (request, reply)
+    }
+
+    fn subscribers(&self) -> Vec<usize> {
+        (0..self.clients.len()).filter(|index| self.clients[*index].subscriber).collect()
+    }
+
+    fn subscribe_all(&mut self) {
+        for index in self.subscribers() {
+            for topic in self.topics.clone() {
+                let mut attempts = 0;
+
+                while !self.clients[index].client.state.sequence_numbers.contains_key(&topic) {
+                    attempts += 1;
+                    assert!(attempts <= ATTEMPTS, "{} can't subscribe to {}", self.clients[index].client.ip, topic);
+
+                    self.operation(index, format!("SUB {}", topic));
+                }
+            }
+        }
+    }
+
+    // Puts the pending update again, or a new one
+    fn put(&mut self, index: usize) {
+        if self.clients[index].pending_put.is_none() {
+            let topic = self.topics[self.rng.gen_range(0..self.topics.len())].clone();
+            let sim_client = &mut self.clients[index];
+
+            sim_client.published += 1;
+            sim_client.pending_put = Some((topic, format!("{}/{}", sim_client.client.ip, sim_client.published)));
+        }
+
+        let (topic, payload) = self.clients[index].pending_put.clone().unwrap();
+
+        let published = match self.operation(index, format!("PUT {} {}", topic, payload)) {
+            (_, Message::REP { result: Ok(ReplyOption::PUTOK(_)) }) => true,
+            // Only a PUT sent again gets the sequence number it was sent with
+            (Message::PUT { sequence_num, .. }, Message::REP { result: Err(ServiceError::ALREAPUT { last }) }) => last == sequence_num,
+            _ => false,
+        };
+
+        if published {
+            self.clients[index].pending_put = None;
+            self.published.entry(topic).or_default().insert(payload);
+        }
+    }
+
+    // Returns whether the client was handed an update
+    fn get(&mut self, index: usize, topic: &String) -> bool {
+        let sequence_num = self.clients[index].client.state.sequence_numbers[topic];
+
+        let (_, reply) = self.operation(index, format!("GET {}", topic));
+
+        let sim_client = &mut self.clients[index];
+
+        if sim_client.client.state.sequence_numbers[topic] == sequence_num {
+            return false;
+        }
+
+        match reply {
+            Message::REP { result: Ok(ReplyOption::TUP((Some(delivery), _))) } => {
+                sim_client.received.entry(topic.clone()).or_default().push((delivery.seq, delivery.payload));
+                true
+            },
+            reply => panic!("client moved on without an update: {}", reply.to_string()),
+        }
+    }
+
+    fn crash_server(&mut self) {
+        self.network.stop(SERVER);
+        start_server(&self.network, &self.dir);
+    }
+
+    // Whatever the client didn't save is lost, the application only keeps what it was handed
+    fn crash_client(&mut self, index: usize) {
+        let ip = self.clients[index].client.ip.clone();
+        let (client, transport) = start_client(&self.network, &ip);
+
+        self.clients[index].client = client;
+        self.clients[index].transport = transport;
+    }
+
+    // The publisher comes back without its state file, so its PUT counters start over
+    fn reset_publisher(&mut self, index: usize) {
+        if let Some((_, payload)) = &self.clients[index].pending_put {
+            self.retried.insert(payload.clone());
+        }
+
+        let _ = fs::remove_dir_all(format!("./data/clients_data/{}/", self.clients[index].client.ip));
+        self.crash_client(index);
+    }
+
+    fn run(&mut self, config: &Config) {
+        self.subscribe_all();
+
+        for _ in 0..config.steps {
+            let index = self.rng.gen_range(0..self.clients.len());
+
+            if self.rng.gen_bool(config.server_crash) {
+                self.crash_server();
+            }
+
+            if self.rng.gen_bool(config.client_crash) {
+                self.crash_client(index);
+            }
+
+            if !self.clients[index].subscriber && self.rng.gen_bool(config.publisher_reset) {
+                self.reset_publisher(index);
+            }
+
+            if !self.clients[index].subscriber || self.rng.gen_bool(0.5) {
+                self.put(index);
+            } else {
+                let topic = self.topics[self.rng.gen_range(0..self.topics.len())].clone();
+                self.get(index, &topic);
+            }
+        }
+
+        // The network heals and the clients catch up
+        self.network.set_faults(Faults::default());
+
+        for index in 0..self.clients.len() {
+            for _ in 0..ATTEMPTS {
+                if self.clients[index].pending_put.is_some() {
+                    self.put(index);
+                }
+            }
+
+            assert!(self.clients[index].pending_put.is_none(), "{} can't put {:?}", self.clients[index].client.ip, self.clients[index].pending_put);
+        }
+
+        let updates = self.published.values().map(|payloads| payloads.len()).sum::<usize>();
+
+        for index in self.subscribers() {
+            for topic in self.topics.clone() {
+                for _ in 0..updates + 1 {
+                    if !self.get(index, &topic) {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn check(&self) {
+        for sim_client in self.clients.iter().filter(|sim_client| sim_client.subscriber) {
+            for topic in &self.topics {
+                let received = sim_client.received.get(topic).cloned().unwrap_or_default();
+                let payloads: Vec<&String> = received.iter().map(|(_, payload)| payload).collect();
+                let unique: HashSet<&String> = payloads.iter().copied().collect();
+                let retried = payloads.iter().filter(|payload| self.retried.contains(**payload)).count()
+                    - unique.iter().filter(|payload| self.retried.contains(**payload)).count();
+                let published = self.published.get(topic).cloned().unwrap_or_default();
+
+                let ip = &sim_client.client.ip;
+
+                assert_eq!(unique.len(), payloads.len() - retried, "{} got duplicates on {}: {:?}", ip, topic, payloads);
+
+                for payload in &published {
+                    assert!(unique.contains(payload), "{} lost {} on {}", ip, payload, topic);
+                }
+
+                assert!(received.windows(2).all(|pair| pair[0].0 < pair[1].0), "{} got {} out of order: {:?}", ip, topic, received);
+            }
+        }
+    }
+
+    fn cleanup(&self) {
+        let _ = fs::remove_dir_all(&self.dir);
+
+        for sim_client in &self.clients {
+            let _ = fs::remove_dir_all(format!("./data/clients_data/{}/", sim_client.client.ip));
+        }
+    }
+}
+
+fn simulate(name: &str, seeds: std::ops::Range<u64>, config: Config) {
+    for seed in seeds {
+        let mut simulation = Simulation::new(name, seed, &config);
+
+        simulation.run(&config);
+        simulation.cleanup();
+        simulation.check();
+    }
+}
+
+fn lossy() -> Faults {
+    Faults { drop: 0.1, duplicate: 0.1, reorder: 0.1, min_delay_ms: 1, max_delay_ms: 50 }
+}
+
+#[test]
+fn reliable_network() {
+    simulate("reliable", 0..3, Config { clients: 3, publishers: 0, topics: 2, steps: 200, faults: Faults::default(), server_crash: 0.0, client_crash: 0.0, publisher_reset: 0.0 });
+}
+
+#[test]
+fn lossy_network() {
+    simulate("lossy", 0..5, Config { clients: 4, publishers: 0, topics: 2, steps: 200, faults: lossy(), server_crash: 0.0, client_crash: 0.0, publisher_reset: 0.0 });
+}
+
+#[test]
+fn crashes() {
+    simulate("crashes", 0..5, Config { clients: 3, publishers: 0, topics: 2, steps: 200, faults: Faults::default(), server_crash: 0.02, client_crash: 0.02, publisher_reset: 0.0 });
+}
+
+#[test]
+fn lossy_network_with_crashes() {
+    simulate("chaos", 0..5, Config { clients: 4, publishers: 0, topics: 3, steps: 200, faults: lossy(), server_crash: 0.02, client_crash: 0.02, publisher_reset: 0.0 });
+}
+
+#[test]
+fn publishers_losing_their_state() {
+    simulate("reset", 0..5, Config { clients: 2, publishers: 2, topics: 2, steps: 200, faults: lossy(), server_crash: 0.02, client_crash: 0.0, publisher_reset: 0.05 });
+}
@@ -0,0 +1,635 @@
+// Tests of single topic features, played directly on a State
+
+use std::fs;
+
+use super::*;
+
+// A state file, and the blobs next to it, in a directory of the temp directory removed
+// when the test ends
+struct TestState {
+    state: State,
+    dir:   std::path::PathBuf,
+    path:  String,
+}
+
+impl TestState {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("rpubsub-tests-{}-{}", name, std::process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("state.json").to_string_lossy().into_owned();
+
+        Self { state: State::new(), dir, path }
+    }
+}
+
+impl Drop for TestState {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// A rejected CONFIG doesn't create the topic nor write the state file
+#[test]
+fn rejected_config_leaves_no_topic() {
+    let mut test = TestState::new("rejected-config");
+    let topic = String::from("orders");
+
+    let missing = rpubsub::TopicOption::DeadLetter(Some(String::from("missing")));
+    assert!(matches!(configure_topic(&mut test.state, &topic, &missing, &test.path), Err(rpubsub::ServiceError::NOTOPIC)));
+
+    let itself = rpubsub::TopicOption::DeadLetter(Some(topic.clone()));
+    assert!(matches!(configure_topic(&mut test.state, &topic, &itself, &test.path), Err(rpubsub::ServiceError::BADCONF)));
+
+    let rate_limit = rpubsub::TopicOption::RateLimit(Some(rpubsub::RateLimit { rate: 0.0, burst: 1.0 }));
+    assert!(matches!(configure_topic(&mut test.state, &topic, &rate_limit, &test.path), Err(rpubsub::ServiceError::BADCONF)));
+
+    assert!(!test.state.topics.contains_key(&topic));
+    assert!(fs::metadata(&test.path).is_err());
+
+    configure_topic(&mut test.state, &topic, &rpubsub::TopicOption::Compaction(true), &test.path).unwrap();
+    assert!(test.state.topics.get(&topic).unwrap().compacted);
+}
+
+// Payloads just over the largest size that may be decompressed are stored as they are,
+// and subscribers get them back unchanged
+#[test]
+fn payloads_over_the_decompression_limit_round_trip() {
+    let mut test = TestState::new("large-payload");
+    let topic = String::from("files");
+    let subscriber = String::from("c0");
+
+    add_topic(&mut test.state, &topic);
+    add_subscription(&mut test.state, &topic, &subscriber, &None, &test.path).unwrap();
+
+    // Compresses well, so it would be stored compressed if it were allowed to
+    let payload = "a".repeat(rpubsub::MAX_DECOMPRESSED_BYTES + 1);
+    publish_update(&mut test.state, &topic, &String::from("c1"), 1, &payload, &rpubsub::PutOptions::default(), &test.path).unwrap();
+
+    let (delivery, _) = get_next_subscriber_update(&mut test.state, &topic, &subscriber, 0, &test.path).unwrap();
+    assert!(delivery.unwrap().payload == payload);
+
+    // Smaller payloads are still kept compressed
+    let payload = "b".repeat(rpubsub::COMPRESSION_MIN_BYTES * 4);
+    publish_update(&mut test.state, &topic, &String::from("c1"), 2, &payload, &rpubsub::PutOptions::default(), &test.path).unwrap();
+
+    let update = test.state.topics.get(&topic).unwrap().update_queue.back().unwrap();
+    assert_eq!(update.compression, rpubsub::Compression::LZ4);
+
+    let (delivery, _) = get_next_subscriber_update(&mut test.state, &topic, &subscriber, 1, &test.path).unwrap();
+    assert!(delivery.unwrap().payload == payload);
+}
+
+// A stored payload that can't be decompressed is an error, never the stored bytes
+#[test]
+fn unreadable_payloads_are_reported() {
+    let mut test = TestState::new("bad-payload");
+    let topic = String::from("files");
+    let subscriber = String::from("c0");
+
+    add_topic(&mut test.state, &topic);
+    add_subscription(&mut test.state, &topic, &subscriber, &None, &test.path).unwrap();
+
+    publish_update(&mut test.state, &topic, &String::from("c1"), 1, &"a".repeat(1024), &rpubsub::PutOptions::default(), &test.path).unwrap();
+
+    let update = test.state.topics.get_mut(&topic).unwrap().update_queue.back_mut().unwrap();
+    update.content = rpubsub::encode_chunk(&rpubsub::compress_bytes(&vec![0; rpubsub::MAX_DECOMPRESSED_BYTES + 1]));
+
+    assert!(matches!(get_next_subscriber_update(&mut test.state, &topic, &subscriber, 0, &test.path), Err(rpubsub::ServiceError::BADPAYLOAD)));
+}
+
+// A blob update moved to a dead-letter topic can be fetched from there, and its blob is
+// kept as long as it is in that topic
+#[test]
+fn dead_lettered_blobs_can_be_fetched() {
+    let mut test = TestState::new("dead-letter-blob");
+    let topic = String::from("uploads");
+    let dead_letter_topic = String::from("uploads-dlq");
+    let publisher = String::from("c1");
+    let blob_id = String::from("report");
+
+    add_topic(&mut test.state, &topic);
+    add_topic(&mut test.state, &dead_letter_topic);
+    configure_topic(&mut test.state, &topic, &rpubsub::TopicOption::DeadLetter(Some(dead_letter_topic.clone())), &test.path).unwrap();
+    add_subscription(&mut test.state, &topic, &String::from("c0"), &None, &test.path).unwrap();
+    add_subscription(&mut test.state, &dead_letter_topic, &String::from("c2"), &None, &test.path).unwrap();
+
+    blob::add_chunk(&mut test.state, &publisher, &blob_id, 0, &rpubsub::encode_chunk(b"quarterly"), &test.path).unwrap();
+
+    // Expires right away, c0 skips it and it goes to the dead-letter topic
+    let options = rpubsub::PutOptions { ttl_ms: Some(0), ..Default::default() };
+    publish_blob(&mut test.state, &topic, &publisher, &blob_id, "report.pdf", &options, &test.path).unwrap();
+
+    let (delivery, _) = get_next_subscriber_update(&mut test.state, &topic, &String::from("c0"), 0, &test.path).unwrap();
+    assert!(delivery.is_none());
+    assert!(test.state.topics.get(&topic).unwrap().update_queue.is_empty());
+
+    remove_unused_blobs(&mut test.state, &test.path);
+
+    let released = serde_json::to_value(test.state.blobs.get(&blob_id).unwrap()).unwrap();
+    assert!(released["released_at"].is_null());
+
+    let chunk = fetch_blob_chunk(&test.state, &dead_letter_topic, &String::from("c2"), &blob_id, 0, &test.path).unwrap();
+    assert_eq!(rpubsub::decode_chunk(&chunk.data), Some(b"quarterly".to_vec()));
+
+    // Topics that don't hold the update still can't reach it
+    add_topic(&mut test.state, &String::from("other"));
+    add_subscription(&mut test.state, &String::from("other"), &String::from("c2"), &None, &test.path).unwrap();
+    assert!(matches!(fetch_blob_chunk(&test.state, &String::from("other"), &String::from("c2"), &blob_id, 0, &test.path), Err(rpubsub::ServiceError::NOBLOB)));
+}
+
+// A QPUT sent again after its reply was lost is only enqueued once
+#[test]
+fn retried_qputs_are_enqueued_once() {
+    let mut test = TestState::new("retried-qput");
+    let queue = String::from("jobs");
+
+    assert_eq!(queue::add_message(&mut test.state, &queue, "c0", 5, "resize", &test.path).unwrap(), 0);
+    assert!(matches!(queue::add_message(&mut test.state, &queue, "c0", 5, "resize", &test.path), Err(rpubsub::ServiceError::ALREAPUT { last: 5 })));
+    assert!(matches!(queue::add_message(&mut test.state, &queue, "c0", 4, "crop", &test.path), Err(rpubsub::ServiceError::ALREAPUT { last: 5 })));
+
+    // Publishers are counted apart
+    assert_eq!(queue::add_message(&mut test.state, &queue, "c1", 5, "rotate", &test.path).unwrap(), 1);
+    assert_eq!(queue::add_message(&mut test.state, &queue, "c0", 6, "crop", &test.path).unwrap(), 2);
+
+    let mut payloads = Vec::new();
+
+    while let (Some(delivery), _) = queue::lease_next_message(&mut test.state, &queue, "w0", &test.path).unwrap() {
+        payloads.push(delivery.payload);
+    }
+
+    assert_eq!(payloads, vec!["resize", "rotate", "crop"]);
+}
+
+// Every update goes to one member of a group, while regular subscribers still get them
+// all, and an update that isn't acknowledged in time goes to the next member that asks
+#[test]
+fn group_members_share_updates() {
+    let mut test = TestState::new("group-delivery");
+    let topic = String::from("jobs");
+    let group = String::from("workers");
+    let (w0, w1) = (String::from("w0"), String::from("w1"));
+
+    add_topic(&mut test.state, &topic);
+    add_subscription(&mut test.state, &topic, &String::from("c0"), &None, &test.path).unwrap();
+    add_group_member(&mut test.state, &topic, &group, &w0, &test.path).unwrap();
+    add_group_member(&mut test.state, &topic, &group, &w1, &test.path).unwrap();
+    assert!(matches!(add_group_member(&mut test.state, &topic, &group, &w1, &test.path), Err(rpubsub::ServiceError::ALREASUB)));
+
+    for (seq, payload) in ["resize", "crop", "rotate"].iter().enumerate() {
+        publish_update(&mut test.state, &topic, &String::from("c1"), seq as u128 + 1, payload, &rpubsub::PutOptions::default(), &test.path).unwrap();
+    }
+
+    let (first, first_id) = get_next_group_update(&mut test.state, &topic, &group, &w0, None, &test.path).unwrap();
+    let (second, second_id) = get_next_group_update(&mut test.state, &topic, &group, &w1, None, &test.path).unwrap();
+    assert_eq!(first.unwrap().payload, "resize");
+    assert_eq!(second.unwrap().payload, "crop");
+
+    // Asking again without acknowledging gets the same update back
+    let (again, again_id) = get_next_group_update(&mut test.state, &topic, &group, &w0, None, &test.path).unwrap();
+    assert_eq!(again.unwrap().payload, "resize");
+    assert_eq!(again_id, first_id);
+
+    // w0 goes quiet past its deadline, so w1 gets its update before the newer one
+    let group_info = test.state.topics.get_mut(&topic).unwrap().groups.get_mut(&group).unwrap();
+    group_info.deliveries.iter_mut().filter(|delivery| delivery.member.as_ref() == Some(&w0)).for_each(|delivery| delivery.deadline = 0);
+
+    let (redelivered, redelivered_id) = get_next_group_update(&mut test.state, &topic, &group, &w1, Some(second_id), &test.path).unwrap();
+    assert_eq!(redelivered.unwrap().payload, "resize");
+
+    let (third, third_id) = get_next_group_update(&mut test.state, &topic, &group, &w0, Some(first_id), &test.path).unwrap();
+    assert_eq!(third.unwrap().payload, "rotate");
+
+    get_next_group_update(&mut test.state, &topic, &group, &w1, Some(redelivered_id), &test.path).unwrap();
+    let (none, _) = get_next_group_update(&mut test.state, &topic, &group, &w0, Some(third_id), &test.path).unwrap();
+    assert!(none.is_none());
+
+    // The regular subscriber still has all three waiting
+    assert_eq!(test.state.topics.get(&topic).unwrap().update_queue.len(), 3);
+
+    let mut payloads = Vec::new();
+    let mut sequence_num = 0;
+
+    while let (Some(delivery), _) = get_next_subscriber_update(&mut test.state, &topic, &String::from("c0"), sequence_num, &test.path).unwrap() {
+        payloads.push(delivery.payload);
+        sequence_num += 1;
+    }
+
+    assert_eq!(payloads, vec!["resize", "crop", "rotate"]);
+    assert!(test.state.topics.get(&topic).unwrap().update_queue.is_empty());
+}
+
+// A scheduled update stays out of the topic, survives a restart, and is delivered once
+// it is released, with the seq it gets then
+#[test]
+fn scheduled_updates_are_released_when_due() {
+    let mut test = TestState::new("scheduled");
+    let topic = String::from("reminders");
+    let subscriber = String::from("c0");
+    let publisher = String::from("c1");
+
+    add_topic(&mut test.state, &topic);
+    add_subscription(&mut test.state, &topic, &subscriber, &None, &test.path).unwrap();
+
+    let delayed = rpubsub::PutOptions { delay_ms: Some(60000), ..Default::default() };
+    let receipt = publish_update(&mut test.state, &topic, &publisher, 1, "later", &delayed, &test.path).unwrap();
+    assert!(receipt.seq.is_none());
+
+    // A time already past is no delay at all
+    let past = rpubsub::PutOptions { deliver_at: Some(1), ..Default::default() };
+    let receipt = publish_update(&mut test.state, &topic, &publisher, 2, "now", &past, &test.path).unwrap();
+    assert_eq!(receipt.seq, Some(0));
+
+    release_scheduled_updates(&mut test.state, &test.path);
+
+    let (delivery, _) = get_next_subscriber_update(&mut test.state, &topic, &subscriber, 0, &test.path).unwrap();
+    assert_eq!(delivery.unwrap().payload, "now");
+    let (delivery, _) = get_next_subscriber_update(&mut test.state, &topic, &subscriber, 1, &test.path).unwrap();
+    assert!(delivery.is_none());
+
+    let mut restored: State = serde_json::from_slice(&fs::read(&test.path).unwrap()).unwrap();
+    restore_state(&mut restored);
+    test.state = restored;
+
+    let topic_info = test.state.topics.get_mut(&topic).unwrap();
+    assert_eq!(topic_info.scheduled_updates.len(), 1);
+    topic_info.scheduled_updates[0].deliver_at = current_time_ms();
+
+    release_scheduled_updates(&mut test.state, &test.path);
+    assert!(test.state.topics.get(&topic).unwrap().scheduled_updates.is_empty());
+
+    let (delivery, _) = get_next_subscriber_update(&mut test.state, &topic, &subscriber, 1, &test.path).unwrap();
+    let delivery = delivery.unwrap();
+    assert_eq!(delivery.payload, "later");
+    assert_eq!(delivery.seq, 1);
+}
+
+// Expired updates are skipped by GET and dropped by the cleanup, and the subscribers'
+// sequence numbers go on as if they were never published
+#[test]
+fn expired_updates_are_never_delivered() {
+    let mut test = TestState::new("ttl");
+    let topic = String::from("alerts");
+    let (c0, c2) = (String::from("c0"), String::from("c2"));
+    let publisher = String::from("c1");
+
+    add_topic(&mut test.state, &topic);
+    add_subscription(&mut test.state, &topic, &c0, &None, &test.path).unwrap();
+    add_subscription(&mut test.state, &topic, &c2, &None, &test.path).unwrap();
+
+    let expired = rpubsub::PutOptions { ttl_ms: Some(0), ..Default::default() };
+    let fresh = rpubsub::PutOptions { ttl_ms: Some(60000), ..Default::default() };
+
+    publish_update(&mut test.state, &topic, &publisher, 1, "first", &rpubsub::PutOptions::default(), &test.path).unwrap();
+    publish_update(&mut test.state, &topic, &publisher, 2, "stale", &expired, &test.path).unwrap();
+    publish_update(&mut test.state, &topic, &publisher, 3, "fresh", &fresh, &test.path).unwrap();
+
+    // c0 is on the first update, the expired one behind it is dropped for c2's sake
+    let (delivery, _) = get_next_subscriber_update(&mut test.state, &topic, &c0, 0, &test.path).unwrap();
+    assert_eq!(delivery.unwrap().payload, "first");
+
+    remove_expired_updates(&mut test.state, &test.path);
+
+    let payloads: Vec<_> = test.state.topics.get(&topic).unwrap().update_queue.iter().map(|update| update.payload().unwrap()).collect();
+    assert_eq!(payloads, vec!["first", "fresh"]);
+
+    let (delivery, _) = get_next_subscriber_update(&mut test.state, &topic, &c0, 1, &test.path).unwrap();
+    assert_eq!(delivery.unwrap().payload, "fresh");
+
+    // c2 skips an expired update itself when the cleanup didn't run
+    publish_update(&mut test.state, &topic, &publisher, 4, "stale again", &expired, &test.path).unwrap();
+    publish_update(&mut test.state, &topic, &publisher, 5, "last", &rpubsub::PutOptions::default(), &test.path).unwrap();
+
+    let mut payloads = Vec::new();
+    let mut sequence_num = 0;
+
+    while let (Some(delivery), _) = get_next_subscriber_update(&mut test.state, &topic, &c2, sequence_num, &test.path).unwrap() {
+        payloads.push(delivery.payload);
+        sequence_num += 1;
+    }
+
+    assert_eq!(payloads, vec!["first", "fresh", "last"]);
+
+    let (delivery, _) = get_next_subscriber_update(&mut test.state, &topic, &c0, 2, &test.path).unwrap();
+    assert_eq!(delivery.unwrap().payload, "last");
+}
+
+// Headers are stored with the update, saved, and handed back with the payload
+#[test]
+fn headers_are_delivered_with_the_payload() {
+    let mut test = TestState::new("headers");
+    let topic = String::from("orders");
+    let subscriber = String::from("c0");
+
+    add_topic(&mut test.state, &topic);
+    add_subscription(&mut test.state, &topic, &subscriber, &None, &test.path).unwrap();
+
+    let headers = rpubsub::Headers::from([
+        (String::from("content-type"), String::from("application/json")),
+        (String::from("correlation-id"), String::from("42")),
+    ]);
+    let options = rpubsub::PutOptions { headers: headers.clone(), ..Default::default() };
+
+    publish_update(&mut test.state, &topic, &String::from("c1"), 1, "{\"id\": 42}", &options, &test.path).unwrap();
+    publish_update(&mut test.state, &topic, &String::from("c1"), 2, "plain", &rpubsub::PutOptions::default(), &test.path).unwrap();
+
+    let mut restored: State = serde_json::from_slice(&fs::read(&test.path).unwrap()).unwrap();
+    restore_state(&mut restored);
+    test.state = restored;
+
+    let (delivery, _) = get_next_subscriber_update(&mut test.state, &topic, &subscriber, 0, &test.path).unwrap();
+    let delivery = delivery.unwrap();
+    assert_eq!(delivery.payload, "{\"id\": 42}");
+    assert_eq!(delivery.headers, headers);
+
+    let (delivery, _) = get_next_subscriber_update(&mut test.state, &topic, &subscriber, 1, &test.path).unwrap();
+    assert!(delivery.unwrap().headers.is_empty());
+}
+
+// Every topic numbers its updates on its own, and a delivery carries the seq and
+// timestamp the publisher got back. Numbering goes on after a restart, even once the
+// queue was emptied.
+#[test]
+fn updates_keep_the_seq_and_timestamp_of_their_receipt() {
+    let mut test = TestState::new("seqs");
+    let (orders, payments) = (String::from("orders"), String::from("payments"));
+    let subscriber = String::from("c0");
+    let publisher = String::from("c1");
+
+    add_topic(&mut test.state, &orders);
+    add_topic(&mut test.state, &payments);
+    add_subscription(&mut test.state, &orders, &subscriber, &None, &test.path).unwrap();
+
+    let before = current_time_ms();
+    let first = publish_update(&mut test.state, &orders, &publisher, 1, "a", &rpubsub::PutOptions::default(), &test.path).unwrap();
+    let other = publish_update(&mut test.state, &payments, &publisher, 2, "b", &rpubsub::PutOptions::default(), &test.path).unwrap();
+    let second = publish_update(&mut test.state, &orders, &publisher, 3, "c", &rpubsub::PutOptions::default(), &test.path).unwrap();
+
+    assert_eq!((first.seq, other.seq, second.seq), (Some(0), Some(0), Some(1)));
+    assert!(first.timestamp >= before && second.timestamp >= first.timestamp && second.timestamp <= current_time_ms());
+
+    for (sequence_num, receipt) in [first, second].iter().enumerate() {
+        let (delivery, _) = get_next_subscriber_update(&mut test.state, &orders, &subscriber, sequence_num as u128, &test.path).unwrap();
+        let delivery = delivery.unwrap();
+        assert_eq!(Some(delivery.seq), receipt.seq);
+        assert_eq!(delivery.timestamp, receipt.timestamp);
+    }
+
+    get_next_subscriber_update(&mut test.state, &orders, &subscriber, 2, &test.path).unwrap();
+    assert!(test.state.topics.get(&orders).unwrap().update_queue.is_empty());
+
+    let mut restored: State = serde_json::from_slice(&fs::read(&test.path).unwrap()).unwrap();
+    restore_state(&mut restored);
+    test.state = restored;
+
+    let third = publish_update(&mut test.state, &orders, &publisher, 4, "d", &rpubsub::PutOptions::default(), &test.path).unwrap();
+    assert_eq!(third.seq, Some(2));
+}
+
+// Compaction keeps only the latest update of a key, except one a subscriber is on, and
+// subscribers behind get the latest value instead
+#[test]
+fn compaction_keeps_the_latest_value_per_key() {
+    let mut test = TestState::new("compaction");
+    let topic = String::from("prices");
+    let (c0, c2) = (String::from("c0"), String::from("c2"));
+    let publisher = String::from("c1");
+
+    add_topic(&mut test.state, &topic);
+    configure_topic(&mut test.state, &topic, &rpubsub::TopicOption::Compaction(true), &test.path).unwrap();
+    add_subscription(&mut test.state, &topic, &c0, &None, &test.path).unwrap();
+    add_subscription(&mut test.state, &topic, &c2, &None, &test.path).unwrap();
+
+    let keyed = |key: &str| rpubsub::PutOptions { key: Some(String::from(key)), ..Default::default() };
+
+    publish_update(&mut test.state, &topic, &publisher, 1, "news", &rpubsub::PutOptions::default(), &test.path).unwrap();
+    publish_update(&mut test.state, &topic, &publisher, 2, "apple 1", &keyed("apple"), &test.path).unwrap();
+    publish_update(&mut test.state, &topic, &publisher, 3, "pear 1", &keyed("pear"), &test.path).unwrap();
+    publish_update(&mut test.state, &topic, &publisher, 4, "apple 2", &keyed("apple"), &test.path).unwrap();
+
+    let payloads = |state: &State| state.topics.get(&topic).unwrap().update_queue.iter().map(|update| update.payload().unwrap()).collect::<Vec<_>>();
+
+    let (delivery, _) = get_next_subscriber_update(&mut test.state, &topic, &c0, 0, &test.path).unwrap();
+    assert_eq!(delivery.unwrap().payload, "news");
+
+    compact_topics(&mut test.state, &test.path);
+    assert_eq!(payloads(&test.state), vec!["news", "pear 1", "apple 2"]);
+
+    // c0 is on the first pear, it stays until c0 is done with it
+    let (delivery, _) = get_next_subscriber_update(&mut test.state, &topic, &c0, 1, &test.path).unwrap();
+    assert_eq!(delivery.unwrap().payload, "pear 1");
+
+    publish_update(&mut test.state, &topic, &publisher, 5, "pear 2", &keyed("pear"), &test.path).unwrap();
+
+    compact_topics(&mut test.state, &test.path);
+    assert_eq!(payloads(&test.state), vec!["news", "pear 1", "apple 2", "pear 2"]);
+
+    let (delivery, _) = get_next_subscriber_update(&mut test.state, &topic, &c0, 2, &test.path).unwrap();
+    assert_eq!(delivery.unwrap().payload, "apple 2");
+
+    compact_topics(&mut test.state, &test.path);
+    assert_eq!(payloads(&test.state), vec!["news", "apple 2", "pear 2"]);
+
+    let mut received = Vec::new();
+    let mut sequence_num = 0;
+
+    while let (Some(delivery), _) = get_next_subscriber_update(&mut test.state, &topic, &c2, sequence_num, &test.path).unwrap() {
+        received.push(delivery.payload);
+        sequence_num += 1;
+    }
+
+    assert_eq!(received, vec!["news", "apple 2", "pear 2"]);
+
+    // Topics that aren't compacted keep every update
+    let other = String::from("history");
+    add_topic(&mut test.state, &other);
+    publish_update(&mut test.state, &other, &publisher, 6, "apple 1", &keyed("apple"), &test.path).unwrap();
+    publish_update(&mut test.state, &other, &publisher, 7, "apple 2", &keyed("apple"), &test.path).unwrap();
+    compact_topics(&mut test.state, &test.path);
+    assert_eq!(test.state.topics.get(&other).unwrap().update_queue.len(), 2);
+}
+
+// A transaction adds all its updates or, when one of them can't be added, none, and
+// sending it again after it was committed adds nothing more
+#[test]
+fn transactions_publish_all_or_nothing() {
+    let mut test = TestState::new("transaction");
+    let (orders, payments) = (String::from("orders"), String::from("payments"));
+    let publisher = String::from("c1");
+
+    add_topic(&mut test.state, &orders);
+    add_topic(&mut test.state, &payments);
+
+    let tx_update = |topic: &str, payload: &str| rpubsub::TxUpdate {
+        topic: String::from(topic),
+        payload: String::from(payload),
+        options: rpubsub::PutOptions::default(),
+        compression: rpubsub::Compression::default()
+    };
+
+    let queued = |state: &State, topic: &String| state.topics.get(topic).unwrap().update_queue.len();
+
+    let broken = [tx_update("orders", "order 1"), tx_update("refunds", "refund 1")];
+    assert!(matches!(publish_transaction(&mut test.state, &publisher, 1, &broken, &test.path), Err(rpubsub::ServiceError::NOTOPIC)));
+    assert_eq!((queued(&test.state, &orders), queued(&test.state, &payments)), (0, 0));
+
+    let updates = [tx_update("orders", "order 1"), tx_update("payments", "payment 1"), tx_update("orders", "order 2")];
+    let receipts = publish_transaction(&mut test.state, &publisher, 1, &updates, &test.path).unwrap();
+    let seqs: Vec<_> = receipts.iter().map(|receipt| receipt.seq).collect();
+    assert_eq!(seqs, vec![Some(0), Some(0), Some(1)]);
+
+    // The reply was lost and the publisher sends it again
+    assert!(matches!(publish_transaction(&mut test.state, &publisher, 1, &updates, &test.path), Err(rpubsub::ServiceError::ALREAPUT { last: 1 })));
+    assert_eq!((queued(&test.state, &orders), queued(&test.state, &payments)), (2, 1));
+
+    // All of them were saved together
+    let saved: State = serde_json::from_slice(&fs::read(&test.path).unwrap()).unwrap();
+    assert_eq!((queued(&saved, &orders), queued(&saved, &payments)), (2, 1));
+}
+
+// A publisher over its rate is throttled with how long to wait, over its quota it is
+// refused, and neither kind of refusal counts as a PUT it made
+#[test]
+fn limits_throttle_and_refuse_publishers() {
+    let mut test = TestState::new("limits");
+    let topic = String::from("metrics");
+    let (noisy, hoarder, quiet) = (String::from("noisy"), String::from("hoarder"), String::from("quiet"));
+
+    let mut config = limits::LimitsConfig::default();
+    config.clients.insert(noisy.clone(), limits::ClientLimits { rate: Some(rpubsub::RateLimit { rate: 1.0, burst: 2.0 }), quota_bytes: None });
+    config.clients.insert(hoarder.clone(), limits::ClientLimits { rate: None, quota_bytes: Some(10) });
+    test.state.limits = limits::Limits::new(config);
+
+    add_topic(&mut test.state, &topic);
+    add_subscription(&mut test.state, &topic, &String::from("c0"), &None, &test.path).unwrap();
+
+    let options = rpubsub::PutOptions::default();
+
+    publish_update(&mut test.state, &topic, &noisy, 1, "a", &options, &test.path).unwrap();
+    publish_update(&mut test.state, &topic, &noisy, 2, "b", &options, &test.path).unwrap();
+
+    // The bucket held two updates and earns one a second
+    match publish_update(&mut test.state, &topic, &noisy, 3, "c", &options, &test.path) {
+        Err(rpubsub::ServiceError::THROTTLED { retry_after_ms }) => assert!(retry_after_ms > 900 && retry_after_ms <= 1000),
+        other => panic!("expected THROTTLED, got {:?}", other),
+    }
+
+    assert_eq!(test.state.topics.get(&topic).unwrap().publishers.get(&noisy), Some(&2));
+
+    // Clients that aren't listed have no limits
+    for sequence_num in 1..=5 {
+        publish_update(&mut test.state, &topic, &quiet, sequence_num, "1234", &options, &test.path).unwrap();
+    }
+
+    publish_update(&mut test.state, &topic, &hoarder, 1, "12345678", &options, &test.path).unwrap();
+    assert!(matches!(publish_update(&mut test.state, &topic, &hoarder, 2, "901", &options, &test.path), Err(rpubsub::ServiceError::OVERQUOTA)));
+    publish_update(&mut test.state, &topic, &hoarder, 2, "90", &options, &test.path).unwrap();
+
+    // Delivered updates no longer count against the quota
+    let mut sequence_num = 0;
+
+    while let (Some(_), _) = get_next_subscriber_update(&mut test.state, &topic, &String::from("c0"), sequence_num, &test.path).unwrap() {
+        sequence_num += 1;
+    }
+
+    publish_update(&mut test.state, &topic, &hoarder, 3, "901", &options, &test.path).unwrap();
+
+    // A topic rate limit holds for every publisher of the topic
+    let limited = String::from("alerts");
+    add_topic(&mut test.state, &limited);
+    configure_topic(&mut test.state, &limited, &rpubsub::TopicOption::RateLimit(Some(rpubsub::RateLimit { rate: 0.5, burst: 1.0 })), &test.path).unwrap();
+
+    publish_update(&mut test.state, &limited, &quiet, 6, "fire", &options, &test.path).unwrap();
+
+    match publish_update(&mut test.state, &limited, &hoarder, 4, "flood", &options, &test.path) {
+        Err(rpubsub::ServiceError::THROTTLED { retry_after_ms }) => assert!(retry_after_ms > 1900 && retry_after_ms <= 2000),
+        other => panic!("expected THROTTLED, got {:?}", other),
+    }
+}
+
+// PUT replies report backpressure from the high watermark until the queue is back down
+// to the low one, and PUTs past the hard limit are rejected
+#[test]
+fn watermarks_signal_backpressure() {
+    let mut test = TestState::new("watermarks");
+    let topic = String::from("events");
+    let subscriber = String::from("c0");
+    let publisher = String::from("c1");
+
+    add_topic(&mut test.state, &topic);
+    add_subscription(&mut test.state, &topic, &subscriber, &None, &test.path).unwrap();
+
+    let watermarks = rpubsub::Watermarks { high: 4, low: 2, hard: Some(5) };
+    configure_topic(&mut test.state, &topic, &rpubsub::TopicOption::Watermarks(Some(watermarks)), &test.path).unwrap();
+
+    let mut sequence_num = 0;
+    let mut put = |state: &mut State| {
+        sequence_num += 1;
+        publish_update(state, &topic, &publisher, sequence_num, "tick", &rpubsub::PutOptions::default(), &test.path).map(|receipt| receipt.backpressure)
+    };
+
+    let backpressure: Vec<_> = (0..5).map(|_| put(&mut test.state).unwrap()).collect();
+    assert_eq!(backpressure, vec![false, false, false, true, true]);
+
+    assert!(matches!(put(&mut test.state), Err(rpubsub::ServiceError::OVERLOADED)));
+
+    // The subscriber catches up on all but two, the one it is on and one more
+    for ack in 0..4 {
+        get_next_subscriber_update(&mut test.state, &topic, &subscriber, ack, &test.path).unwrap();
+    }
+
+    assert_eq!(test.state.topics.get(&topic).unwrap().update_queue.len(), 2);
+
+    // Between the watermarks nothing changes, under the low one it stops
+    assert!(put(&mut test.state).unwrap());
+
+    get_next_subscriber_update(&mut test.state, &topic, &subscriber, 4, &test.path).unwrap();
+    get_next_subscriber_update(&mut test.state, &topic, &subscriber, 5, &test.path).unwrap();
+
+    assert!(!put(&mut test.state).unwrap());
+}
+
+// Subscribers and groups get the pending updates of the highest priority first, in the
+// order they were published within a priority, and every one of them is freed once done
+#[test]
+fn higher_priorities_are_delivered_first() {
+    let mut test = TestState::new("priorities");
+    let topic = String::from("tasks");
+    let subscriber = String::from("c0");
+    let group = String::from("workers");
+    let member = String::from("w0");
+
+    add_topic(&mut test.state, &topic);
+    add_subscription(&mut test.state, &topic, &subscriber, &None, &test.path).unwrap();
+    add_group_member(&mut test.state, &topic, &group, &member, &test.path).unwrap();
+
+    for (sequence_num, (payload, priority)) in [("low 1", 0), ("low 2", 0), ("high 1", 9), ("mid 1", 5), ("high 2", 9)].iter().enumerate() {
+        let options = rpubsub::PutOptions { priority: *priority, ..Default::default() };
+        publish_update(&mut test.state, &topic, &String::from("c1"), sequence_num as u128 + 1, payload, &options, &test.path).unwrap();
+    }
+
+    let expected = vec!["high 1", "high 2", "mid 1", "low 1", "low 2"];
+
+    let mut received = Vec::new();
+    let mut sequence_num = 0;
+
+    while let (Some(delivery), _) = get_next_subscriber_update(&mut test.state, &topic, &subscriber, sequence_num, &test.path).unwrap() {
+        received.push(delivery.payload);
+        sequence_num += 1;
+    }
+
+    assert_eq!(received, expected);
+
+    let mut received = Vec::new();
+    let mut ack = None;
+
+    while let (Some(delivery), delivery_id) = get_next_group_update(&mut test.state, &topic, &group, &member, ack, &test.path).unwrap() {
+        received.push(delivery.payload);
+        ack = Some(delivery_id);
+    }
+
+    assert_eq!(received, expected);
+    assert!(test.state.topics.get(&topic).unwrap().update_queue.is_empty());
+}
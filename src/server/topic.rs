@@ -1,33 +1,224 @@
-use std::collections::{ HashMap, VecDeque };
+use std::collections::{ HashMap, HashSet, VecDeque };
 use serde::{Deserialize, Serialize};
 use std::fs;
-
-
-#[derive(Serialize, Deserialize, Debug)]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod queue;
+pub mod filter;
+pub mod limits;
+pub mod blob;
+pub mod persist;
+pub mod service;
+
+#[cfg(test)]
+mod properties;
+#[cfg(test)]
+mod tests;
+// Runs the workers' code on an in-memory network
+#[cfg(all(test, not(feature = "async")))]
+mod simulation;
+
+// How long a group member has to acknowledge an update before it is handed to another member
+const GROUP_ACK_TIMEOUT_MS: u128 = 10000;
+// Updates that expire this many times in a group are dead-lettered instead of redelivered
+const GROUP_MAX_DELIVERIES: u32 = 5;
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Update {
     content: String,
-    pending_updates: usize
+    pending_updates: usize,
+    #[serde(default)]
+    expires_at: Option<rpubsub::Timestamp>,
+    #[serde(default)]
+    headers: rpubsub::Headers,
+    #[serde(default)]
+    seq: rpubsub::SequenceNum,
+    #[serde(default)]
+    received_at: rpubsub::Timestamp,
+    #[serde(default)]
+    key: Option<String>,
+    // Client that published the update, empty for the ones the server adds itself
+    #[serde(default)]
+    publisher: String,
+    #[serde(default)]
+    priority: u8,
+    #[serde(default)]
+    blob: Option<rpubsub::BlobInfo>,
+    // How content is stored, large payloads are kept compressed
+    #[serde(default)]
+    compression: rpubsub::Compression,
+    // Bytes of the payload as published, before compression, which is what quotas count
+    #[serde(default)]
+    size: usize,
+    // The content is encrypted end to end with that version of the topic key
+    #[serde(default)]
+    key_version: Option<u32>
+}
+
+impl Update {
+    fn is_expired(&self, now: u128) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    // The payload as published. The stored content is never handed out in its place, it
+    // may be compressed.
+    fn payload(&self) -> Result<String, rpubsub::ServiceError> {
+        rpubsub::decompress_payload(&self.content, self.compression).ok_or(rpubsub::ServiceError::BADPAYLOAD)
+    }
+
+    fn to_delivery(&self) -> Result<rpubsub::Delivery, rpubsub::ServiceError> {
+        Ok(rpubsub::Delivery {
+            payload: self.payload()?,
+            headers: self.headers.clone(),
+            seq: self.seq,
+            timestamp: self.received_at,
+            blob: self.blob.clone(),
+            compression: rpubsub::Compression::NONE,
+            key_version: self.key_version
+        })
+    }
+
+    // Bytes the update counts for in the quotas
+    fn stored_size(&self) -> usize {
+        self.size + blob_size(&self.blob)
+    }
 }
 
+fn blob_size(blob: &Option<rpubsub::BlobInfo>) -> usize {
+    blob.as_ref().map_or(0, |blob| blob.size as usize)
+}
+
+// Updates to republish on a dead-letter topic, with why and after how many deliveries.
+// Those whose payload couldn't be read are only reported.
+type DeadLetters = Vec<(Result<rpubsub::Delivery, rpubsub::ServiceError>, rpubsub::DeadLetterReason, u32)>;
+
 type UpdatesQueue = VecDeque<Update>;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct SubscriptionInfo {
     last_recv_sequence_num: Option<rpubsub::SequenceNum>,
-    topic_update_idx:       Option<usize>
+    // Oldest update the subscriber didn't get yet
+    topic_update_idx:       Option<usize>,
+    // Updates that don't match it are skipped, see filter.rs
+    #[serde(default)]
+    filter:                 Option<String>,
+    // The filter parsed on SUB, or by restore_state when the state is loaded
+    #[serde(skip)]
+    parsed_filter:          Option<filter::Filter>,
+    // seqs of the updates after topic_update_idx it already got, ahead of older ones of a lower priority
+    #[serde(default)]
+    consumed:               Vec<rpubsub::SequenceNum>,
+    // seq of the update handed out by the last GET, acknowledged by the next one
+    #[serde(default)]
+    current:                Option<rpubsub::SequenceNum>
+}
+
+impl SubscriptionInfo {
+    // Position of the update the subscriber is on: the one it was handed out or, if it
+    // wasn't handed any, the oldest one it didn't get
+    fn held_update_pos(&self, queue: &UpdatesQueue) -> Option<usize> {
+        match self.current {
+            Some(seq) => update_pos(queue, seq),
+            None => self.topic_update_idx,
+        }
+    }
+
+    fn wants(&self, update: &Update) -> bool {
+        match &self.parsed_filter {
+            // A payload that can't be read is filtered on its headers, the GET then reports it
+            Some(filter) => filter.matches(&update.payload().unwrap_or_default(), &update.headers),
+            None => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GroupDelivery {
+    delivery_id:      rpubsub::SequenceNum,
+    topic_update_idx: usize,
+    // None when the ack deadline passed and the update waits to be handed to another member
+    member:           Option<String>,
+    deadline:         u128,
+    delivery_count:   u32
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GroupInfo {
+    members:          Vec<String>,
+    // Oldest update that wasn't handed out to any member yet
+    topic_update_idx: Option<usize>,
+    deliveries:       Vec<GroupDelivery>,
+    next_delivery_id: rpubsub::SequenceNum,
+    // seqs of the updates after topic_update_idx already handed out, ahead of older ones of a lower priority
+    #[serde(default)]
+    dispatched:       Vec<rpubsub::SequenceNum>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ScheduledUpdate {
+    deliver_at:  rpubsub::Timestamp,
+    content:     String,
+    #[serde(default)]
+    options:     rpubsub::PutOptions,
+    #[serde(default)]
+    received_at: rpubsub::Timestamp,
+    #[serde(default)]
+    publisher:   String,
+    #[serde(default)]
+    blob:        Option<rpubsub::BlobInfo>
+}
+
+impl ScheduledUpdate {
+    fn stored_size(&self) -> usize {
+        self.content.len() + blob_size(&self.blob)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TopicInfo {
     subscriptions: HashMap<String, SubscriptionInfo>,
-    update_queue: UpdatesQueue
+    #[serde(default)]
+    groups: HashMap<rpubsub::GroupName, GroupInfo>,
+    update_queue: UpdatesQueue,
+    #[serde(default)]
+    dead_letter_topic: Option<rpubsub::Topic>,
+    // Kept apart from the queue until they are due, ordered by deliver_at
+    #[serde(default)]
+    scheduled_updates: Vec<ScheduledUpdate>,
+    // seq of the next update added to the queue
+    #[serde(default)]
+    next_update_seq: rpubsub::SequenceNum,
+    // Whether older updates are dropped once there is a newer one with the same key
+    #[serde(default)]
+    compacted: bool,
+    #[serde(default)]
+    rate_limit: Option<rpubsub::RateLimit>,
+    // Payload bytes the topic may be storing at once, scheduled updates included
+    #[serde(default)]
+    quota_bytes: Option<usize>,
+    #[serde(default)]
+    watermarks: Option<rpubsub::Watermarks>,
+    // Set when the queue goes over the high watermark, cleared when it goes under the low one
+    #[serde(default)]
+    backpressure: bool,
+    // Sequence number of the last PUT of each publisher
+    #[serde(default)]
+    publishers: HashMap<String, rpubsub::SequenceNum>,
+    // Payload bytes stored on the topic by each publisher, scheduled updates included.
+    // restore_state counts them again when the state is loaded.
+    #[serde(skip)]
+    stored_bytes: HashMap<String, usize>
 }
 
 impl TopicInfo {
     pub fn new() -> Self {
         let subs = HashMap::new();
+        let groups = HashMap::new();
         let queue = UpdatesQueue::new();
-        Self { subscriptions: subs, update_queue: queue }
+        Self { subscriptions: subs, groups, update_queue: queue, dead_letter_topic: None, scheduled_updates: Vec::new(), next_update_seq: 0, compacted: false,
+               rate_limit: None, quota_bytes: None, watermarks: None, backpressure: false, publishers: HashMap::new(),
+               stored_bytes: HashMap::new() }
     }
 
     pub fn remove_subscription_info(&mut self, ip: &String) {
@@ -35,24 +226,174 @@ impl TopicInfo {
     }
 }
 
+// Counts bytes stored by the publisher, or takes back those it doesn't store anymore
+fn add_stored_bytes(stored_bytes: &mut HashMap<String, usize>, publisher: &str, size: usize) {
+    *stored_bytes.entry(String::from(publisher)).or_insert(0) += size;
+}
+
+fn remove_stored_bytes(stored_bytes: &mut HashMap<String, usize>, publisher: &String, size: usize) {
+    if let Some(bytes) = stored_bytes.get_mut(publisher) {
+        *bytes = bytes.saturating_sub(size);
+
+        if *bytes == 0 {
+            stored_bytes.remove(publisher);
+        }
+    }
+}
+
 pub type Topics = HashMap<rpubsub::Topic, TopicInfo>;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct State {
-    pub topics: Topics
+    pub topics: Topics,
+    #[serde(default)]
+    pub queues: queue::Queues,
+    // Sequence number of the last transaction committed by each publisher
+    #[serde(default)]
+    pub transactions: HashMap<String, rpubsub::SequenceNum>,
+    #[serde(skip)]
+    pub limits: limits::Limits,
+    #[serde(default)]
+    pub uploads: blob::Uploads,
+    #[serde(default)]
+    pub blobs: blob::Blobs,
+    // The state file is encrypted with it when there is one
+    #[serde(skip)]
+    pub state_key: Option<rpubsub::StateKey>,
+    // Writes the state file in the background when there is one, see persist.rs
+    #[serde(skip)]
+    pub persister: Option<persist::Persister>,
+    // Generation of the state file that holds the last change, when there is a persister
+    #[serde(skip)]
+    pub generation: u64
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self { topics: Topics::new(), queues: queue::Queues::new(), transactions: HashMap::new(), limits: limits::Limits::default(),
+               uploads: blob::Uploads::new(), blobs: blob::Blobs::new(), state_key: None, persister: None, generation: 0 }
+    }
 }
 
 pub fn add_topic(state: &mut State, topic: &rpubsub::Topic) {
     state.topics.insert(topic.clone(), TopicInfo::new());
 }
 
+pub fn save_state(state: &mut State, path: &String) {
+    match &state.persister {
+        Some(persister) => state.generation = persister.request(),
+        None => {
+            if let Err(e) = write_state(&serialize_state(state), path) {
+                println!("{}", e);
+            }
+        },
+    }
+}
+
+// Completes a state read from the state file, which may have been written by an older version
+pub fn restore_state(state: &mut State) {
+    for (topic, topic_info) in state.topics.iter_mut() {
+        for update in topic_info.update_queue.iter_mut() {
+            if update.size == 0 {
+                if let Ok(payload) = update.payload() {
+                    update.size = payload.len();
+                }
+            }
+        }
+
+        // Updates written before they had a seq all have 0, which update_pos can't search.
+        // Nothing refers to them by seq yet, so they are numbered in queue order.
+        let queue = &mut topic_info.update_queue;
+
+        if queue.iter().zip(queue.iter().skip(1)).any(|(update, next)| update.seq >= next.seq) {
+            for (seq, update) in queue.iter_mut().enumerate() {
+                update.seq = seq as rpubsub::SequenceNum;
+            }
+        }
+
+        if let Some(last) = queue.back() {
+            topic_info.next_update_seq = topic_info.next_update_seq.max(last.seq+1);
+        }
+
+        topic_info.stored_bytes.clear();
+
+        for update in topic_info.update_queue.iter() {
+            add_stored_bytes(&mut topic_info.stored_bytes, &update.publisher, update.stored_size());
+        }
+
+        for scheduled in topic_info.scheduled_updates.iter() {
+            add_stored_bytes(&mut topic_info.stored_bytes, &scheduled.publisher, scheduled.stored_size());
+        }
+
+        let blobs = topic_info.update_queue.iter().filter_map(|update| update.blob.as_ref())
+            .chain(topic_info.scheduled_updates.iter().filter_map(|scheduled| scheduled.blob.as_ref()));
+
+        for blob in blobs {
+            state.blobs.entry(blob.id.clone()).or_insert_with(|| blob::StoredBlob::new(topic, blob, false));
+        }
+
+        // Filters were checked on SUB, so one that doesn't parse anymore was written by a
+        // version with another syntax: its subscriber gets everything rather than nothing
+        for (ip, subscription_info) in topic_info.subscriptions.iter_mut() {
+            subscription_info.parsed_filter = match subscription_info.filter.as_ref().map(|filter| filter::Filter::parse(filter)) {
+                Some(Ok(parsed_filter)) => Some(parsed_filter),
+                Some(Err(e)) => {
+                    println!("error: ignoring the filter of {} on topic {}: {}", ip, topic, e);
+                    None
+                },
+                None => None,
+            };
+        }
+    }
+}
+
+pub fn serialize_state(state: &State) -> Vec<u8> {
+    rpubsub::seal_state(state.state_key.as_ref(), serde_json::to_vec(state).unwrap())
+}
+
+// The state is written next to the file and renamed over it, so a crash while saving
+// leaves the previous state instead of a truncated one
+pub fn write_state(content: &[u8], path: &String) -> Result<(), String> {
+    let tmp_path = format!("{}.tmp", path);
+
+    fs::write(&tmp_path, content)
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| format!("error: couldn't write state file {}: {}", path, e))
+}
+
+pub(crate) fn current_time_ms() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+// Each consumer group counts as a single subscriber
 fn topic_subscriber_num(topic_info: &TopicInfo) -> usize {
-    topic_info.subscriptions.len()
+    topic_info.subscriptions.len() + topic_info.groups.len()
 }
 
-fn remove_nonpending_updates(queue: &mut UpdatesQueue) {
-    while !queue.is_empty() && queue.front().as_mut().unwrap().pending_updates == 0 {
-        queue.pop_front();
+// Pops the updates at the front of the queue that nobody is waiting on and
+// shifts every subscriber and group index accordingly
+fn remove_nonpending_updates(topic_info: &mut TopicInfo) {
+    let queue = &mut topic_info.update_queue;
+
+    while !queue.is_empty() && queue.front().unwrap().pending_updates == 0 {
+        let update = queue.pop_front().unwrap();
+        remove_stored_bytes(&mut topic_info.stored_bytes, &update.publisher, update.stored_size());
+
+        for subscription_info in topic_info.subscriptions.values_mut() {
+            if let Some(idx) = subscription_info.topic_update_idx {
+                subscription_info.topic_update_idx = Some(idx-1);
+            }
+        }
+
+        for group_info in topic_info.groups.values_mut() {
+            if let Some(idx) = group_info.topic_update_idx {
+                group_info.topic_update_idx = Some(idx-1);
+            }
+
+            for delivery in &mut group_info.deliveries {
+                delivery.topic_update_idx -= 1;
+            }
+        }
     }
 }
 
@@ -73,9 +414,195 @@ fn associate_subscribers_to_last_update(topic_info: &mut TopicInfo) {
             subscription_info.topic_update_idx = idx;
         }
     }
+
+    for group_info in topic_info.groups.values_mut() {
+        if group_info.topic_update_idx.is_none() {
+            group_info.topic_update_idx = idx;
+        }
+    }
+}
+
+// Updates stay ordered by seq in the queue
+fn update_pos(queue: &UpdatesQueue, seq: rpubsub::SequenceNum) -> Option<usize> {
+    queue.binary_search_by_key(&seq, |update| update.seq).ok()
+}
+
+// Position of the update to hand out next to a subscriber or group whose oldest update
+// left is at idx, and that already got the ones in done: the oldest of the highest priority
+fn next_update_pos(queue: &UpdatesQueue, idx: Option<usize>, done: &[rpubsub::SequenceNum]) -> Option<usize> {
+    let mut next: Option<usize> = None;
+
+    let done: HashSet<&rpubsub::SequenceNum> = done.iter().collect();
+
+    for pos in idx?..queue.len() {
+        let update = queue.get(pos).unwrap();
+
+        if done.contains(&update.seq) {
+            continue;
+        }
+
+        if next.is_none_or(|next| update.priority > queue.get(next).unwrap().priority) {
+            next = Some(pos);
+        }
+    }
+
+    next
+}
+
+// Records that the subscriber or group whose oldest update left is at idx got the
+// update at pos. When that is the oldest one, idx moves past it and past the following
+// ones it already got.
+fn mark_update_done(queue: &UpdatesQueue, idx: &mut Option<usize>, done: &mut Vec<rpubsub::SequenceNum>, pos: usize) {
+    if *idx != Some(pos) {
+        done.push(queue.get(pos).unwrap().seq);
+        return;
+    }
+
+    let mut next = pos+1;
+
+    while next < queue.len() && done.contains(&queue.get(next).unwrap().seq) {
+        let seq = queue.get(next).unwrap().seq;
+        done.retain(|done_seq| *done_seq != seq);
+        next += 1;
+    }
+
+    *idx = if next == queue.len() { None } else { Some(next) };
+}
+
+// Fixes the cursor of a subscriber or group after the update that was at pos, with the
+// given seq, was taken out of the queue
+fn fix_cursor_after_removal(queue: &UpdatesQueue, idx: &mut Option<usize>, done: &mut Vec<rpubsub::SequenceNum>, pos: usize, seq: rpubsub::SequenceNum) {
+    done.retain(|done_seq| *done_seq != seq);
+
+    match *idx {
+        Some(cursor) if cursor > pos => *idx = Some(cursor-1),
+
+        // The oldest update left is now the next one it didn't get
+        Some(cursor) if cursor == pos => {
+            let mut next = pos;
+
+            while next < queue.len() && done.contains(&queue.get(next).unwrap().seq) {
+                let seq = queue.get(next).unwrap().seq;
+                done.retain(|done_seq| *done_seq != seq);
+                next += 1;
+            }
+
+            *idx = if next == queue.len() { None } else { Some(next) };
+        },
+
+        _ => (),
+    }
+}
+
+pub fn configure_topic(state: &mut State, topic: &rpubsub::Topic, option: &rpubsub::TopicOption, path: &String) -> Result<(), rpubsub::ServiceError> {
+    // The option is checked before the topic is created, so that a rejected CONFIG leaves no topic behind
+    match option {
+        rpubsub::TopicOption::DeadLetter(dead_letter_topic) => {
+            if dead_letter_topic.as_ref() == Some(topic) {
+                return Err(rpubsub::ServiceError::BADCONF);
+            }
+
+            if dead_letter_topic.as_ref().is_some_and(|dead_letter_topic| !state.topics.contains_key(dead_letter_topic)) {
+                return Err(rpubsub::ServiceError::NOTOPIC);
+            }
+        },
+
+        rpubsub::TopicOption::RateLimit(rate_limit) => {
+            if rate_limit.is_some_and(|rate_limit| rate_limit.rate <= 0.0 || rate_limit.burst < 1.0) {
+                return Err(rpubsub::ServiceError::BADCONF);
+            }
+        },
+
+        rpubsub::TopicOption::Watermarks(watermarks) => {
+            if watermarks.is_some_and(|watermarks| watermarks.low > watermarks.high || watermarks.hard.is_some_and(|hard| hard < watermarks.high)) {
+                return Err(rpubsub::ServiceError::BADCONF);
+            }
+        },
+
+        rpubsub::TopicOption::Compaction(_) | rpubsub::TopicOption::Quota(_) => {},
+    }
+
+    if !state.topics.contains_key(topic) {
+        add_topic(state, topic);
+    }
+
+    let topic_info = state.topics.get_mut(topic).unwrap();
+
+    match option {
+        rpubsub::TopicOption::DeadLetter(dead_letter_topic) => {
+            topic_info.dead_letter_topic = dead_letter_topic.clone();
+        },
+
+        rpubsub::TopicOption::Compaction(compacted) => {
+            topic_info.compacted = *compacted;
+        },
+
+        rpubsub::TopicOption::RateLimit(rate_limit) => {
+            topic_info.rate_limit = *rate_limit;
+        },
+
+        rpubsub::TopicOption::Quota(quota_bytes) => {
+            topic_info.quota_bytes = *quota_bytes;
+        },
+
+        rpubsub::TopicOption::Watermarks(watermarks) => {
+            topic_info.watermarks = *watermarks;
+            topic_info.backpressure = false;
+        },
+    }
+
+    save_state(state, path);
+
+    Ok(())
+}
+
+// Whether requests on the topic may also change another one, its dead-letter topic
+pub(crate) fn has_dead_letter_topic(state: &State, topic: &rpubsub::Topic) -> bool {
+    state.topics.get(topic).is_some_and(|topic_info| topic_info.dead_letter_topic.is_some())
+}
+
+// Republishes the given updates on the dead-letter topic of the topic they came from.
+// They are dropped if the topic has none.
+fn dead_letter_updates(state: &mut State, topic: &rpubsub::Topic, updates: DeadLetters, path: &String) {
+    let dead_letter_topic = match &state.topics.get(topic).unwrap().dead_letter_topic {
+        Some(dead_letter_topic) => dead_letter_topic.clone(),
+        None => return,
+    };
+
+    for (delivery, reason, delivery_count) in updates {
+        let delivery = match delivery {
+            Ok(delivery) => delivery,
+            Err(e) => {
+                println!("error: couldn't dead-letter an update of topic {} on {}: {:?}", topic, dead_letter_topic, e);
+                continue;
+            },
+        };
+
+        let dead_letter = rpubsub::DeadLetter {
+            topic: topic.clone(),
+            reason,
+            delivery_count,
+            payload: delivery.payload,
+            headers: delivery.headers,
+            seq: delivery.seq,
+            timestamp: delivery.timestamp,
+            key_version: delivery.key_version
+        };
+
+        if let Err(e) = add_update(state, &dead_letter_topic, "", &serde_json::to_string(&dead_letter).unwrap(), &rpubsub::PutOptions::default(),
+                                    &delivery.blob, current_time_ms(), path) {
+            println!("error: couldn't dead-letter update {} of topic {} on {}: {:?}", dead_letter.seq, topic, dead_letter_topic, e);
+        }
+    }
 }
 
-pub fn add_subscription(state: &mut State, topic: &rpubsub::Topic, ip: &String, path: &String) -> Result<(), rpubsub::ServiceError> {
+pub fn add_subscription(state: &mut State, topic: &rpubsub::Topic, ip: &String, filter: &Option<String>, path: &String) -> Result<(), rpubsub::ServiceError> {
+    let parsed_filter = match filter.as_ref().map(|filter| filter::Filter::parse(filter)) {
+        Some(Ok(parsed_filter)) => Some(parsed_filter),
+        Some(Err(_)) => return Err(rpubsub::ServiceError::BADFILTER),
+        None => None,
+    };
+
     if !state.topics.contains_key(topic) {
         add_topic(state, topic);
     }
@@ -89,9 +616,10 @@ pub fn add_subscription(state: &mut State, topic: &rpubsub::Topic, ip: &String,
 
         None => {
             // Receives only updates inserted after his subscription
-            topic_info.subscriptions.insert(ip.clone(), SubscriptionInfo { last_recv_sequence_num: None, topic_update_idx: None });
+            topic_info.subscriptions.insert(ip.clone(), SubscriptionInfo { last_recv_sequence_num: None, topic_update_idx: None, filter: filter.clone(),
+                                                                  parsed_filter, consumed: Vec::new(), current: None });
             
-            fs::write(path, serde_json::to_string(state).unwrap());
+            save_state(state, path);
 
             Ok(())
         }
@@ -110,22 +638,33 @@ pub fn remove_subscription(state: &mut State, topic: &rpubsub::Topic, ip: &Strin
     match subscription {
         Some(subscription_info) => {
             let idx = subscription_info.topic_update_idx;
+            let consumed = subscription_info.consumed.clone();
 
             topic_info.remove_subscription_info(ip);
 
             let update_queue = &mut topic_info.update_queue;
 
-            if idx.is_none() {
-                return Ok(());
-            }
+            let mut evicted = Vec::new();
+
+            if let Some(idx) = idx {
+                for i in idx..update_queue.len() {
+                    let update = update_queue.get_mut(i).unwrap();
 
-            for i in idx.unwrap()..update_queue.len() {
-                update_queue.get_mut(i).unwrap().pending_updates -= 1;
+                    if !consumed.contains(&update.seq) {
+                        update.pending_updates -= 1;
+
+                        if update.pending_updates == 0 {
+                            evicted.push((update.to_delivery(), rpubsub::DeadLetterReason::EVICTED, 0));
+                        }
+                    }
+                }
             }
 
-            remove_nonpending_updates(update_queue);
+            remove_nonpending_updates(topic_info);
+
+            dead_letter_updates(state, topic, evicted, path);
            
-            fs::write(path, serde_json::to_string(state).unwrap());
+            save_state(state, path);
 
             Ok(())
         },
@@ -136,27 +675,450 @@ pub fn remove_subscription(state: &mut State, topic: &rpubsub::Topic, ip: &Strin
 
 
 
-pub fn add_update(state: &mut State, topic: &rpubsub::Topic, content: &String, path: &String) -> Result<(), rpubsub::ServiceError> {
+// Appends the update to the topic queue, returning the seq it was given
+#[allow(clippy::too_many_arguments)]
+pub fn add_update(state: &mut State, topic: &rpubsub::Topic, publisher: &str, content: &str, options: &rpubsub::PutOptions,
+                    blob: &Option<rpubsub::BlobInfo>, received_at: rpubsub::Timestamp, path: &String) -> Result<rpubsub::SequenceNum, rpubsub::ServiceError> {
+    let seq = append_update(state, topic, publisher, content, options, blob, received_at)?;
+
+    save_state(state, path);
+
+    Ok(seq)
+}
+
+fn append_update(state: &mut State, topic: &rpubsub::Topic, publisher: &str, content: &str, options: &rpubsub::PutOptions,
+                    blob: &Option<rpubsub::BlobInfo>, received_at: rpubsub::Timestamp) -> Result<rpubsub::SequenceNum, rpubsub::ServiceError> {
     if !state.topics.contains_key(topic) {
         return Err(rpubsub::ServiceError::NOTOPIC);
     }
 
     let topic_info = state.topics.get_mut(topic).unwrap();
 
-    let sub_num = topic_subscriber_num(&topic_info);
+    let sub_num = topic_subscriber_num(topic_info);
+
+    let expires_at = options.ttl_ms.map(|ttl_ms| current_time_ms() + ttl_ms as u128);
+
+    let seq = topic_info.next_update_seq;
+    topic_info.next_update_seq += 1;
 
-    let update = Update { content: content.clone(), pending_updates: sub_num };
+    let size = content.len();
+    let (content, compression) = rpubsub::compress_payload(content, rpubsub::Compression::LZ4);
+
+    let update = Update {
+        content,
+        pending_updates: sub_num,
+        expires_at,
+        headers: options.headers.clone(),
+        seq,
+        received_at,
+        key: options.key.clone(),
+        publisher: String::from(publisher),
+        priority: options.priority,
+        blob: blob.clone(),
+        compression,
+        size,
+        key_version: options.key_version
+    };
 
     let queue = &mut topic_info.update_queue;
 
+    add_stored_bytes(&mut topic_info.stored_bytes, publisher, update.stored_size());
+
     queue.push_back(update);
 
     // when a topic doesnt have an update and gets one, update all None topic_update_idxs
     associate_subscribers_to_last_update(topic_info);
 
-    fs::write(path, serde_json::to_string(state).unwrap());
+    Ok(seq)
+}
+
+// Adds the update right away, or holds it back until the time the publisher asked for.
+// A PUT that was already published, sent again or duplicated on the way, gets ALREAPUT
+// with the last sequence number published, so a publisher that lost count can go on.
+pub fn publish_update(state: &mut State, topic: &rpubsub::Topic, ip: &String, sequence_num: rpubsub::SequenceNum, content: &str,
+                        options: &rpubsub::PutOptions, path: &String) -> Result<rpubsub::PutReceipt, rpubsub::ServiceError> {
+    let topic_info = match state.topics.get(topic) {
+        Some(topic_info) => topic_info,
+        None => return Err(rpubsub::ServiceError::NOTOPIC),
+    };
+
+    if let Some(&last) = topic_info.publishers.get(ip).filter(|last| sequence_num <= **last) {
+        return Err(rpubsub::ServiceError::ALREAPUT { last });
+    }
+
+    let now = current_time_ms();
 
-    Ok(())
+    admit_updates(state, ip, &vec![(topic, content.len())], now)?;
+
+    let receipt = stage_update(state, topic, ip, content, options, &None, now)?;
+
+    state.topics.get_mut(topic).unwrap().publishers.insert(ip.clone(), sequence_num);
+
+    save_state(state, path);
+
+    Ok(receipt)
+}
+
+// Publishes all the updates or, if any of them can't be, none. They are saved together,
+// so a crash never leaves only some of them in the topics. A publisher retrying a
+// transaction that was already committed gets ALREAPUT, like a PUT.
+pub fn publish_transaction(state: &mut State, ip: &String, sequence_num: rpubsub::SequenceNum, updates: &[rpubsub::TxUpdate],
+                            path: &String) -> Result<Vec<rpubsub::PutReceipt>, rpubsub::ServiceError> {
+    if let Some(&last) = state.transactions.get(ip).filter(|last| sequence_num <= **last) {
+        return Err(rpubsub::ServiceError::ALREAPUT { last });
+    }
+
+    if updates.iter().any(|update| !state.topics.contains_key(&update.topic)) {
+        return Err(rpubsub::ServiceError::NOTOPIC);
+    }
+
+    let now = current_time_ms();
+
+    let sizes = updates.iter().map(|update| (&update.topic, update.payload.len())).collect();
+    admit_updates(state, ip, &sizes, now)?;
+
+    // The topics were checked above, so no update fails once the first one is staged
+    let receipts = updates.iter()
+        .map(|update| stage_update(state, &update.topic, ip, &update.payload, &update.options, &None, now))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    state.transactions.insert(ip.clone(), sequence_num);
+
+    save_state(state, path);
+
+    Ok(receipts)
+}
+
+// Publishes the blob the client finished uploading as one update, along with a regular
+// payload. Subscribers then FETCH the blob chunk by chunk.
+pub fn publish_blob(state: &mut State, topic: &rpubsub::Topic, ip: &String, upload_id: &rpubsub::BlobId, content: &str,
+                    options: &rpubsub::PutOptions, path: &String) -> Result<rpubsub::PutReceipt, rpubsub::ServiceError> {
+    if !state.topics.contains_key(topic) {
+        return Err(rpubsub::ServiceError::NOTOPIC);
+    }
+
+    let mut blob = blob::uploaded_blob(state, ip, topic, upload_id)?;
+    blob.info.key_version = options.key_version;
+
+    let now = current_time_ms();
+
+    admit_updates(state, ip, &vec![(topic, content.len() + blob.info.size as usize)], now)?;
+
+    let info = blob.info.clone();
+    state.blobs.insert(info.id.clone(), blob);
+
+    let receipt = stage_update(state, topic, ip, content, options, &Some(info), now)?;
+
+    state.uploads.remove(upload_id);
+
+    save_state(state, path);
+
+    Ok(receipt)
+}
+
+// Chunk of a blob attached to an update of the topic, for its subscribers and group members
+pub fn fetch_blob_chunk(state: &State, topic: &rpubsub::Topic, ip: &String, blob_id: &rpubsub::BlobId, index: u64, path: &String)
+                        -> Result<rpubsub::Chunk, rpubsub::ServiceError> {
+    let topic_info = match state.topics.get(topic) {
+        Some(topic_info) => topic_info,
+        None => return Err(rpubsub::ServiceError::NOTOPIC),
+    };
+
+    if !topic_info.subscriptions.contains_key(ip) && !topic_info.groups.values().any(|group_info| group_info.members.contains(ip)) {
+        return Err(rpubsub::ServiceError::NOSUB);
+    }
+
+    // The blob may also be fetched from a topic its update was dead-lettered on
+    let referenced = |blob: &blob::StoredBlob| blob.topic.eq(topic)
+        || topic_info.update_queue.iter().any(|update| update.blob.as_ref().is_some_and(|info| info.id.eq(blob_id)));
+
+    match state.blobs.get(blob_id) {
+        Some(blob) if referenced(blob) => blob::read_chunk(blob, state.state_key.as_ref(), index, path),
+        _ => Err(rpubsub::ServiceError::NOBLOB),
+    }
+}
+
+// Deletes the blobs of the updates that left their topics. Updates moved to a dead-letter
+// topic keep their blob.
+pub fn remove_unused_blobs(state: &mut State, path: &String) {
+    let mut referenced = HashSet::new();
+
+    for topic_info in state.topics.values() {
+        let blobs = topic_info.update_queue.iter().filter_map(|update| update.blob.as_ref())
+            .chain(topic_info.scheduled_updates.iter().filter_map(|scheduled| scheduled.blob.as_ref()));
+
+        for blob in blobs {
+            referenced.insert(blob.id.clone());
+        }
+    }
+
+    blob::remove_unused_blobs(state, referenced, path);
+}
+
+// Payload bytes stored on the topic, only counting the given publisher's if there is one
+fn stored_bytes(topic_info: &TopicInfo, publisher: Option<&String>) -> usize {
+    match publisher {
+        Some(publisher) => topic_info.stored_bytes.get(publisher).copied().unwrap_or(0),
+        None => topic_info.stored_bytes.values().sum(),
+    }
+}
+
+// Checks that the publisher may add updates of the given sizes to the topics, which
+// must exist, without going over the storage quotas of the client and the topics,
+// the hard watermarks of the topics or their rate limits
+fn admit_updates(state: &mut State, ip: &String, updates: &Vec<(&rpubsub::Topic, usize)>, now: rpubsub::Timestamp) -> Result<(), rpubsub::ServiceError> {
+    let mut per_topic: HashMap<&rpubsub::Topic, (usize, usize)> = HashMap::new();
+
+    for (topic, size) in updates {
+        let (count, bytes) = per_topic.entry(topic).or_insert((0, 0));
+        *count += 1;
+        *bytes += size;
+    }
+
+    for (topic, (count, bytes)) in &per_topic {
+        let topic_info = state.topics.get(*topic).unwrap();
+
+        if topic_info.quota_bytes.is_some_and(|quota_bytes| stored_bytes(topic_info, None) + bytes > quota_bytes) {
+            return Err(rpubsub::ServiceError::OVERQUOTA);
+        }
+
+        let hard = topic_info.watermarks.and_then(|watermarks| watermarks.hard);
+
+        if hard.is_some_and(|hard| topic_info.update_queue.len() + count > hard) {
+            return Err(rpubsub::ServiceError::OVERLOADED);
+        }
+    }
+
+    if let Some(quota_bytes) = state.limits.client_limits(ip).quota_bytes {
+        let stored: usize = state.topics.values().map(|topic_info| stored_bytes(topic_info, Some(ip))).sum();
+        let incoming: usize = updates.iter().map(|(_, size)| size).sum();
+
+        if stored + incoming > quota_bytes {
+            return Err(rpubsub::ServiceError::OVERQUOTA);
+        }
+    }
+
+    let rate_limited = per_topic.iter()
+        .map(|(topic, (count, _))| ((*topic).clone(), *count, state.topics.get(*topic).unwrap().rate_limit))
+        .collect();
+
+    match state.limits.take_tokens(ip, &rate_limited, now) {
+        Ok(_) => Ok(()),
+        Err(retry_after_ms) => Err(rpubsub::ServiceError::THROTTLED { retry_after_ms }),
+    }
+}
+
+// Adds or schedules the update on a topic that exists, without saving the state
+fn stage_update(state: &mut State, topic: &rpubsub::Topic, ip: &str, content: &str, options: &rpubsub::PutOptions,
+                    blob: &Option<rpubsub::BlobInfo>, now: rpubsub::Timestamp) -> Result<rpubsub::PutReceipt, rpubsub::ServiceError> {
+    let deliver_at = match options.delay_ms {
+        Some(delay_ms) => Some(now + delay_ms as u128),
+        None => options.deliver_at,
+    };
+
+    let seq = match deliver_at {
+        Some(deliver_at) if deliver_at > now => {
+            let topic_info = state.topics.get_mut(topic).unwrap();
+
+            let scheduled = ScheduledUpdate {
+                deliver_at,
+                content: String::from(content),
+                options: options.clone(),
+                received_at: now,
+                publisher: String::from(ip),
+                blob: blob.clone()
+            };
+
+            add_stored_bytes(&mut topic_info.stored_bytes, ip, scheduled.stored_size());
+
+            let pos = topic_info.scheduled_updates.partition_point(|scheduled| scheduled.deliver_at <= deliver_at);
+            topic_info.scheduled_updates.insert(pos, scheduled);
+
+            None
+        },
+
+        _ => Some(append_update(state, topic, ip, content, options, blob, now)?),
+    };
+
+    let backpressure = update_backpressure(state.topics.get_mut(topic).unwrap());
+
+    Ok(rpubsub::PutReceipt { seq, timestamp: now, backpressure })
+}
+
+// Whether the topic queue is between the watermarks, having gone over the high one
+// and not yet under the low one
+fn update_backpressure(topic_info: &mut TopicInfo) -> bool {
+    let queued = topic_info.update_queue.len();
+
+    match topic_info.watermarks {
+        Some(watermarks) if queued >= watermarks.high => topic_info.backpressure = true,
+        Some(watermarks) if queued <= watermarks.low => topic_info.backpressure = false,
+        Some(_) => (),
+        None => topic_info.backpressure = false,
+    }
+
+    topic_info.backpressure
+}
+
+// Moves the scheduled updates that are due into their topic queues, as if they
+// were published now. Their time-to-live starts counting then.
+pub fn release_scheduled_updates(state: &mut State, path: &String) {
+    let now = current_time_ms();
+
+    let mut due = Vec::new();
+
+    for (topic, topic_info) in state.topics.iter_mut() {
+        let count = topic_info.scheduled_updates.partition_point(|scheduled| scheduled.deliver_at <= now);
+
+        for scheduled in topic_info.scheduled_updates.drain(..count) {
+            remove_stored_bytes(&mut topic_info.stored_bytes, &scheduled.publisher, scheduled.stored_size());
+            due.push((topic.clone(), scheduled));
+        }
+    }
+
+    for (topic, scheduled) in due {
+        let _ = add_update(state, &topic, &scheduled.publisher, &scheduled.content, &scheduled.options, &scheduled.blob, scheduled.received_at, path);
+    }
+}
+
+// Takes the update at pos out of the queue, keeping every index pointing to the same
+// updates. Nobody may be holding the update: no subscriber can be on it and no group
+// can have it out for delivery.
+fn remove_update_at(topic_info: &mut TopicInfo, pos: usize) -> Update {
+    let update = topic_info.update_queue.remove(pos).unwrap();
+    remove_stored_bytes(&mut topic_info.stored_bytes, &update.publisher, update.stored_size());
+    let queue = &topic_info.update_queue;
+
+    for subscription_info in topic_info.subscriptions.values_mut() {
+        fix_cursor_after_removal(queue, &mut subscription_info.topic_update_idx, &mut subscription_info.consumed, pos, update.seq);
+    }
+
+    for group_info in topic_info.groups.values_mut() {
+        fix_cursor_after_removal(queue, &mut group_info.topic_update_idx, &mut group_info.dispatched, pos, update.seq);
+
+        for delivery in &mut group_info.deliveries {
+            if delivery.topic_update_idx > pos {
+                delivery.topic_update_idx -= 1;
+            }
+        }
+    }
+
+    update
+}
+
+fn is_update_held(topic_info: &TopicInfo, pos: usize) -> bool {
+    topic_info.subscriptions.values().any(|subscription_info| subscription_info.held_update_pos(&topic_info.update_queue) == Some(pos))
+        || topic_info.groups.values().any(|group_info| group_info.deliveries.iter().any(|delivery| delivery.topic_update_idx == pos))
+}
+
+// Drops the expired updates from every topic queue and sends them to the dead-letter
+// topics. Updates a subscriber is on are left for its next GET to skip, since it may
+// still have to acknowledge them.
+pub fn remove_expired_updates(state: &mut State, path: &String) {
+    let now = current_time_ms();
+
+    let mut expired = Vec::new();
+
+    for (topic, topic_info) in state.topics.iter_mut() {
+        let mut pos = 0;
+
+        while pos < topic_info.update_queue.len() {
+            if topic_info.update_queue.get(pos).unwrap().is_expired(now) && !is_update_held(topic_info, pos) {
+                let update = remove_update_at(topic_info, pos);
+                expired.push((topic.clone(), update.to_delivery()));
+            } else {
+                pos += 1;
+            }
+        }
+    }
+
+    if expired.is_empty() {
+        return;
+    }
+
+    for (topic, delivery) in expired {
+        dead_letter_updates(state, &topic, vec![(delivery, rpubsub::DeadLetterReason::EXPIRED, 0)], path);
+    }
+
+    save_state(state, path);
+}
+
+// Drops, from the compacted topics, the keyed updates that have a newer update with the
+// same key further down the queue. Subscribers behind them get the newer value instead.
+// Updates someone is holding are kept, as they may still have to be acknowledged.
+pub fn compact_topics(state: &mut State, path: &String) {
+    let mut compacted = false;
+
+    for topic_info in state.topics.values_mut().filter(|topic_info| topic_info.compacted) {
+        let mut newer_keys = std::collections::HashSet::new();
+
+        // Going from the back, removals don't move the positions still to visit
+        for pos in (0..topic_info.update_queue.len()).rev() {
+            let key = match &topic_info.update_queue.get(pos).unwrap().key {
+                Some(key) => key.clone(),
+                None => continue,
+            };
+
+            if newer_keys.contains(&key) {
+                if !is_update_held(topic_info, pos) {
+                    remove_update_at(topic_info, pos);
+                    compacted = true;
+                }
+            } else {
+                newer_keys.insert(key);
+            }
+        }
+    }
+
+    if compacted {
+        save_state(state, path);
+    }
+}
+
+// Picks the update to hand out to a subscriber that isn't on one, the oldest of the
+// highest priority it didn't get yet. Those on the way that are expired or that its
+// filter rejects are skipped, without touching its sequence number since they are
+// never delivered. Returns whether anything changed and the expired updates nobody
+// else was waiting on, to be dead-lettered.
+fn select_subscriber_update(topic_info: &mut TopicInfo, ip: &String, now: u128) -> (bool, Vec<Result<rpubsub::Delivery, rpubsub::ServiceError>>) {
+    let subscription_info = topic_info.subscriptions.get_mut(ip).unwrap();
+    let queue = &mut topic_info.update_queue;
+
+    let mut skipped = false;
+    let mut dropped = Vec::new();
+
+    // The subscriber didn't acknowledge the update it was handed, it gets it again
+    if subscription_info.current.is_some() {
+        return (false, dropped);
+    }
+
+    while let Some(pos) = next_update_pos(queue, subscription_info.topic_update_idx, &subscription_info.consumed) {
+        let update = queue.get_mut(pos).unwrap();
+
+        let expired = update.is_expired(now);
+
+        if !expired && subscription_info.wants(update) {
+            subscription_info.current = Some(update.seq);
+            break;
+        }
+
+        update.pending_updates -= 1;
+        skipped = true;
+
+        if expired && update.pending_updates == 0 {
+            dropped.push(update.to_delivery());
+        }
+
+        mark_update_done(queue, &mut subscription_info.topic_update_idx, &mut subscription_info.consumed, pos);
+    }
+
+    let changed = skipped || subscription_info.current.is_some();
+
+    if skipped {
+        remove_nonpending_updates(topic_info);
+    }
+
+    (changed, dropped)
 }
 
 pub fn update_subscriber_update_ack(state: &mut State, topic: &rpubsub::Topic, ip: &String, 
@@ -172,7 +1134,7 @@ pub fn update_subscriber_update_ack(state: &mut State, topic: &rpubsub::Topic, i
 
     let queue = &mut topic_info.update_queue;
 
-    let mut ret_idx: Option<usize> = subscription_info.as_ref().unwrap().topic_update_idx.clone();
+    let mut ret_idx: Option<usize> = subscription_info.as_ref().and_then(|subscription_info| subscription_info.topic_update_idx);
 
     return match subscription_info {
         Some(subscription_info) => {
@@ -181,41 +1143,19 @@ pub fn update_subscriber_update_ack(state: &mut State, topic: &rpubsub::Topic, i
             } else if sequence_num == subscription_info.last_recv_sequence_num.unwrap()+1 {
                 subscription_info.last_recv_sequence_num = Some(sequence_num);
 
-                if !subscription_info.topic_update_idx.is_none() {
-                    let idx = subscription_info.topic_update_idx.unwrap();
+                if let Some(pos) = subscription_info.held_update_pos(queue) {
+                    queue.get_mut(pos).unwrap().pending_updates -= 1;
 
-                    let pending_updates = queue.get(idx).unwrap().pending_updates.clone()-1;
+                    mark_update_done(queue, &mut subscription_info.topic_update_idx, &mut subscription_info.consumed, pos);
+                    subscription_info.current = None;
 
-                    queue.get_mut(idx).unwrap().pending_updates -= 1;
+                    remove_nonpending_updates(topic_info);
 
-                    if idx+1 == queue.len() {
-                        subscription_info.topic_update_idx = None;
-                        ret_idx = None;
-                    } else {
-                        subscription_info.topic_update_idx = Some(idx+1);
-                        ret_idx = Some(idx+1);
-                    }
-
-                    if idx == 0 && pending_updates == 0 {
-                        queue.pop_front();
-
-                        for info in &mut topic_info.subscriptions {
-                            if info.1.topic_update_idx.is_none() {
-                                continue
-                            }
-
-                            info.1.topic_update_idx = Some(info.1.topic_update_idx.unwrap()-1);
-
-                            if info.0.eq(ip) {
-                                ret_idx = info.1.topic_update_idx;
-                            }
-                        }
-                    }
-                    
+                    ret_idx = topic_info.subscriptions.get(ip).unwrap().topic_update_idx;
                 }
             }
 
-            fs::write(path, serde_json::to_string(state).unwrap());
+            save_state(state, path);
 
             Ok(ret_idx)
         },
@@ -225,11 +1165,26 @@ pub fn update_subscriber_update_ack(state: &mut State, topic: &rpubsub::Topic, i
 }
 
 pub fn get_next_subscriber_update(state: &mut State, topic: &rpubsub::Topic, ip: &String, sequence_num: rpubsub::SequenceNum, path: &String) 
-                                                                -> Result<(Option<rpubsub::UpdateContent>, rpubsub::SequenceNum), rpubsub::ServiceError> {
+                                                                -> Result<(Option<rpubsub::Delivery>, rpubsub::SequenceNum), rpubsub::ServiceError> {
     match update_subscriber_update_ack(state, topic, ip, sequence_num, path) {
-        Ok(topic_update_idx) => {
-            Ok(match topic_update_idx {
-                Some(idx) => (Some(state.topics.get_mut(topic).unwrap().update_queue.get(idx).unwrap().content.clone()), sequence_num),
+        Ok(_) => {
+            let topic_info = state.topics.get_mut(topic).unwrap();
+
+            let (changed, dropped) = select_subscriber_update(topic_info, ip, current_time_ms());
+
+            if changed {
+                let dead_letters = dropped.into_iter().map(|delivery| (delivery, rpubsub::DeadLetterReason::EXPIRED, 0)).collect();
+
+                dead_letter_updates(state, topic, dead_letters, path);
+
+                save_state(state, path);
+            }
+
+            let topic_info = state.topics.get(topic).unwrap();
+            let current = topic_info.subscriptions.get(ip).unwrap().current;
+
+            Ok(match current.and_then(|seq| update_pos(&topic_info.update_queue, seq)) {
+                Some(pos) => (Some(topic_info.update_queue.get(pos).unwrap().to_delivery()?), sequence_num),
 
                 None => (None, sequence_num),
             })
@@ -237,4 +1192,274 @@ pub fn get_next_subscriber_update(state: &mut State, topic: &rpubsub::Topic, ip:
         
         Err(e) => Err(e),
     }
-}
\ No newline at end of file
+}
+pub fn add_group_member(state: &mut State, topic: &rpubsub::Topic, group: &rpubsub::GroupName, ip: &String, path: &String) -> Result<(), rpubsub::ServiceError> {
+    if !state.topics.contains_key(topic) {
+        add_topic(state, topic);
+    }
+
+    let topic_info = state.topics.get_mut(topic).unwrap();
+
+    // Like a regular subscriber, a new group receives only updates inserted after its creation
+    let group_info = topic_info.groups.entry(group.clone()).or_insert(GroupInfo {
+        members: Vec::new(),
+        topic_update_idx: None,
+        deliveries: Vec::new(),
+        next_delivery_id: 0,
+        dispatched: Vec::new()
+    });
+
+    if group_info.members.contains(ip) {
+        return Err(rpubsub::ServiceError::ALREASUB);
+    }
+
+    group_info.members.push(ip.clone());
+
+    save_state(state, path);
+
+    Ok(())
+}
+
+pub fn remove_group_member(state: &mut State, topic: &rpubsub::Topic, group: &rpubsub::GroupName, ip: &String, path: &String) -> Result<(), rpubsub::ServiceError> {
+    if !state.topics.contains_key(topic) {
+        return Err(rpubsub::ServiceError::NOTOPIC);
+    }
+
+    let topic_info = state.topics.get_mut(topic).unwrap();
+
+    let group_info = match topic_info.groups.get_mut(group) {
+        Some(group_info) if group_info.members.contains(ip) => group_info,
+        _ => return Err(rpubsub::ServiceError::NOSUB),
+    };
+
+    group_info.members.retain(|member| member != ip);
+
+    // Whatever the member was holding goes to the next member that asks for an update
+    for delivery in &mut group_info.deliveries {
+        if delivery.member.as_ref() == Some(ip) {
+            delivery.member = None;
+        }
+    }
+
+    let mut evicted = Vec::new();
+
+    if group_info.members.is_empty() {
+        let group_info = topic_info.groups.remove(group).unwrap();
+        let queue = &mut topic_info.update_queue;
+
+        for delivery in &group_info.deliveries {
+            let update = queue.get_mut(delivery.topic_update_idx).unwrap();
+            update.pending_updates -= 1;
+
+            if update.pending_updates == 0 {
+                evicted.push((update.to_delivery(), rpubsub::DeadLetterReason::EVICTED, delivery.delivery_count));
+            }
+        }
+
+        if let Some(idx) = group_info.topic_update_idx {
+            for i in idx..queue.len() {
+                let update = queue.get_mut(i).unwrap();
+
+                if !group_info.dispatched.contains(&update.seq) {
+                    update.pending_updates -= 1;
+
+                    if update.pending_updates == 0 {
+                        evicted.push((update.to_delivery(), rpubsub::DeadLetterReason::EVICTED, 0));
+                    }
+                }
+            }
+        }
+
+        remove_nonpending_updates(topic_info);
+    }
+
+    dead_letter_updates(state, topic, evicted, path);
+
+    save_state(state, path);
+
+    Ok(())
+}
+
+// Takes back the updates whose ack deadline passed so they can be handed to another
+// member. Those that already went through too many deliveries are given up on and
+// returned to be dead-lettered.
+fn expire_group_deliveries(topic_info: &mut TopicInfo, group: &rpubsub::GroupName, now: u128) -> DeadLetters {
+    let group_info = topic_info.groups.get_mut(group).unwrap();
+    let queue = &mut topic_info.update_queue;
+
+    let mut dead_letters = Vec::new();
+
+    for delivery in &mut group_info.deliveries {
+        if delivery.member.is_some() && delivery.deadline <= now {
+            delivery.member = None;
+        }
+    }
+
+    group_info.deliveries.retain(|delivery| {
+        if delivery.member.is_some() || delivery.delivery_count < GROUP_MAX_DELIVERIES {
+            return true;
+        }
+
+        let update = queue.get_mut(delivery.topic_update_idx).unwrap();
+        update.pending_updates -= 1;
+
+        dead_letters.push((update.to_delivery(), rpubsub::DeadLetterReason::MAXDELIV, delivery.delivery_count));
+
+        false
+    });
+
+    remove_nonpending_updates(topic_info);
+
+    dead_letters
+}
+
+// Drops the expired updates that the group would otherwise hand out next. Returns
+// those nobody else was waiting on, to be dead-lettered.
+fn skip_expired_group_updates(topic_info: &mut TopicInfo, group: &rpubsub::GroupName, now: u128) -> DeadLetters {
+    let group_info = topic_info.groups.get_mut(group).unwrap();
+    let queue = &mut topic_info.update_queue;
+
+    let mut dropped = Vec::new();
+
+    group_info.deliveries.retain(|delivery| {
+        let update = queue.get_mut(delivery.topic_update_idx).unwrap();
+
+        if delivery.member.is_some() || !update.is_expired(now) {
+            return true;
+        }
+
+        update.pending_updates -= 1;
+
+        if update.pending_updates == 0 {
+            dropped.push((update.to_delivery(), rpubsub::DeadLetterReason::EXPIRED, delivery.delivery_count));
+        }
+
+        false
+    });
+
+    while let Some(pos) = next_update_pos(queue, group_info.topic_update_idx, &group_info.dispatched) {
+        let update = queue.get_mut(pos).unwrap();
+
+        if !update.is_expired(now) {
+            break;
+        }
+
+        update.pending_updates -= 1;
+
+        if update.pending_updates == 0 {
+            dropped.push((update.to_delivery(), rpubsub::DeadLetterReason::EXPIRED, 0));
+        }
+
+        mark_update_done(queue, &mut group_info.topic_update_idx, &mut group_info.dispatched, pos);
+    }
+
+    remove_nonpending_updates(topic_info);
+
+    dropped
+}
+
+// Hands the next update of the topic to a group member. The member acknowledges the
+// previous update it received by sending back its delivery id. Updates that aren't 
+// acknowledged in time are redelivered to whichever member asks next.
+pub fn get_next_group_update(state: &mut State, topic: &rpubsub::Topic, group: &rpubsub::GroupName, ip: &String,
+                                ack: Option<rpubsub::SequenceNum>, path: &String)
+                                                                -> Result<(Option<rpubsub::Delivery>, rpubsub::SequenceNum), rpubsub::ServiceError> {
+    if !state.topics.contains_key(topic) {
+        return Err(rpubsub::ServiceError::NOTOPIC);
+    }
+
+    let topic_info = state.topics.get_mut(topic).unwrap();
+
+    match topic_info.groups.get(group) {
+        Some(group_info) if group_info.members.contains(ip) => (),
+        _ => return Err(rpubsub::ServiceError::NOSUB),
+    };
+
+    let now = current_time_ms();
+
+    let mut dead_letters = expire_group_deliveries(topic_info, group, now);
+    dead_letters.extend(skip_expired_group_updates(topic_info, group, now));
+
+    dead_letter_updates(state, topic, dead_letters, path);
+
+    let topic_info = state.topics.get_mut(topic).unwrap();
+
+    let group_info = topic_info.groups.get_mut(group).unwrap();
+
+    let current = group_info.deliveries.iter().position(|delivery| delivery.member.as_ref() == Some(ip));
+
+    if let Some(pos) = current {
+        if ack == Some(group_info.deliveries[pos].delivery_id) {
+            let delivery = group_info.deliveries.remove(pos);
+
+            topic_info.update_queue.get_mut(delivery.topic_update_idx).unwrap().pending_updates -= 1;
+
+            remove_nonpending_updates(topic_info);
+        } else {
+            // The member didn't get the last reply, send the same update again
+            let delivery = group_info.deliveries.get_mut(pos).unwrap();
+            delivery.deadline = now + GROUP_ACK_TIMEOUT_MS;
+            delivery.delivery_count += 1;
+
+            let res = (Some(topic_info.update_queue.get(delivery.topic_update_idx).unwrap().to_delivery()?), delivery.delivery_id);
+
+            save_state(state, path);
+
+            return Ok(res);
+        }
+    }
+
+    let group_info = topic_info.groups.get_mut(group).unwrap();
+
+    let delivery_id = group_info.next_delivery_id;
+
+    let queue = &topic_info.update_queue;
+
+    // Redeliveries go by priority too, the oldest first within a priority
+    let unassigned = group_info.deliveries.iter_mut()
+                                          .filter(|delivery| delivery.member.is_none())
+                                          .max_by_key(|delivery| (queue.get(delivery.topic_update_idx).unwrap().priority,
+                                                                  std::cmp::Reverse(delivery.topic_update_idx)));
+
+    let topic_update_idx = match unassigned {
+        Some(delivery) => {
+            delivery.delivery_id = delivery_id;
+            delivery.member = Some(ip.clone());
+            delivery.deadline = now + GROUP_ACK_TIMEOUT_MS;
+            delivery.delivery_count += 1;
+
+            Some(delivery.topic_update_idx)
+        },
+
+        None => match next_update_pos(queue, group_info.topic_update_idx, &group_info.dispatched) {
+            Some(pos) => {
+                group_info.deliveries.push(GroupDelivery {
+                    delivery_id,
+                    topic_update_idx: pos,
+                    member: Some(ip.clone()),
+                    deadline: now + GROUP_ACK_TIMEOUT_MS,
+                    delivery_count: 1
+                });
+
+                mark_update_done(queue, &mut group_info.topic_update_idx, &mut group_info.dispatched, pos);
+
+                Some(pos)
+            },
+
+            None => None
+        }
+    };
+
+    let res = match topic_update_idx {
+        Some(idx) => {
+            group_info.next_delivery_id += 1;
+            (Some(topic_info.update_queue.get(idx).unwrap().to_delivery()?), delivery_id)
+        },
+
+        None => (None, ack.unwrap_or(0)),
+    };
+
+    save_state(state, path);
+
+    Ok(res)
+}